@@ -0,0 +1,44 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Embeds every JSON fixture in src/tests as `FIXTURES`, so the conformance
+// tests find new fixtures without being edited, also under wasm where there
+// is no file system to scan. Also writes one `fixture_test!` invocation per
+// fixture, for the test modules to turn into a test each.
+fn main() {
+    let dir = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("src/tests");
+    println!("cargo:rerun-if-changed={}", dir.display());
+    let mut fixtures: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "json"))
+        .collect();
+    fixtures.sort();
+
+    let mut out = String::from("pub const FIXTURES: &[(&str, &str)] = &[\n");
+    let mut tests = String::new();
+    for path in fixtures {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        out.push_str(&format!(
+            "    ({:?}, include_str!({:?})),\n",
+            name,
+            path.display().to_string()
+        ));
+        let test: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_lowercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        tests.push_str(&format!("fixture_test!(fixture_{}, {:?});\n", test, name));
+    }
+    out.push_str("];\n");
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("fixtures.rs"), out).unwrap();
+    fs::write(Path::new(&out_dir).join("fixture_tests.rs"), tests).unwrap();
+}
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// `VM::try_load` must reject any instruction list it can't load with an
+// error rather than a panic.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(serde_json::Value::Array(instructions)) = serde_json::from_slice(data) {
+        let mut vm = tzo::vm::VM::new();
+        let _ = vm.try_load(instructions);
+    }
+});
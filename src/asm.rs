@@ -0,0 +1,290 @@
+use std::fmt::Write;
+
+use crate::vm::VM;
+
+// Plain-text assembly syntax for Tzo programs, as an alternative to the JSON
+// instruction list. Tokens are separated by whitespace:
+//
+//   42  -1.5      push a number
+//   "hello"       push a string (JSON string syntax)
+//   plus  {  }    invoke a function
+//   start:        label the next instruction
+//   # ...         comment until the end of the line
+//
+// Parsing produces the same JSON instruction objects `VM::load` accepts.
+
+fn is_number(token: &str) -> bool {
+    let digits = token.strip_prefix('-').unwrap_or(token);
+    let digits = digits.strip_prefix('.').unwrap_or(digits);
+    digits.starts_with(|c: char| c.is_ascii_digit())
+}
+
+pub fn parse(source: &str) -> Result<Vec<serde_json::Value>, String> {
+    parse_with_lines(source).map(|(instructions, _)| instructions)
+}
+
+// Like `parse`, also returning the (1-based) source line of each instruction.
+pub fn parse_with_lines(source: &str) -> Result<(Vec<serde_json::Value>, Vec<usize>), String> {
+    let mut instructions = vec![];
+    let mut lines = vec![];
+    let mut pending_label: Option<String> = None;
+    for (n, line) in source.lines().enumerate() {
+        let err = |msg: String| format!("line {}: {}", n + 1, msg);
+        let mut rest = line.trim_start();
+        while !rest.is_empty() {
+            let mut instr = if let Some(body) = rest.strip_prefix('"') {
+                // find the closing quote, skipping escaped characters
+                let mut end = None;
+                let mut escaped = false;
+                for (i, c) in body.char_indices() {
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '"' => {
+                            end = Some(i);
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+                let end = end.ok_or_else(|| err("unterminated string".to_string()))?;
+                let literal = &rest[..end + 2];
+                rest = &rest[end + 2..];
+                let value: String = serde_json::from_str(literal)
+                    .map_err(|e| err(format!("invalid string {}: {}", literal, e)))?;
+                serde_json::json!({ "type": "push-string-instruction", "value": value })
+            } else {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                let token = &rest[..end];
+                rest = &rest[end..];
+                if token.starts_with('#') {
+                    break;
+                }
+                if token.len() > 1 && token.ends_with(':') {
+                    if pending_label.is_some() {
+                        return Err(err(format!("second label {} for one instruction", token)));
+                    }
+                    pending_label = Some(token[..token.len() - 1].to_string());
+                    rest = rest.trim_start();
+                    continue;
+                }
+                if is_number(token) {
+                    let value: f64 = token
+                        .parse()
+                        .map_err(|_| err(format!("invalid number {}", token)))?;
+                    if !value.is_finite() {
+                        return Err(err(format!("number {} is out of range", token)));
+                    }
+                    serde_json::json!({ "type": "push-number-instruction", "value": value })
+                } else {
+                    serde_json::json!({ "type": "invoke-function-instruction", "functionName": token })
+                }
+            };
+            if let Some(label) = pending_label.take() {
+                instr["label"] = serde_json::json!(label);
+            }
+            instructions.push(instr);
+            lines.push(n + 1);
+            rest = rest.trim_start();
+        }
+    }
+    if let Some(label) = pending_label {
+        return Err(format!("label {} does not precede an instruction", label));
+    }
+    Ok((instructions, lines))
+}
+
+// Accepts either a JSON instruction list, a JSON test fixture (using its
+// `input_program`), or the text syntax above.
+pub fn parse_program(contents: &str) -> Result<Vec<serde_json::Value>, String> {
+    match serde_json::from_str::<serde_json::Value>(contents) {
+        Ok(serde_json::Value::Array(instructions)) => Ok(instructions),
+        Ok(serde_json::Value::Object(fixture)) if fixture.contains_key("input_program") => {
+            match &fixture["input_program"] {
+                serde_json::Value::Array(instructions) => Ok(instructions.clone()),
+                _ => Err("input_program is not an array".to_string()),
+            }
+        }
+        _ => parse(contents),
+    }
+}
+
+// Like `parse_program`, also accepting bytecode, recognized by its magic
+// bytes.
+pub fn parse_program_bytes(contents: &[u8]) -> Result<Vec<serde_json::Value>, String> {
+    if contents.starts_with(BYTECODE_MAGIC) {
+        return decode(contents);
+    }
+    match std::str::from_utf8(contents) {
+        Ok(contents) => parse_program(contents),
+        Err(_) => Err("not a Tzo program: neither text nor bytecode".to_string()),
+    }
+}
+
+// Bytecode: a compact binary form of the instruction list, written by
+// `tzo compile`. After the magic bytes, each instruction is an opcode byte
+// followed by its operand:
+//
+//   0x01 f64      push a number (little-endian)
+//   0x02 string   push a string
+//   0x03 string   invoke a function
+//   0x04 string   label the next instruction
+//   0x05 string   an instruction of another type, given as JSON, which
+//                 loading skips like the instruction it stands for
+//
+// Strings are their UTF-8 length as a little-endian u32, then the bytes.
+pub const BYTECODE_MAGIC: &[u8] = b"TZO\x01";
+
+const OP_NUMBER: u8 = 0x01;
+const OP_STRING: u8 = 0x02;
+const OP_CALL: u8 = 0x03;
+const OP_LABEL: u8 = 0x04;
+const OP_OTHER: u8 = 0x05;
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend((s.len() as u32).to_le_bytes());
+    out.extend(s.as_bytes());
+}
+
+// Encodes a JSON instruction list as bytecode.
+pub fn encode(instructions: &[serde_json::Value]) -> Result<Vec<u8>, String> {
+    let mut out = BYTECODE_MAGIC.to_vec();
+    for (index, i) in instructions.iter().enumerate() {
+        let err = |message: &str| format!("instruction {}: {}", index, message);
+        if !i.is_object() {
+            return Err(err("instruction is not an object"));
+        }
+        if let Some(label) = i.get("label") {
+            let label = label
+                .as_str()
+                .ok_or_else(|| err("label must be a string"))?;
+            out.push(OP_LABEL);
+            write_string(&mut out, label);
+        }
+        match i["type"].as_str() {
+            Some("push-number-instruction") => {
+                let value = i["value"]
+                    .as_f64()
+                    .ok_or_else(|| err("push-number-instruction needs a numeric value"))?;
+                out.push(OP_NUMBER);
+                out.extend(value.to_le_bytes());
+            }
+            Some("push-string-instruction") => {
+                let value = i["value"]
+                    .as_str()
+                    .ok_or_else(|| err("push-string-instruction needs a string value"))?;
+                out.push(OP_STRING);
+                write_string(&mut out, value);
+            }
+            Some("invoke-function-instruction") => {
+                let name = i["functionName"]
+                    .as_str()
+                    .ok_or_else(|| err("invoke-function-instruction needs a functionName"))?;
+                out.push(OP_CALL);
+                write_string(&mut out, name);
+            }
+            // kept so that a label on it still names the instruction before
+            _ => {
+                out.push(OP_OTHER);
+                write_string(&mut out, &i["type"].to_string());
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Decodes bytecode into the JSON instruction list it was encoded from.
+pub fn decode(bytes: &[u8]) -> Result<Vec<serde_json::Value>, String> {
+    let mut rest = bytes
+        .strip_prefix(BYTECODE_MAGIC)
+        .ok_or("not Tzo bytecode")?;
+    let mut take = |n: usize| -> Result<&[u8], String> {
+        if rest.len() < n {
+            return Err("bytecode ends in the middle of an instruction".to_string());
+        }
+        let (taken, after) = rest.split_at(n);
+        rest = after;
+        Ok(taken)
+    };
+    let mut instructions = vec![];
+    let mut pending_label: Option<String> = None;
+    while let Ok(op) = take(1) {
+        let op = op[0];
+        let mut string = || -> Result<String, String> {
+            let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+            String::from_utf8(take(len)?.to_vec())
+                .map_err(|_| "invalid UTF-8 in bytecode".to_string())
+        };
+        let mut instr = match op {
+            OP_NUMBER => {
+                let value = f64::from_le_bytes(take(8)?.try_into().unwrap());
+                if !value.is_finite() {
+                    return Err(format!("number {} in bytecode is not finite", value));
+                }
+                serde_json::json!({ "type": "push-number-instruction", "value": value })
+            }
+            OP_STRING => {
+                serde_json::json!({ "type": "push-string-instruction", "value": string()? })
+            }
+            OP_CALL => {
+                serde_json::json!({ "type": "invoke-function-instruction", "functionName": string()? })
+            }
+            OP_OTHER => {
+                let kind: serde_json::Value = serde_json::from_str(&string()?)
+                    .map_err(|_| "invalid instruction type in bytecode".to_string())?;
+                match kind {
+                    serde_json::Value::Null => serde_json::json!({}),
+                    kind => serde_json::json!({ "type": kind }),
+                }
+            }
+            OP_LABEL => {
+                let label = string()?;
+                if pending_label.is_some() {
+                    return Err(format!("second label {} for one instruction", label));
+                }
+                pending_label = Some(label);
+                continue;
+            }
+            op => return Err(format!("unknown opcode {:#04x} in bytecode", op)),
+        };
+        if let Some(label) = pending_label.take() {
+            instr["label"] = serde_json::json!(label);
+        }
+        instructions.push(instr);
+    }
+    if let Some(label) = pending_label {
+        return Err(format!("label {} does not precede an instruction", label));
+    }
+    Ok(instructions)
+}
+
+// Source line of each instruction if `contents` is a text program, `None`
+// for the JSON formats.
+pub fn source_lines(contents: &str) -> Option<Vec<usize>> {
+    match serde_json::from_str::<serde_json::Value>(contents) {
+        Ok(serde_json::Value::Array(_)) => None,
+        Ok(serde_json::Value::Object(fixture)) if fixture.contains_key("input_program") => None,
+        _ => parse_with_lines(contents).ok().map(|(_, lines)| lines),
+    }
+}
+
+// Lists `pcs` of the loaded program in the text syntax, one instruction per
+// line prefixed with its pc. The instruction at the VM's pc is marked with `>`.
+pub fn disassemble(vm: &VM, pcs: std::ops::Range<usize>) -> String {
+    let mut out = String::new();
+    for pc in pcs.start..pcs.end.min(vm.programlist.len()) {
+        let mut labels: Vec<&String> = vm
+            .labels
+            .iter()
+            .filter(|(_, l)| **l as usize == pc)
+            .map(|(k, _)| k)
+            .collect();
+        labels.sort();
+        for label in labels {
+            writeln!(out, "{}:", label).unwrap();
+        }
+        let marker = if pc == vm.pc { '>' } else { ' ' };
+        writeln!(out, "{} {:>4}  {}", marker, pc, vm.programlist[pc]).unwrap();
+    }
+    out
+}
@@ -0,0 +1,304 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::vm::{Instr, VM};
+
+// Control-flow graph of a loaded program, split into basic blocks.
+//
+// A block ends after any instruction that may transfer control elsewhere
+// (`{`, `}`, `goto`, `jz`, `jgz`, `exit`, `pause`), and a new block starts at
+// every label and every jump target.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    // Instructions `start..end` of the program list.
+    pub start: usize,
+    pub end: usize,
+    pub labels: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    Fallthrough,
+    // `jz`/`jgz` skipping the next instruction.
+    Skip,
+    // `{` jumping past its matching `}`.
+    SkipBlock,
+    Goto,
+    Exit,
+    // `goto` whose target is only known at runtime.
+    DynamicGoto,
+    // Execution continues here after a `pause` once the host resumes the VM.
+    Resume,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Block(usize),
+    // Execution stops: falls off the end, `exit`, or an unresolvable jump.
+    End,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: Target,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlFlowGraph {
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<Edge>,
+}
+
+fn ends_block(instr: &Instr) -> bool {
+    match instr {
+        Instr::OpenBrace | Instr::CloseBrace => true,
+        Instr::Func(_, name) => {
+            matches!(name.as_str(), "goto" | "jz" | "jgz" | "exit" | "pause")
+        }
+        _ => false,
+    }
+}
+
+// Whether each pc may be jumped to other than by a computed `goto`.
+fn jump_targets(vm: &VM) -> Vec<bool> {
+    let len = vm.programlist.len();
+    let mut targets = vec![false; len + 1];
+    for l in vm.labels.values() {
+        targets[(*l as usize).min(len)] = true;
+    }
+    for (pc, instr) in vm.programlist.iter().enumerate() {
+        match instr {
+            Instr::OpenBrace => {
+                if let Some(close) = vm.matching_brace(pc) {
+                    targets[(close + 1).min(len)] = true;
+                }
+            }
+            Instr::Func(_, name) if name == "jz" || name == "jgz" => {
+                targets[(pc + 2).min(len)] = true;
+            }
+            Instr::Func(_, name) if name == "goto" && pc > 0 => {
+                if let Instr::Number(n) = &vm.programlist[pc - 1] {
+                    targets[VM::goto_target(*n).min(len)] = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    targets
+}
+
+// Which `goto`s have a target only known at runtime: those whose operand
+// isn't pushed by the instruction right before them, or that can be jumped
+// to, and so entered with any operand. A computed goto that may be given a
+// number can land on any of them.
+fn dynamic_gotos(vm: &VM) -> Vec<bool> {
+    let targets = jump_targets(vm);
+    let gotos: Vec<usize> = (0..vm.programlist.len())
+        .filter(|pc| matches!(&vm.programlist[*pc], Instr::Func(_, name) if name == "goto"))
+        .collect();
+    let mut dynamic = vec![false; vm.programlist.len()];
+    for pc in &gotos {
+        dynamic[*pc] = *pc == 0
+            || !matches!(vm.programlist[pc - 1], Instr::Number(_) | Instr::String(_))
+            || targets[*pc];
+    }
+    if vm.policy.goto_by_number && dynamic.contains(&true) {
+        for pc in gotos {
+            dynamic[pc] = true;
+        }
+    }
+    dynamic
+}
+
+// Resolves the target of the `goto` at `pc`, whose operand is pushed by the
+// instruction right before it unless it is `dynamic`. `None` means the
+// target is dynamic.
+fn goto_target(vm: &VM, pc: usize, dynamic: bool) -> Option<Target> {
+    if dynamic {
+        return None;
+    }
+    let target = match &vm.programlist[pc - 1] {
+        Instr::String(label) => vm.labels.get(label).map(|l| *l as usize),
+        Instr::Number(n) => Some(VM::goto_target(*n)),
+        _ => return None,
+    };
+    match target {
+        Some(t) if t < vm.programlist.len() => Some(Target::Block(t)),
+        _ => Some(Target::End),
+    }
+}
+
+impl ControlFlowGraph {
+    pub fn build(vm: &VM) -> ControlFlowGraph {
+        let len = vm.programlist.len();
+        let dynamic = dynamic_gotos(vm);
+
+        // Successors of each block-ending instruction, as pcs.
+        let successors = |pc: usize| -> Vec<(Option<usize>, EdgeKind)> {
+            let at = |t: usize| if t < len { Some(t) } else { None };
+            match &vm.programlist[pc] {
+                Instr::OpenBrace => match vm.matching_brace(pc) {
+                    Some(close) => vec![(at(close + 1), EdgeKind::SkipBlock)],
+                    None => vec![(None, EdgeKind::SkipBlock)],
+                },
+                Instr::Func(_, name) => match name.as_str() {
+                    "jz" | "jgz" => vec![
+                        (at(pc + 1), EdgeKind::Fallthrough),
+                        (at(pc + 2), EdgeKind::Skip),
+                    ],
+                    "exit" => vec![(None, EdgeKind::Exit)],
+                    "pause" => vec![(at(pc + 1), EdgeKind::Resume)],
+                    "goto" => match goto_target(vm, pc, dynamic[pc]) {
+                        Some(Target::Block(t)) => vec![(Some(t), EdgeKind::Goto)],
+                        Some(Target::End) => vec![(None, EdgeKind::Goto)],
+                        None => {
+                            // a label, or with a number any pc past the first
+                            let mut targets: Vec<usize> =
+                                vm.labels.values().map(|l| *l as usize).collect();
+                            if vm.policy.goto_by_number {
+                                targets.extend(1..len);
+                            }
+                            targets.sort();
+                            targets.dedup();
+                            targets
+                                .into_iter()
+                                .map(|t| (at(t), EdgeKind::DynamicGoto))
+                                .collect()
+                        }
+                    },
+                    _ => vec![(at(pc + 1), EdgeKind::Fallthrough)],
+                },
+                _ => vec![(at(pc + 1), EdgeKind::Fallthrough)],
+            }
+        };
+
+        let mut leaders = BTreeSet::new();
+        if len > 0 {
+            leaders.insert(0);
+        }
+        for l in vm.labels.values() {
+            leaders.insert(*l as usize);
+        }
+        for (pc, instr) in vm.programlist.iter().enumerate() {
+            if ends_block(instr) {
+                leaders.insert(pc + 1);
+                for (t, _) in successors(pc) {
+                    leaders.extend(t);
+                }
+            }
+        }
+        leaders.retain(|l| *l < len);
+
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let block_of = |pc: usize| starts.partition_point(|s| *s <= pc) - 1;
+        let mut blocks = vec![];
+        for (i, start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(len);
+            let mut labels: Vec<String> = vm
+                .labels
+                .iter()
+                .filter(|(_, l)| **l as usize == *start)
+                .map(|(k, _)| k.clone())
+                .collect();
+            labels.sort();
+            blocks.push(BasicBlock {
+                start: *start,
+                end,
+                labels,
+            });
+        }
+
+        let mut edges = vec![];
+        for (i, block) in blocks.iter().enumerate() {
+            let last = block.end - 1;
+            let succ = if ends_block(&vm.programlist[last]) {
+                successors(last)
+            } else {
+                let next = if block.end < len {
+                    Some(block.end)
+                } else {
+                    None
+                };
+                vec![(next, EdgeKind::Fallthrough)]
+            };
+            for (t, kind) in succ {
+                edges.push(Edge {
+                    from: i,
+                    to: match t {
+                        Some(pc) => Target::Block(block_of(pc)),
+                        None => Target::End,
+                    },
+                    kind,
+                });
+            }
+        }
+
+        ControlFlowGraph { blocks, edges }
+    }
+
+    pub fn block_at(&self, pc: usize) -> Option<usize> {
+        self.blocks.iter().position(|b| b.start <= pc && pc < b.end)
+    }
+
+    // Renders the graph in Graphviz DOT format. Blocks calling foreign
+    // functions are highlighted.
+    pub fn to_dot(&self, vm: &VM) -> String {
+        let mut out = String::new();
+        out.push_str("digraph tzo {\n");
+        out.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        for (i, block) in self.blocks.iter().enumerate() {
+            let mut text = String::new();
+            for label in &block.labels {
+                write!(text, "{}:\\l", escape(label)).unwrap();
+            }
+            let mut foreign = false;
+            for pc in block.start..block.end {
+                let instr = &vm.programlist[pc];
+                write!(text, "{:>4}  {}", pc, escape(&instr.to_string())).unwrap();
+                if let Instr::Func(_, name) = instr {
+                    if VM::builtin(name).is_none() {
+                        text.push_str("  (foreign)");
+                        foreign = true;
+                    }
+                }
+                text.push_str("\\l");
+            }
+            let style = if foreign {
+                ", style=filled, fillcolor=\"lightblue\""
+            } else {
+                ""
+            };
+            writeln!(out, "    b{} [label=\"{}\"{}];", i, text, style).unwrap();
+        }
+        if self.edges.iter().any(|e| e.to == Target::End) {
+            out.push_str("    end [shape=doublecircle, label=\"end\"];\n");
+        }
+        for edge in &self.edges {
+            let to = match edge.to {
+                Target::Block(b) => format!("b{}", b),
+                Target::End => "end".to_string(),
+            };
+            let attrs = match edge.kind {
+                EdgeKind::Fallthrough => "",
+                EdgeKind::Skip => " [label=\"skip\"]",
+                EdgeKind::SkipBlock => " [label=\"{ }\"]",
+                EdgeKind::Goto => " [label=\"goto\", color=\"blue\"]",
+                EdgeKind::Exit => " [label=\"exit\"]",
+                EdgeKind::DynamicGoto => " [label=\"goto?\", color=\"blue\", style=\"dashed\"]",
+                EdgeKind::Resume => " [label=\"resume\", style=\"dotted\"]",
+            };
+            writeln!(out, "    b{} -> {}{};", edge.from, to, attrs).unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::vm::{SharedBuffer, Value, VM};
+
+// Runs the JSON test fixtures in `src/tests`:
+//
+//   { "input_program": [...], "initial_context": {...}, "expected": {...} }
+//
+// `expected` can hold any of
+//
+//   stack    the whole final stack, bottom first
+//   context  the whole final context
+//   stdout   everything the program wrote with `stdout`
+//   error    the runtime error message, for programs that must fail
+//   exited   whether the program called `exit`
+//   paused   whether the program called `pause`
+//
+// and only those present are checked, except that a runtime error fails a
+// fixture that doesn't expect one.
+
+// The fixtures in `src/tests`, as `(name, contents)` sorted by name. Only
+// built with the `conformance` feature, to keep them out of the library.
+#[cfg(any(test, feature = "conformance"))]
+include!(concat!(env!("OUT_DIR"), "/fixtures.rs"));
+
+// Checks the fixture called `name` in `FIXTURES`.
+#[cfg(any(test, feature = "conformance"))]
+pub fn check_fixture(name: &str) -> Report {
+    check_all(FIXTURES.iter().copied().filter(|(n, _)| *n == name))
+}
+
+// What running a fixture's program produced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub stack: Vec<Value>,
+    pub context: HashMap<String, Value>,
+    pub stdout: String,
+    pub error: Option<String>,
+    pub exited: bool,
+    pub paused: bool,
+}
+
+// Loads the fixture's program with its initial context into a fresh VM.
+pub fn load(fixture: &serde_json::Value) -> Result<VM, String> {
+    let instructions = match &fixture["input_program"] {
+        serde_json::Value::Array(instructions) => instructions.clone(),
+        _ => return Err("input_program is not an array".to_string()),
+    };
+    let mut vm = VM::new();
+    vm.try_load(instructions)
+        .map_err(|e| format!("cannot load: {}", e))?;
+    if !fixture["initial_context"].is_null() {
+        vm.set_context_from_json(&fixture["initial_context"])
+            .map_err(|e| format!("invalid initial_context: {}", e))?;
+    }
+    Ok(vm)
+}
+
+// Runs `vm` to completion, capturing its output.
+pub fn run(mut vm: VM) -> Outcome {
+    let stdout = SharedBuffer::default();
+    vm.stdout = Box::new(stdout.clone());
+    let error = vm.try_run().err().map(|e| e.to_string());
+    Outcome {
+        stack: vm.stack.clone(),
+        context: vm.context.to_map(),
+        stdout: stdout.take_string(),
+        error,
+        exited: vm.exited,
+        paused: !vm.running && !vm.exited,
+    }
+}
+
+fn values_json(values: &[Value]) -> serde_json::Value {
+    serde_json::Value::Array(values.iter().map(|v| v.to_json()).collect())
+}
+
+fn context_json(context: &HashMap<String, Value>) -> serde_json::Value {
+    serde_json::Value::Object(
+        context
+            .iter()
+            .map(|(k, v)| (k.clone(), v.to_json()))
+            .collect(),
+    )
+}
+
+// Compares an outcome against a fixture's `expected`, returning every
+// mismatch.
+pub fn compare(expected: &serde_json::Value, outcome: &Outcome) -> Vec<String> {
+    let mut mismatches = vec![];
+    let mut mismatch = |what: &str, expected: &dyn fmt::Display, actual: &dyn fmt::Display| {
+        mismatches.push(format!("{}: expected {}, got {}", what, expected, actual));
+    };
+    if let Some(stack) = expected.get("stack") {
+        let matches = match stack.as_array() {
+            Some(stack) => {
+                stack.len() == outcome.stack.len()
+                    && stack
+                        .iter()
+                        .zip(&outcome.stack)
+                        .all(|(e, v)| Value::from_json(e).as_ref() == Some(v))
+            }
+            None => false,
+        };
+        if !matches {
+            mismatch("stack", stack, &values_json(&outcome.stack));
+        }
+    }
+    if let Some(context) = expected.get("context") {
+        let matches = match context.as_object() {
+            Some(context) => {
+                context.len() == outcome.context.len()
+                    && context
+                        .iter()
+                        .all(|(k, e)| Value::from_json(e).as_ref() == outcome.context.get(k))
+            }
+            None => false,
+        };
+        if !matches {
+            mismatch("context", context, &context_json(&outcome.context));
+        }
+    }
+    if let Some(stdout) = expected.get("stdout") {
+        if stdout.as_str() != Some(outcome.stdout.as_str()) {
+            mismatch("stdout", stdout, &serde_json::json!(outcome.stdout));
+        }
+    }
+    match (expected.get("error"), &outcome.error) {
+        (Some(e), Some(actual)) if e.as_str() == Some(actual.as_str()) => {}
+        (Some(e), actual) => {
+            let actual = actual
+                .as_ref()
+                .map_or("no error".to_string(), |a| format!("{:?}", a));
+            mismatch("error", e, &actual)
+        }
+        (None, Some(actual)) => mismatch("error", &"none", &format!("{:?}", actual)),
+        (None, None) => {}
+    }
+    for (key, actual) in [("exited", outcome.exited), ("paused", outcome.paused)] {
+        if let Some(e) = expected.get(key) {
+            if e.as_bool() != Some(actual) {
+                mismatch(key, e, &actual);
+            }
+        }
+    }
+    mismatches
+}
+
+// Checks one fixture, returning every mismatch or why it couldn't run.
+pub fn check(contents: &str) -> Result<(), Vec<String>> {
+    let fixture: serde_json::Value =
+        serde_json::from_str(contents).map_err(|e| vec![format!("invalid JSON: {}", e)])?;
+    let vm = load(&fixture).map_err(|e| vec![e])?;
+    let mismatches = compare(&fixture["expected"], &run(vm));
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(mismatches)
+    }
+}
+
+// Results of checking a set of fixtures, shown as one line per fixture
+// followed by its mismatches.
+pub struct Report {
+    pub results: Vec<(String, Result<(), Vec<String>>)>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|(_, r)| r.is_ok())
+    }
+
+    pub fn failures(&self) -> Vec<&str> {
+        self.results
+            .iter()
+            .filter(|(_, r)| r.is_err())
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, result) in &self.results {
+            match result {
+                Ok(()) => writeln!(f, "ok    {}", name)?,
+                Err(mismatches) => {
+                    writeln!(f, "FAIL  {}", name)?;
+                    for m in mismatches {
+                        writeln!(f, "      {}", m)?;
+                    }
+                }
+            }
+        }
+        let failed = self.failures().len();
+        write!(
+            f,
+            "{} passed, {} failed",
+            self.results.len() - failed,
+            failed
+        )
+    }
+}
+
+// Checks `(name, contents)` fixtures.
+pub fn check_all<'a>(fixtures: impl IntoIterator<Item = (&'a str, &'a str)>) -> Report {
+    Report {
+        results: fixtures
+            .into_iter()
+            .map(|(name, contents)| (name.to_string(), check(contents)))
+            .collect(),
+    }
+}
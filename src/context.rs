@@ -0,0 +1,382 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::vm::{Value, VmError, VM};
+
+// Where a VM keeps its context. A `HashMap` is the default; hosts can back
+// script state with their own storage by setting `vm.context`:
+//
+//   vm.context = Box::new(FileStore::open("save.json")?);
+//
+// Stores return the reason a write failed, e.g. a file that can't be
+// written, which makes the instruction that wrote to the context fail.
+pub trait ContextStore {
+    fn get(&self, key: &str) -> Option<Value>;
+
+    fn has(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Returns the value `key` had.
+    fn set(&mut self, key: &str, value: Value) -> Result<Option<Value>, String>;
+
+    // Returns the value `key` had.
+    fn delete(&mut self, key: &str) -> Result<Option<Value>, String>;
+
+    // All keys and their values, in no particular order.
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, Value)> + '_>;
+
+    fn to_map(&self) -> HashMap<String, Value> {
+        self.iter().collect()
+    }
+
+    // Makes the store hold exactly `values`, writing only what differs.
+    fn assign(&mut self, values: &HashMap<String, Value>) -> Result<(), String> {
+        let stale: Vec<String> = self
+            .iter()
+            .map(|(k, _)| k)
+            .filter(|k| !values.contains_key(k))
+            .collect();
+        for k in stale {
+            self.delete(&k)?;
+        }
+        for (k, v) in values {
+            if self.get(k).as_ref() != Some(v) {
+                self.set(k, v.clone())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Box<dyn ContextStore> {
+    fn default() -> Self {
+        Box::new(HashMap::new())
+    }
+}
+
+impl ContextStore for HashMap<String, Value> {
+    fn get(&self, key: &str) -> Option<Value> {
+        HashMap::get(self, key).cloned()
+    }
+
+    fn has(&self, key: &str) -> bool {
+        self.contains_key(key)
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> Result<Option<Value>, String> {
+        Ok(self.insert(key.to_string(), value))
+    }
+
+    fn delete(&mut self, key: &str) -> Result<Option<Value>, String> {
+        Ok(self.remove(key))
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, Value)> + '_> {
+        Box::new(HashMap::iter(self).map(|(k, v)| (k.clone(), v.clone())))
+    }
+
+    fn to_map(&self) -> HashMap<String, Value> {
+        self.clone()
+    }
+}
+
+// Reads through to a base store shared with other VMs, e.g. game defaults,
+// and keeps its own writes and deletes on top of it.
+pub struct Layered {
+    base: Rc<dyn ContextStore>,
+    // None for keys deleted from the base.
+    overlay: HashMap<String, Option<Value>>,
+}
+
+impl Layered {
+    pub fn new(base: Rc<dyn ContextStore>) -> Layered {
+        Layered {
+            base,
+            overlay: HashMap::new(),
+        }
+    }
+
+    // What differs from the base, with None for deleted keys.
+    pub fn changes(&self) -> &HashMap<String, Option<Value>> {
+        &self.overlay
+    }
+}
+
+impl ContextStore for Layered {
+    fn get(&self, key: &str) -> Option<Value> {
+        match self.overlay.get(key) {
+            Some(v) => v.clone(),
+            None => self.base.get(key),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> Result<Option<Value>, String> {
+        let old = self.get(key);
+        self.overlay.insert(key.to_string(), Some(value));
+        Ok(old)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<Option<Value>, String> {
+        let old = self.get(key);
+        if self.base.has(key) {
+            self.overlay.insert(key.to_string(), None);
+        } else {
+            self.overlay.remove(key);
+        }
+        Ok(old)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, Value)> + '_> {
+        let base = self
+            .base
+            .iter()
+            .filter(|(k, _)| !self.overlay.contains_key(k));
+        let overlay = self
+            .overlay
+            .iter()
+            .filter_map(|(k, v)| v.clone().map(|v| (k.clone(), v)));
+        Box::new(base.chain(overlay))
+    }
+}
+
+// Keeps the context in a file holding a JSON object, written on every
+// change.
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    values: HashMap<String, Value>,
+}
+
+impl FileStore {
+    // Reads the context from `path`, starting out empty if there is no such
+    // file yet.
+    pub fn open(path: impl Into<PathBuf>) -> Result<FileStore, String> {
+        let path = path.into();
+        let values = match fs::read_to_string(&path) {
+            Ok(contents) => parse_context(&contents)
+                .map_err(|e| format!("cannot read {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(format!("cannot read {}: {}", path.display(), e)),
+        };
+        Ok(FileStore { path, values })
+    }
+
+    fn save(&self) -> Result<(), String> {
+        // keys sorted, so that files diff well
+        let values: BTreeMap<&String, serde_json::Value> =
+            self.values.iter().map(|(k, v)| (k, v.to_json())).collect();
+        let json = serde_json::to_string_pretty(&values).unwrap();
+        // written next to the file first, so that it is never half written
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, json + "\n")
+            .and_then(|_| fs::rename(&temporary, &self.path))
+            .map_err(|e| format!("cannot write {}: {}", self.path.display(), e))
+    }
+
+    // Keeps the values as they were if they can't be saved, so that they
+    // match the file.
+    fn update(&mut self, key: &str, value: Option<Value>) -> Result<Option<Value>, String> {
+        let old = match value {
+            Some(value) => self.values.insert(key.to_string(), value),
+            None => self.values.remove(key),
+        };
+        if let Err(e) = self.save() {
+            match &old {
+                Some(old) => self.values.insert(key.to_string(), old.clone()),
+                None => self.values.remove(key),
+            };
+            return Err(e);
+        }
+        Ok(old)
+    }
+}
+
+fn parse_context(contents: &str) -> Result<HashMap<String, Value>, String> {
+    let json: serde_json::Value = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+    from_json(&json)
+}
+
+// A context from a JSON object of numbers and strings.
+pub fn from_json(json: &serde_json::Value) -> Result<HashMap<String, Value>, String> {
+    let object = json.as_object().ok_or("not a JSON object")?;
+    object
+        .iter()
+        .map(|(k, v)| match Value::from_json(v) {
+            Some(v) => Ok((k.clone(), v)),
+            None => Err(format!("{} is not a number or string", k)),
+        })
+        .collect()
+}
+
+impl ContextStore for FileStore {
+    fn get(&self, key: &str) -> Option<Value> {
+        self.values.get(key).cloned()
+    }
+
+    fn has(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> Result<Option<Value>, String> {
+        self.update(key, Some(value))
+    }
+
+    fn delete(&mut self, key: &str) -> Result<Option<Value>, String> {
+        if !self.values.contains_key(key) {
+            return Ok(None);
+        }
+        self.update(key, None)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, Value)> + '_> {
+        Box::new(self.values.iter().map(|(k, v)| (k.clone(), v.clone())))
+    }
+}
+
+// How a context changed between two snapshots of it, e.g. for migrating
+// saved games or asserting what a script did:
+//
+//   let diff = ContextDiff::between(&before.context, &vm.snapshot().context);
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContextDiff {
+    pub added: BTreeMap<String, Value>,
+    pub removed: BTreeMap<String, Value>,
+    // The old and new value of each key.
+    pub changed: BTreeMap<String, (Value, Value)>,
+}
+
+impl ContextDiff {
+    pub fn between(before: &HashMap<String, Value>, after: &HashMap<String, Value>) -> ContextDiff {
+        let mut diff = ContextDiff::default();
+        for (k, old) in before {
+            match after.get(k) {
+                None => {
+                    diff.removed.insert(k.clone(), old.clone());
+                }
+                Some(new) if new != old => {
+                    diff.changed.insert(k.clone(), (old.clone(), new.clone()));
+                }
+                Some(_) => {}
+            }
+        }
+        for (k, new) in after {
+            if !before.contains_key(k) {
+                diff.added.insert(k.clone(), new.clone());
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    // Makes the same changes to the VM's context, as `setContext` and
+    // `delContext` would.
+    pub fn apply(&self, vm: &mut VM) -> Result<(), VmError> {
+        for k in self.removed.keys() {
+            vm.delete_context(k)?;
+        }
+        for (k, v) in self
+            .added
+            .iter()
+            .chain(self.changed.iter().map(|(k, (_, new))| (k, new)))
+        {
+            vm.set_context(k.clone(), v.clone())?;
+        }
+        Ok(())
+    }
+}
+
+impl VM {
+    // Sets the keys of a JSON object of numbers and strings, as `setContext`
+    // would, leaving other keys alone. Nothing is set if any value is
+    // invalid, and if the store fails to write one the keys already set are
+    // put back.
+    pub fn set_context_from_json(&mut self, json: &serde_json::Value) -> Result<(), VmError> {
+        let mut values: Vec<(String, Value)> = from_json(json)
+            .map_err(|e| self.error(e))?
+            .into_iter()
+            .collect();
+        // in a fixed order, for observers
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut written: Vec<(String, Option<Value>)> = vec![];
+        for (k, v) in values {
+            let old = self.context.get(&k);
+            if let Err(e) = self.set_context(k.clone(), v) {
+                for (k, old) in written.into_iter().rev() {
+                    // the write failure is the error to report
+                    let _ = self.undo(&k, old);
+                }
+                return Err(e);
+            }
+            written.push((k, old));
+        }
+        Ok(())
+    }
+
+    // The context as a JSON object, with sorted keys.
+    pub fn context_to_json(&self) -> serde_json::Value {
+        serde_json::Value::Object(self.context.iter().map(|(k, v)| (k, v.to_json())).collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    Get(String),
+    Has(String),
+    Set(String, Value),
+    Delete(String),
+    Iter,
+}
+
+// Logs every access to another store into a shared list.
+pub struct Recording {
+    inner: Box<dyn ContextStore>,
+    log: Rc<RefCell<Vec<Access>>>,
+}
+
+impl Recording {
+    // Puts a recording around the VM's store, returning the log it fills in.
+    pub fn attach(vm: &mut VM) -> Rc<RefCell<Vec<Access>>> {
+        let log = Rc::new(RefCell::new(vec![]));
+        vm.context = Box::new(Recording {
+            inner: std::mem::take(&mut vm.context),
+            log: log.clone(),
+        });
+        log
+    }
+}
+
+impl ContextStore for Recording {
+    fn get(&self, key: &str) -> Option<Value> {
+        self.log.borrow_mut().push(Access::Get(key.to_string()));
+        self.inner.get(key)
+    }
+
+    fn has(&self, key: &str) -> bool {
+        self.log.borrow_mut().push(Access::Has(key.to_string()));
+        self.inner.has(key)
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> Result<Option<Value>, String> {
+        self.log
+            .borrow_mut()
+            .push(Access::Set(key.to_string(), value.clone()));
+        self.inner.set(key, value)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<Option<Value>, String> {
+        self.log.borrow_mut().push(Access::Delete(key.to_string()));
+        self.inner.delete(key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, Value)> + '_> {
+        self.log.borrow_mut().push(Access::Iter);
+        self.inner.iter()
+    }
+}
@@ -0,0 +1,229 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+use crate::trace::Hooks;
+use crate::vm::{Instr, VM};
+
+// Which instructions ran, and which way each `jz`/`jgz` went, over one or
+// more runs of the same program. Coverage of separate runs can be merged,
+// also across processes through its JSON form.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Branch {
+    // Times the next instruction was skipped.
+    pub skipped: u64,
+    // Times execution went on with the next instruction.
+    pub fell_through: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    // Execution count per pc.
+    pub executed: BTreeMap<usize, u64>,
+    // Outcomes per pc of a `jz` or `jgz`.
+    pub branches: BTreeMap<usize, Branch>,
+}
+
+fn is_branch(instr: Option<&Instr>) -> bool {
+    matches!(instr, Some(Instr::Func(_, name)) if name == "jz" || name == "jgz")
+}
+
+// `label+offset` for a pc inside a label region, or just the pc.
+fn location(vm: &VM, pc: usize) -> String {
+    match vm.label_region(pc) {
+        Some(label) => match pc - vm.labels[label] as usize {
+            0 => label.to_string(),
+            offset => format!("{}+{}", label, offset),
+        },
+        None => pc.to_string(),
+    }
+}
+
+impl Coverage {
+    pub fn merge(&mut self, other: &Coverage) {
+        for (pc, n) in &other.executed {
+            *self.executed.entry(*pc).or_default() += n;
+        }
+        for (pc, b) in &other.branches {
+            let branch = self.branches.entry(*pc).or_default();
+            branch.skipped += b.skipped;
+            branch.fell_through += b.fell_through;
+        }
+    }
+
+    // `{"executed": {"<pc>": count}, "branches": {"<pc>": [skipped, fell_through]}}`
+    pub fn to_json(&self) -> serde_json::Value {
+        let executed: serde_json::Map<String, serde_json::Value> = self
+            .executed
+            .iter()
+            .map(|(pc, n)| (pc.to_string(), serde_json::json!(n)))
+            .collect();
+        let branches: serde_json::Map<String, serde_json::Value> = self
+            .branches
+            .iter()
+            .map(|(pc, b)| {
+                (
+                    pc.to_string(),
+                    serde_json::json!([b.skipped, b.fell_through]),
+                )
+            })
+            .collect();
+        serde_json::json!({ "executed": executed, "branches": branches })
+    }
+
+    pub fn from_json(v: &serde_json::Value) -> Result<Coverage, String> {
+        let entries = |key: &str| -> Result<Vec<(usize, &serde_json::Value)>, String> {
+            let map = match &v[key] {
+                serde_json::Value::Object(map) => map,
+                _ => return Err(format!("coverage has no {} object", key)),
+            };
+            map.iter()
+                .map(|(pc, n)| match pc.parse() {
+                    Ok(pc) => Ok((pc, n)),
+                    Err(_) => Err(format!("invalid pc {}", pc)),
+                })
+                .collect()
+        };
+        let count = |n: &serde_json::Value| n.as_u64().ok_or(format!("invalid count {}", n));
+        let mut coverage = Coverage::default();
+        for (pc, n) in entries("executed")? {
+            coverage.executed.insert(pc, count(n)?);
+        }
+        for (pc, b) in entries("branches")? {
+            let branch = Branch {
+                skipped: count(&b[0])?,
+                fell_through: count(&b[1])?,
+            };
+            coverage.branches.insert(pc, branch);
+        }
+        Ok(coverage)
+    }
+
+    // Maximal runs of instructions that never executed.
+    pub fn uncovered(&self, vm: &VM) -> Vec<std::ops::Range<usize>> {
+        let mut ranges: Vec<std::ops::Range<usize>> = vec![];
+        for pc in 0..vm.programlist.len() {
+            if self.executed.contains_key(&pc) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(r) if r.end == pc => r.end = pc + 1,
+                _ => ranges.push(pc..pc + 1),
+            }
+        }
+        ranges
+    }
+
+    // Summary, uncovered ranges and one-sided branches of the program loaded
+    // in `vm`. `lines` holds the source line of each instruction for text
+    // programs, see `asm::source_lines`.
+    pub fn report(&self, vm: &VM, lines: Option<&[usize]>) -> String {
+        let len = vm.programlist.len();
+        let covered = (0..len).filter(|pc| self.executed.contains_key(pc)).count();
+        let branch_pcs: Vec<usize> = (0..len)
+            .filter(|pc| is_branch(vm.programlist.get(*pc)))
+            .collect();
+        let outcomes = branch_pcs
+            .iter()
+            .map(|pc| match self.branches.get(pc) {
+                Some(b) => (b.skipped > 0) as usize + (b.fell_through > 0) as usize,
+                None => 0,
+            })
+            .sum::<usize>();
+        let percent = |n: usize, of: usize| {
+            if of == 0 {
+                100.0
+            } else {
+                n as f64 * 100.0 / of as f64
+            }
+        };
+
+        let mut out = String::new();
+        writeln!(
+            out,
+            "instructions: {}/{} ({:.1}%), branch outcomes: {}/{} ({:.1}%)",
+            covered,
+            len,
+            percent(covered, len),
+            outcomes,
+            branch_pcs.len() * 2,
+            percent(outcomes, branch_pcs.len() * 2)
+        )
+        .unwrap();
+        let line_of = |pc: usize| lines.and_then(|l| l.get(pc).copied());
+        for r in self.uncovered(vm) {
+            let last = r.end - 1;
+            write!(out, "uncovered {}", r.start).unwrap();
+            if last != r.start {
+                write!(out, "-{}", last).unwrap();
+            }
+            write!(out, " ({}", location(vm, r.start)).unwrap();
+            if last != r.start {
+                write!(out, " to {}", location(vm, last)).unwrap();
+            }
+            match (line_of(r.start), line_of(last)) {
+                (Some(a), Some(b)) if a == b => write!(out, ", line {}", a).unwrap(),
+                (Some(a), Some(b)) => write!(out, ", lines {}-{}", a, b).unwrap(),
+                _ => {}
+            }
+            out.push_str(")\n");
+        }
+        for pc in branch_pcs {
+            let b = match self.branches.get(&pc) {
+                Some(b) => b,
+                None => continue,
+            };
+            let missing = match (b.skipped > 0, b.fell_through > 0) {
+                (true, false) => "never fell through",
+                (false, true) => "never skipped",
+                _ => continue,
+            };
+            write!(
+                out,
+                "branch {} {} ({}",
+                pc,
+                vm.programlist[pc],
+                location(vm, pc)
+            )
+            .unwrap();
+            if let Some(line) = line_of(pc) {
+                write!(out, ", line {}", line).unwrap();
+            }
+            writeln!(out, "): {}", missing).unwrap();
+        }
+        out
+    }
+}
+
+// Hooks that record coverage into a shared `Coverage`.
+pub struct CoverageRecorder {
+    coverage: Rc<RefCell<Coverage>>,
+}
+
+impl CoverageRecorder {
+    // Adds a recorder to the VM's hooks, returning the coverage it fills in.
+    pub fn attach(vm: &mut VM) -> Rc<RefCell<Coverage>> {
+        let coverage = Rc::new(RefCell::new(Coverage::default()));
+        vm.hooks.push(Box::new(CoverageRecorder {
+            coverage: coverage.clone(),
+        }));
+        coverage
+    }
+}
+
+impl Hooks for CoverageRecorder {
+    fn after_instruction(&mut self, vm: &VM, pc: usize) {
+        let mut coverage = self.coverage.borrow_mut();
+        *coverage.executed.entry(pc).or_default() += 1;
+        if is_branch(vm.programlist.get(pc)) {
+            let branch = coverage.branches.entry(pc).or_default();
+            if vm.pc == pc + 2 {
+                branch.skipped += 1;
+            } else {
+                branch.fell_through += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,537 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc;
+use std::thread;
+
+use serde_json::json;
+
+use crate::asm;
+use crate::debugger::{Condition, Debugger, Location, StopReason};
+use crate::vm::{SharedBuffer, Value, VM};
+
+// Debug Adapter Protocol server, so editors can debug Tzo programs through
+// the step debugger.
+//
+// The program is shown as a generated source with one instruction per line,
+// so line n is pc n - 1. There is a single thread and, since Tzo has no
+// calls, a single stack frame, named after the label region the pc is in.
+// Its scopes are the stack (top first), the context and the labels.
+
+const THREAD_ID: i64 = 1;
+const SOURCE_REFERENCE: i64 = 1;
+const STACK_REFERENCE: i64 = 1;
+const CONTEXT_REFERENCE: i64 = 2;
+const LABELS_REFERENCE: i64 = 3;
+
+// Instructions run between checks for incoming requests while the program
+// is running, which is what lets `pause` interrupt it.
+const STEP_BUDGET: usize = 1000;
+
+// Reads one `Content-Length` framed message; `None` at the end of the input.
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<serde_json::Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+    })?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn write_message(out: &mut impl Write, message: &serde_json::Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+pub struct Server<W: Write> {
+    out: W,
+    seq: i64,
+    debugger: Option<Debugger>,
+    program: String,
+    // What the program wrote to stdout, forwarded as output events.
+    output: SharedBuffer,
+    stop_on_entry: bool,
+    running: bool,
+    // Whether the pc is where the program last stopped, see `Debugger::cont_for`.
+    resuming: bool,
+    terminated: bool,
+    // Debugger breakpoint ids set by each kind of request, which replaces
+    // all the ones it set before.
+    source_breakpoints: Vec<usize>,
+    function_breakpoints: Vec<usize>,
+    instruction_breakpoints: Vec<usize>,
+}
+
+impl<W: Write> Server<W> {
+    pub fn new(out: W) -> Server<W> {
+        Server {
+            out,
+            seq: 1,
+            debugger: None,
+            program: String::new(),
+            output: SharedBuffer::default(),
+            stop_on_entry: false,
+            running: false,
+            resuming: false,
+            terminated: false,
+            source_breakpoints: vec![],
+            function_breakpoints: vec![],
+            instruction_breakpoints: vec![],
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    // Whether the program is running and `poll` should be called.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    fn send(&mut self, mut message: serde_json::Value) {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        write_message(&mut self.out, &message).unwrap();
+    }
+
+    fn event(&mut self, event: &str, body: serde_json::Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn flush_output(&mut self) {
+        let output = self.output.take_string();
+        if !output.is_empty() {
+            self.event("output", json!({ "category": "stdout", "output": output }));
+        }
+    }
+
+    // Tells the client why the program stopped.
+    fn report(&mut self, reason: StopReason) {
+        self.flush_output();
+        let body = match reason {
+            StopReason::Step => json!({ "reason": "step" }),
+            StopReason::Breakpoint(id) => {
+                json!({ "reason": "breakpoint", "hitBreakpointIds": [id] })
+            }
+            StopReason::Watchpoint { .. } => json!({ "reason": "data breakpoint" }),
+            StopReason::Paused => json!({ "reason": "pause", "description": "program paused" }),
+            StopReason::Error(e) => {
+                json!({ "reason": "exception", "description": "runtime error", "text": e })
+            }
+            StopReason::Exited | StopReason::Finished => {
+                self.running = false;
+                self.terminated = true;
+                self.event("exited", json!({ "exitCode": 0 }));
+                self.event("terminated", json!({}));
+                return;
+            }
+        };
+        let mut body = body;
+        body["threadId"] = json!(THREAD_ID);
+        body["allThreadsStopped"] = json!(true);
+        self.event("stopped", body);
+    }
+
+    fn debugger(&mut self) -> Result<&mut Debugger, String> {
+        self.debugger
+            .as_mut()
+            .ok_or("no program launched".to_string())
+    }
+
+    // A debugger that can execute instructions, for stepping and continuing.
+    fn stopped_debugger(&mut self) -> Result<&mut Debugger, String> {
+        if self.terminated {
+            return Err("the program has terminated".to_string());
+        }
+        if self.running {
+            return Err("the program is running".to_string());
+        }
+        self.debugger()
+    }
+
+    fn launch(&mut self, args: &serde_json::Value) -> Result<serde_json::Value, String> {
+        let path = args["program"]
+            .as_str()
+            .ok_or("launch needs a program path")?
+            .to_string();
+        let contents = fs::read(&path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let instructions = asm::parse_program_bytes(&contents)
+            .map_err(|e| format!("cannot parse {}: {}", path, e))?;
+        let mut vm = VM::new();
+        vm.try_load(instructions)
+            .map_err(|e| format!("cannot load {}: {}", path, e))?;
+        vm.stdout = Box::new(self.output.clone());
+        self.debugger = Some(Debugger::new(vm));
+        self.program = path;
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.terminated = false;
+        Ok(json!({}))
+    }
+
+    // Removes the breakpoints in `old` and adds `new`, returning the DAP
+    // breakpoint objects in the order of `new`.
+    fn replace_breakpoints(
+        &mut self,
+        old: Vec<usize>,
+        new: Vec<Result<(Location, Option<Condition>), String>>,
+    ) -> Result<(Vec<usize>, serde_json::Value), String> {
+        let debugger = self.debugger()?;
+        for id in old {
+            debugger.remove(id);
+        }
+        let mut ids = vec![];
+        let mut breakpoints = vec![];
+        for b in new {
+            match b {
+                Ok((location, condition)) => {
+                    let pc = match &location {
+                        Location::Pc(pc) => Some(*pc),
+                        Location::Label(l) => debugger.vm.labels.get(l).map(|pc| *pc as usize),
+                        Location::Function(_) => None,
+                    };
+                    let id = debugger.add_breakpoint(location, condition);
+                    ids.push(id);
+                    let mut breakpoint = json!({ "id": id, "verified": true });
+                    if let Some(pc) = pc {
+                        if pc >= debugger.vm.programlist.len() {
+                            breakpoint["verified"] = json!(false);
+                            breakpoint["message"] = json!("no instruction there");
+                        }
+                        breakpoint["line"] = json!(pc + 1);
+                        breakpoint["instructionReference"] = json!(pc.to_string());
+                    }
+                    breakpoints.push(breakpoint);
+                }
+                Err(e) => breakpoints.push(json!({ "verified": false, "message": e })),
+            }
+        }
+        Ok((ids, serde_json::Value::Array(breakpoints)))
+    }
+
+    fn condition(b: &serde_json::Value) -> Result<Option<Condition>, String> {
+        match b["condition"].as_str() {
+            Some(c) if !c.trim().is_empty() => Condition::parse(c.trim()).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn breakpoint_list(args: &serde_json::Value) -> Vec<serde_json::Value> {
+        args["breakpoints"].as_array().cloned().unwrap_or_default()
+    }
+
+    // The generated source: one instruction per line, prefixed with its
+    // labels.
+    fn source(vm: &VM) -> String {
+        let mut out = String::new();
+        for (pc, instr) in vm.programlist.iter().enumerate() {
+            let mut labels: Vec<&String> = vm
+                .labels
+                .iter()
+                .filter(|(_, l)| **l as usize == pc)
+                .map(|(k, _)| k)
+                .collect();
+            labels.sort();
+            for label in labels {
+                out.push_str(label);
+                out.push_str(": ");
+            }
+            out.push_str(&instr.to_string());
+            out.push('\n');
+        }
+        out
+    }
+
+    fn variables(&mut self, args: &serde_json::Value) -> Result<serde_json::Value, String> {
+        let vm = &self.debugger()?.vm;
+        let variable = |name: String, value: serde_json::Value| {
+            let kind = if value.is_string() {
+                "string"
+            } else {
+                "number"
+            };
+            json!({ "name": name, "value": value.to_string(), "type": kind, "variablesReference": 0 })
+        };
+        let variables: Vec<serde_json::Value> = match args["variablesReference"].as_i64() {
+            Some(STACK_REFERENCE) => vm
+                .stack
+                .iter()
+                .rev()
+                .enumerate()
+                .map(|(depth, v)| variable(depth.to_string(), v.to_json()))
+                .collect(),
+            Some(CONTEXT_REFERENCE) => {
+                let mut context: Vec<(String, Value)> = vm.context.iter().collect();
+                context.sort_by(|a, b| a.0.cmp(&b.0));
+                context
+                    .into_iter()
+                    .map(|(k, v)| variable(k, v.to_json()))
+                    .collect()
+            }
+            Some(LABELS_REFERENCE) => {
+                let mut labels: Vec<(&String, &i64)> = vm.labels.iter().collect();
+                labels.sort_by_key(|(name, pc)| (**pc, *name));
+                labels
+                    .into_iter()
+                    .map(|(name, pc)| variable(name.clone(), json!(pc)))
+                    .collect()
+            }
+            _ => return Err("unknown variablesReference".to_string()),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    // Runs a request, returning the response body and, for requests that
+    // execute instructions, why the program stopped.
+    fn dispatch(
+        &mut self,
+        command: &str,
+        args: &serde_json::Value,
+    ) -> Result<(serde_json::Value, Option<StopReason>), String> {
+        let mut stop = None;
+        let body = match command {
+            "initialize" => json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsTerminateRequest": true,
+            }),
+            "launch" => self.launch(args)?,
+            "setBreakpoints" => {
+                let new = Self::breakpoint_list(args)
+                    .iter()
+                    .map(|b| {
+                        let line = b["line"].as_u64().ok_or("breakpoint without a line")?;
+                        let pc = (line as usize).checked_sub(1).ok_or("lines start at 1")?;
+                        Ok((Location::Pc(pc), Self::condition(b)?))
+                    })
+                    .collect();
+                let old = std::mem::take(&mut self.source_breakpoints);
+                let (ids, breakpoints) = self.replace_breakpoints(old, new)?;
+                self.source_breakpoints = ids;
+                json!({ "breakpoints": breakpoints })
+            }
+            "setFunctionBreakpoints" => {
+                let debugger = self.debugger()?;
+                let new = Self::breakpoint_list(args)
+                    .iter()
+                    .map(|b| {
+                        let name = b["name"].as_str().ok_or("breakpoint without a name")?;
+                        Ok((debugger.parse_location(name.trim()), Self::condition(b)?))
+                    })
+                    .collect();
+                let old = std::mem::take(&mut self.function_breakpoints);
+                let (ids, breakpoints) = self.replace_breakpoints(old, new)?;
+                self.function_breakpoints = ids;
+                json!({ "breakpoints": breakpoints })
+            }
+            "setInstructionBreakpoints" => {
+                let new = Self::breakpoint_list(args)
+                    .iter()
+                    .map(|b| {
+                        let pc: i64 = b["instructionReference"]
+                            .as_str()
+                            .and_then(|r| r.parse().ok())
+                            .ok_or("invalid instructionReference")?;
+                        let pc = pc + b["offset"].as_i64().unwrap_or(0);
+                        let pc = usize::try_from(pc).map_err(|_| "negative pc".to_string())?;
+                        Ok((Location::Pc(pc), Self::condition(b)?))
+                    })
+                    .collect();
+                let old = std::mem::take(&mut self.instruction_breakpoints);
+                let (ids, breakpoints) = self.replace_breakpoints(old, new)?;
+                self.instruction_breakpoints = ids;
+                json!({ "breakpoints": breakpoints })
+            }
+            "configurationDone" => {
+                self.debugger()?;
+                if self.stop_on_entry {
+                    stop = Some(StopReason::Step);
+                } else {
+                    self.running = true;
+                    self.resuming = false;
+                }
+                json!({})
+            }
+            "threads" => json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+            "stackTrace" => {
+                let program = self.program.clone();
+                let vm = &self.debugger()?.vm;
+                let pc = vm.pc;
+                let name = std::path::Path::new(&program)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or(program);
+                json!({
+                    "stackFrames": [{
+                        "id": 0,
+                        "name": vm.label_region(pc).unwrap_or("main"),
+                        "line": pc + 1,
+                        "column": 1,
+                        "source": { "name": name, "sourceReference": SOURCE_REFERENCE },
+                        "instructionPointerReference": pc.to_string(),
+                    }],
+                    "totalFrames": 1,
+                })
+            }
+            "source" => {
+                let vm = &self.debugger()?.vm;
+                json!({ "content": Self::source(vm), "mimeType": "text/x-tzo" })
+            }
+            "scopes" => {
+                let vm = &self.debugger()?.vm;
+                json!({ "scopes": [
+                    { "name": "Stack", "variablesReference": STACK_REFERENCE,
+                      "indexedVariables": vm.stack.len(), "expensive": false },
+                    { "name": "Context", "variablesReference": CONTEXT_REFERENCE,
+                      "namedVariables": vm.context.iter().count(), "expensive": false },
+                    { "name": "Labels", "variablesReference": LABELS_REFERENCE,
+                      "namedVariables": vm.labels.len(), "expensive": false },
+                ]})
+            }
+            "variables" => self.variables(args)?,
+            "continue" => {
+                self.stopped_debugger()?;
+                self.running = true;
+                self.resuming = true;
+                json!({ "allThreadsContinued": true })
+            }
+            "next" => {
+                stop = Some(self.stopped_debugger()?.step_over());
+                json!({})
+            }
+            "stepIn" => {
+                stop = Some(self.stopped_debugger()?.step());
+                json!({})
+            }
+            "stepOut" => return Err("Tzo has no calls to step out of".to_string()),
+            "pause" => {
+                if self.running {
+                    self.running = false;
+                    stop = Some(StopReason::Paused);
+                }
+                json!({})
+            }
+            "disconnect" | "terminate" => json!({}),
+            _ => return Err(format!("unsupported request {}", command)),
+        };
+        Ok((body, stop))
+    }
+
+    // Handles one message from the client; returns false when the session
+    // is over.
+    pub fn handle(&mut self, message: &serde_json::Value) -> bool {
+        if message["type"] != "request" {
+            return true;
+        }
+        let command = message["command"].as_str().unwrap_or("").to_string();
+        let mut response = json!({
+            "type": "response",
+            "request_seq": message["seq"],
+            "command": command,
+        });
+        let stop = match self.dispatch(&command, &message["arguments"]) {
+            Ok((body, stop)) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+                stop
+            }
+            Err(e) => {
+                response["success"] = json!(false);
+                response["message"] = json!(e);
+                None
+            }
+        };
+        self.send(response);
+        match command.as_str() {
+            "initialize" => self.event("initialized", json!({})),
+            "disconnect" => return false,
+            "terminate" => {
+                self.running = false;
+                self.terminated = true;
+                self.event("terminated", json!({}));
+            }
+            _ => {}
+        }
+        if let Some(reason) = stop {
+            self.report(reason);
+        }
+        true
+    }
+
+    // Runs the program for a while if it is running, reporting when it stops.
+    pub fn poll(&mut self) {
+        if !self.running {
+            return;
+        }
+        let resuming = self.resuming;
+        self.resuming = false;
+        let reason = match self.debugger.as_mut() {
+            Some(debugger) => debugger.cont_for(STEP_BUDGET, resuming),
+            None => return,
+        };
+        match reason {
+            Some(reason) => {
+                self.running = false;
+                self.report(reason);
+            }
+            None => self.flush_output(),
+        }
+    }
+}
+
+// Serves one debug session, reading requests from `input` on a separate
+// thread so that a running program can be paused. Returns the output once
+// the client disconnects or the input ends.
+pub fn serve<R: BufRead + Send + 'static, W: Write>(input: R, output: W) -> W {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = input;
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    let mut server = Server::new(output);
+    loop {
+        let message = if server.is_running() {
+            match receiver.try_recv() {
+                Ok(message) => Some(message),
+                Err(mpsc::TryRecvError::Empty) => None,
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match receiver.recv() {
+                Ok(message) => Some(message),
+                Err(_) => break,
+            }
+        };
+        if let Some(message) = message {
+            if !server.handle(&message) {
+                break;
+            }
+        }
+        server.poll();
+    }
+    server.into_inner()
+}
@@ -0,0 +1,473 @@
+use std::fmt::Write;
+
+use crate::asm;
+use crate::vm::{Instr, Value, VM};
+
+// Step debugger on top of `VM::step`.
+//
+// Breakpoints are checked before an instruction executes and watchpoints
+// after it, so a stop always leaves the VM's pc at the next instruction to
+// run.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    Pc(usize),
+    // Resolved on every check, so it also works for labels loaded later.
+    Label(String),
+    // Any call of the named built-in or foreign function.
+    Function(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Depth,
+    Top,
+    Context(String),
+}
+
+// A comparison like `depth > 3`, `top == "x"` or `ctx.hp <= 0`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub operand: Operand,
+    pub op: CmpOp,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Watchpoint {
+    // Triggers whenever the key is set to a different value or deleted.
+    Context(String),
+    // Triggers when the condition goes from false to true.
+    Condition(Condition),
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub location: Location,
+    pub condition: Option<Condition>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    Step,
+    Breakpoint(usize),
+    // `old` and `new` hold the context value for `Watchpoint::Context` and are
+    // `None` for conditions.
+    Watchpoint {
+        id: usize,
+        old: Option<Value>,
+        new: Option<Value>,
+    },
+    Paused,
+    Exited,
+    // The pc ran past the end of the program.
+    Finished,
+    Error(String),
+}
+
+impl CmpOp {
+    fn parse(s: &str) -> Option<CmpOp> {
+        Some(match s {
+            "==" => CmpOp::Eq,
+            "!=" => CmpOp::Ne,
+            "<" => CmpOp::Lt,
+            "<=" => CmpOp::Le,
+            ">" => CmpOp::Gt,
+            ">=" => CmpOp::Ge,
+            _ => return None,
+        })
+    }
+
+    fn holds(self, ordering: Option<std::cmp::Ordering>) -> bool {
+        use std::cmp::Ordering::*;
+        match (self, ordering) {
+            (CmpOp::Ne, None) => true,
+            (_, None) => false,
+            (CmpOp::Eq, Some(o)) => o == Equal,
+            (CmpOp::Ne, Some(o)) => o != Equal,
+            (CmpOp::Lt, Some(o)) => o == Less,
+            (CmpOp::Le, Some(o)) => o != Greater,
+            (CmpOp::Gt, Some(o)) => o == Greater,
+            (CmpOp::Ge, Some(o)) => o != Less,
+        }
+    }
+}
+
+impl Condition {
+    // Parses `<operand> <op> <value>` where operand is `depth`, `top` or
+    // `ctx.<key>` and value is a number or a JSON string.
+    pub fn parse(s: &str) -> Result<Condition, String> {
+        let parts: Vec<&str> = s.splitn(3, char::is_whitespace).collect();
+        if parts.len() != 3 {
+            return Err(format!("expected `<operand> <op> <value>`, got {:?}", s));
+        }
+        let operand = match parts[0] {
+            "depth" => Operand::Depth,
+            "top" => Operand::Top,
+            other => match other.strip_prefix("ctx.") {
+                Some(key) => Operand::Context(key.to_string()),
+                None => return Err(format!("unknown operand {}", other)),
+            },
+        };
+        let op = CmpOp::parse(parts[1]).ok_or(format!("unknown comparison {}", parts[1]))?;
+        let value = match serde_json::from_str::<serde_json::Value>(parts[2].trim()) {
+            Ok(v) => Value::from_json(&v),
+            Err(_) => None,
+        }
+        .ok_or(format!("invalid value {}", parts[2]))?;
+        Ok(Condition { operand, op, value })
+    }
+
+    pub fn eval(&self, vm: &VM) -> bool {
+        let actual = match &self.operand {
+            Operand::Depth => Value::Number(vm.stack.len() as f64),
+            Operand::Top => match vm.stack.last() {
+                Some(v) => v.clone(),
+                None => return false,
+            },
+            Operand::Context(key) => match vm.context.get(key) {
+                Some(v) => v,
+                None => return false,
+            },
+        };
+        let ordering = match (&actual, &self.value) {
+            (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            _ => None,
+        };
+        self.op.holds(ordering)
+    }
+}
+
+pub struct Debugger {
+    pub vm: VM,
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<(usize, Watchpoint)>,
+    next_id: usize,
+}
+
+impl Debugger {
+    pub fn new(vm: VM) -> Debugger {
+        Debugger {
+            vm,
+            breakpoints: vec![],
+            watchpoints: vec![],
+            next_id: 1,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, location: Location, condition: Option<Condition>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint {
+            id,
+            location,
+            condition,
+        });
+        id
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watchpoints.push((id, watchpoint));
+        id
+    }
+
+    // Removes a breakpoint or watchpoint; returns whether it existed.
+    pub fn remove(&mut self, id: usize) -> bool {
+        let before = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.watchpoints.retain(|(i, _)| *i != id);
+        before != self.breakpoints.len() + self.watchpoints.len()
+    }
+
+    fn breakpoint_at_pc(&self) -> Option<usize> {
+        let pc = self.vm.pc;
+        self.breakpoints
+            .iter()
+            .find(|b| {
+                let hit = match &b.location {
+                    Location::Pc(p) => *p == pc,
+                    Location::Label(l) => self.vm.labels.get(l).map(|p| *p as usize) == Some(pc),
+                    Location::Function(f) => {
+                        matches!(self.vm.programlist.get(pc), Some(Instr::Func(_, name)) if name == f)
+                    }
+                };
+                hit && b.condition.as_ref().is_none_or(|c| c.eval(&self.vm))
+            })
+            .map(|b| b.id)
+    }
+
+    // Executes one instruction, returning why execution has to stop, if it does.
+    fn execute(&mut self) -> Option<StopReason> {
+        if self.vm.exited {
+            return Some(StopReason::Exited);
+        }
+        if self.vm.pc >= self.vm.programlist.len() {
+            return Some(StopReason::Finished);
+        }
+        let watched: Vec<(Option<Value>, bool)> = self
+            .watchpoints
+            .iter()
+            .map(|(_, w)| match w {
+                Watchpoint::Context(key) => (self.vm.context.get(key), false),
+                Watchpoint::Condition(c) => (None, c.eval(&self.vm)),
+            })
+            .collect();
+
+        self.vm.running = true;
+        if let Err(e) = self.vm.step() {
+            return Some(StopReason::Error(e.to_string()));
+        }
+        if self.vm.exited {
+            return Some(StopReason::Exited);
+        }
+        if !self.vm.running {
+            return Some(StopReason::Paused);
+        }
+
+        for ((id, w), (old, held)) in self.watchpoints.iter().zip(watched) {
+            match w {
+                Watchpoint::Context(key) => {
+                    let new = self.vm.context.get(key);
+                    if new != old {
+                        return Some(StopReason::Watchpoint { id: *id, old, new });
+                    }
+                }
+                Watchpoint::Condition(c) => {
+                    if !held && c.eval(&self.vm) {
+                        return Some(StopReason::Watchpoint {
+                            id: *id,
+                            old: None,
+                            new: None,
+                        });
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    pub fn step(&mut self) -> StopReason {
+        self.execute().unwrap_or(StopReason::Step)
+    }
+
+    // Like `step`, but runs a whole brace block that a `jz`/`jgz` would enter
+    // as one step.
+    pub fn step_over(&mut self) -> StopReason {
+        let pc = self.vm.pc;
+        let is_jump = matches!(
+            self.vm.programlist.get(pc),
+            Some(Instr::Func(_, name)) if name == "jz" || name == "jgz"
+        );
+        let block = match self.vm.programlist.get(pc + 1) {
+            Some(Instr::OpenBrace) if is_jump => self.vm.matching_brace(pc + 1),
+            _ => None,
+        };
+        let close = match block {
+            Some(close) => close,
+            None => return self.step(),
+        };
+        if let Some(reason) = self.execute() {
+            return reason;
+        }
+        while self.vm.pc > pc && self.vm.pc <= close {
+            if let Some(id) = self.breakpoint_at_pc() {
+                return StopReason::Breakpoint(id);
+            }
+            if let Some(reason) = self.execute() {
+                return reason;
+            }
+        }
+        StopReason::Step
+    }
+
+    // Runs until a breakpoint or watchpoint triggers or the program stops. A
+    // breakpoint at the current pc doesn't trigger, so that continuing from
+    // it makes progress.
+    pub fn cont(&mut self) -> StopReason {
+        self.cont_for(usize::MAX, true).unwrap()
+    }
+
+    // Like `cont`, but gives up after `budget` instructions and returns `None`.
+    // `resuming` says whether the pc is where execution last stopped; pass
+    // false when carrying on after a previous call ran out of budget.
+    pub fn cont_for(&mut self, budget: usize, resuming: bool) -> Option<StopReason> {
+        for n in 0..budget {
+            if n > 0 || !resuming {
+                if let Some(id) = self.breakpoint_at_pc() {
+                    return Some(StopReason::Breakpoint(id));
+                }
+            }
+            if let Some(reason) = self.execute() {
+                return Some(reason);
+            }
+        }
+        None
+    }
+
+    // Disassembly of the instructions around the pc.
+    pub fn listing(&self, radius: usize) -> String {
+        let pc = self.vm.pc;
+        asm::disassemble(&self.vm, pc.saturating_sub(radius)..pc + radius + 1)
+    }
+
+    fn describe(&self, reason: &StopReason) -> String {
+        let mut out = String::new();
+        match reason {
+            StopReason::Step => {}
+            StopReason::Breakpoint(id) => writeln!(out, "breakpoint {} hit", id).unwrap(),
+            StopReason::Watchpoint { id, old, new } => {
+                let show = |v: &Option<Value>| match v {
+                    Some(v) => v.to_json().to_string(),
+                    None => "(unset)".to_string(),
+                };
+                if old.is_some() || new.is_some() {
+                    writeln!(out, "watchpoint {}: {} -> {}", id, show(old), show(new)).unwrap();
+                } else {
+                    writeln!(out, "watchpoint {} triggered", id).unwrap();
+                }
+            }
+            StopReason::Paused => out.push_str("program paused\n"),
+            StopReason::Exited => return "program exited".to_string(),
+            StopReason::Finished => return "program finished".to_string(),
+            StopReason::Error(e) => writeln!(out, "runtime error: {}", e).unwrap(),
+        }
+        out.push_str(&self.listing(3));
+        let stack: Vec<serde_json::Value> = self.vm.stack.iter().map(|v| v.to_json()).collect();
+        write!(out, "stack: {}", serde_json::Value::Array(stack)).unwrap();
+        out
+    }
+
+    // A pc if `s` is a number, else a label if one has that name, else a
+    // function name.
+    pub fn parse_location(&self, s: &str) -> Location {
+        if let Ok(pc) = s.parse() {
+            Location::Pc(pc)
+        } else if self.vm.labels.contains_key(s) {
+            Location::Label(s.to_string())
+        } else {
+            Location::Function(s.to_string())
+        }
+    }
+
+    // Handles one debugger command and returns the text to show, or `None`
+    // when the session should end.
+    pub fn command(&mut self, line: &str) -> Option<String> {
+        let line = line.trim();
+        let (command, arg) = match line.split_once(char::is_whitespace) {
+            Some((c, a)) => (c, a.trim()),
+            None => (line, ""),
+        };
+        Some(match command {
+            "q" | "quit" => return None,
+            "h" | "help" => HELP.to_string(),
+            "s" | "step" => {
+                let reason = self.step();
+                self.describe(&reason)
+            }
+            "n" | "next" => {
+                let reason = self.step_over();
+                self.describe(&reason)
+            }
+            "c" | "continue" => {
+                let reason = self.cont();
+                self.describe(&reason)
+            }
+            "l" | "list" => self
+                .listing(arg.parse().unwrap_or(5))
+                .trim_end()
+                .to_string(),
+            "stack" => {
+                let stack: Vec<serde_json::Value> =
+                    self.vm.stack.iter().map(|v| v.to_json()).collect();
+                serde_json::Value::Array(stack).to_string()
+            }
+            "ctx" => {
+                let context: serde_json::Map<String, serde_json::Value> = self
+                    .vm
+                    .context
+                    .iter()
+                    .map(|(k, v)| (k, v.to_json()))
+                    .collect();
+                serde_json::Value::Object(context).to_string()
+            }
+            "b" | "break" => {
+                let (location, condition) = match arg.split_once(" if ") {
+                    Some((l, c)) => match Condition::parse(c.trim()) {
+                        Ok(c) => (l.trim(), Some(c)),
+                        Err(e) => return Some(format!("error: {}", e)),
+                    },
+                    None => (arg, None),
+                };
+                if location.is_empty() {
+                    return Some("error: break needs a pc, label or function".to_string());
+                }
+                let location = self.parse_location(location);
+                let desc = format!("{:?}", location);
+                let id = self.add_breakpoint(location, condition);
+                format!("breakpoint {} at {}", id, desc)
+            }
+            "w" | "watch" => {
+                let watchpoint = match arg.strip_prefix("ctx.") {
+                    Some(key) if !key.contains(char::is_whitespace) => {
+                        Watchpoint::Context(key.to_string())
+                    }
+                    _ => match Condition::parse(arg) {
+                        Ok(c) => Watchpoint::Condition(c),
+                        Err(e) => return Some(format!("error: {}", e)),
+                    },
+                };
+                let id = self.add_watchpoint(watchpoint);
+                format!("watchpoint {}", id)
+            }
+            "d" | "delete" => match arg.parse() {
+                Ok(id) if self.remove(id) => format!("deleted {}", id),
+                _ => format!("error: no breakpoint or watchpoint {}", arg),
+            },
+            "info" => {
+                let mut out = String::new();
+                for b in &self.breakpoints {
+                    write!(out, "breakpoint {} at {:?}", b.id, b.location).unwrap();
+                    if let Some(c) = &b.condition {
+                        write!(out, " if {:?}", c).unwrap();
+                    }
+                    out.push('\n');
+                }
+                for (id, w) in &self.watchpoints {
+                    writeln!(out, "watchpoint {} on {:?}", id, w).unwrap();
+                }
+                out.trim_end().to_string()
+            }
+            "" => String::new(),
+            _ => format!("error: unknown command {}, try help", command),
+        })
+    }
+}
+
+pub const HELP: &str = "  s, step                  execute one instruction
+  n, next                  like step, but runs a brace block entered by jz/jgz in one go
+  c, continue              run until a breakpoint, a watchpoint, or the program stops
+  b, break <where> [if <condition>]
+                           break at a pc, a label or any call of a function
+  w, watch ctx.<key>       stop when a context value changes
+  w, watch <condition>     stop when a condition becomes true
+  d, delete <id>           delete a breakpoint or watchpoint
+  info                     list breakpoints and watchpoints
+  l, list [n]              disassemble n instructions around the pc
+  stack, ctx               show the stack or the context
+  q, quit                  leave the debugger
+
+conditions: depth|top|ctx.<key> ==|!=|<|<=|>|>= <number or \"string\">";
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::conformance::{self, Outcome};
+use crate::vm::Value;
+
+// Differential testing against a reference implementation of Tzo, such as
+// the TypeScript one, run as a subprocess. The reference reads a fixture
+// (`input_program` and `initial_context`) from stdin and prints one JSON
+// object:
+//
+//   {"stack": [...], "context": {...}, "stdout": "...", "error": "...",
+//    "exited": false, "paused": false}
+//
+// where `error` is only present if the program failed, and `exited` and
+// `paused` default to false. Error messages differ between implementations,
+// so only whether there was an error is compared, and the stack and context
+// are only compared when neither run failed.
+
+pub struct Reference {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl Reference {
+    // `node $TZO_REFERENCE`, if the variable is set and node can be run.
+    pub fn from_env() -> Option<Reference> {
+        let script = env::var("TZO_REFERENCE").ok()?;
+        let node = Command::new("node").arg("--version").output().ok()?;
+        if !node.status.success() {
+            return None;
+        }
+        Some(Reference {
+            program: "node".to_string(),
+            args: vec![script],
+        })
+    }
+
+    pub fn run(&self, fixture: &serde_json::Value) -> Result<Outcome, String> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("cannot start {}: {}", self.program, e))?;
+        let input = serde_json::json!({
+            "input_program": fixture["input_program"],
+            "initial_context": fixture["initial_context"],
+        });
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.to_string().as_bytes())
+            .map_err(|e| format!("cannot write to the reference: {}", e))?;
+        let output = child
+            .wait_with_output()
+            .map_err(|e| format!("reference failed: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "reference failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let v: serde_json::Value = serde_json::from_slice(&output.stdout)
+            .map_err(|e| format!("invalid reference output: {}", e))?;
+        parse_outcome(&v)
+    }
+}
+
+fn parse_outcome(v: &serde_json::Value) -> Result<Outcome, String> {
+    let value = |v: &serde_json::Value| Value::from_json(v).ok_or(format!("invalid value {}", v));
+    let stack = match &v["stack"] {
+        serde_json::Value::Array(stack) => stack.iter().map(value).collect::<Result<_, _>>()?,
+        _ => return Err("reference output has no stack".to_string()),
+    };
+    let context = match &v["context"] {
+        serde_json::Value::Object(context) => context
+            .iter()
+            .map(|(k, v)| Ok((k.clone(), value(v)?)))
+            .collect::<Result<HashMap<String, Value>, String>>()?,
+        _ => return Err("reference output has no context".to_string()),
+    };
+    Ok(Outcome {
+        stack,
+        context,
+        stdout: v["stdout"].as_str().unwrap_or("").to_string(),
+        error: v["error"].as_str().map(|e| e.to_string()),
+        exited: v["exited"].as_bool().unwrap_or(false),
+        paused: v["paused"].as_bool().unwrap_or(false),
+    })
+}
+
+// Differences between this VM's outcome and the reference's.
+pub fn diff(ours: &Outcome, theirs: &Outcome) -> Vec<String> {
+    let mut diffs = vec![];
+    let mut differ = |what: &str, ours: serde_json::Value, theirs: serde_json::Value| {
+        if ours != theirs {
+            diffs.push(format!("{}: here {}, reference {}", what, ours, theirs));
+        }
+    };
+    let stack =
+        |o: &Outcome| serde_json::json!(o.stack.iter().map(|v| v.to_json()).collect::<Vec<_>>());
+    let context = |o: &Outcome| {
+        serde_json::Value::Object(
+            o.context
+                .iter()
+                .map(|(k, v)| (k.clone(), v.to_json()))
+                .collect(),
+        )
+    };
+    differ(
+        "error",
+        serde_json::json!(ours.error.is_some()),
+        serde_json::json!(theirs.error.is_some()),
+    );
+    if ours.error.is_none() && theirs.error.is_none() {
+        differ("stack", stack(ours), stack(theirs));
+        differ("context", context(ours), context(theirs));
+        differ(
+            "exited",
+            serde_json::json!(ours.exited),
+            serde_json::json!(theirs.exited),
+        );
+        differ(
+            "paused",
+            serde_json::json!(ours.paused),
+            serde_json::json!(theirs.paused),
+        );
+    }
+    differ(
+        "stdout",
+        serde_json::json!(ours.stdout),
+        serde_json::json!(theirs.stdout),
+    );
+    diffs
+}
+
+// Runs a fixture here and in the reference, returning the differences.
+pub fn compare(reference: &Reference, fixture: &serde_json::Value) -> Result<Vec<String>, String> {
+    let ours = conformance::run(conformance::load(fixture)?);
+    let theirs = reference.run(fixture)?;
+    Ok(diff(&ours, &theirs))
+}
@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::rc::Rc;
+
+use crate::vm::{ForeignFunc, StackSignature, Value, ValueType, VmError, VM};
+
+// Typed foreign functions, registered with `VM::register`:
+//
+//   vm.register("dist", |x: f64, y: f64| (x * x + y * y).sqrt());
+//
+// Arguments are taken from the top of the stack so that they read in push
+// order: in `3 4 dist`, `x` is 3 and `y` is 4, i.e. the last argument is the
+// top of the stack. They are converted with `FromValue` and only popped once
+// they all have the right type; a function with too few arguments or one of
+// the wrong type fails with an error naming it. The return value is pushed
+// with `IntoValues`: `()` pushes nothing, `Option` pushes its value if there
+// is one, tuples push their elements in order, so the last ends up on top,
+// and `Result` pushes its `Ok` value or fails with its error.
+//
+// The stack signature the verifier checks programs against is derived from
+// the argument and return types.
+
+// A type arguments can be converted to.
+pub trait FromValue: Sized {
+    const TYPE: ValueType;
+    // What the argument must be, for error messages.
+    const EXPECTED: &'static str;
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+impl FromValue for f64 {
+    const TYPE: ValueType = ValueType::Number;
+    const EXPECTED: &'static str = "a number";
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for i64 {
+    const TYPE: ValueType = ValueType::Number;
+    const EXPECTED: &'static str = "an integer";
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            // i64::MAX as f64 rounds up to 2^63, which doesn't fit
+            Value::Number(n)
+                if n.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(n) =>
+            {
+                Some(*n as i64)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Like `jz`, anything but 0 is true.
+impl FromValue for bool {
+    const TYPE: ValueType = ValueType::Number;
+    const EXPECTED: &'static str = "a number";
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(n) => Some(*n != 0.0),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for String {
+    const TYPE: ValueType = ValueType::String;
+    const EXPECTED: &'static str = "a string";
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::String(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for Value {
+    const TYPE: ValueType = ValueType::Any;
+    const EXPECTED: &'static str = "a value";
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(value.clone())
+    }
+}
+
+// A type that can be pushed as a single value.
+pub trait IntoValue {
+    const TYPE: ValueType;
+    fn into_value(self) -> Value;
+}
+
+impl IntoValue for f64 {
+    const TYPE: ValueType = ValueType::Number;
+    fn into_value(self) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl IntoValue for i64 {
+    const TYPE: ValueType = ValueType::Number;
+    fn into_value(self) -> Value {
+        Value::Number(self as f64)
+    }
+}
+
+// Pushed as 1 or 0, like `eq` and the other comparisons.
+impl IntoValue for bool {
+    const TYPE: ValueType = ValueType::Number;
+    fn into_value(self) -> Value {
+        Value::Number(if self { 1.0 } else { 0.0 })
+    }
+}
+
+impl IntoValue for String {
+    const TYPE: ValueType = ValueType::String;
+    fn into_value(self) -> Value {
+        Value::String(self)
+    }
+}
+
+impl IntoValue for &str {
+    const TYPE: ValueType = ValueType::String;
+    fn into_value(self) -> Value {
+        Value::String(self.to_string())
+    }
+}
+
+impl IntoValue for Value {
+    const TYPE: ValueType = ValueType::Any;
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+// What a typed foreign function returns: any number of values.
+pub trait IntoValues {
+    // The types pushed, in push order, or None if that varies.
+    fn types() -> Option<Vec<ValueType>>;
+    // Fails with the message of the runtime error to report.
+    fn push_onto(self, stack: &mut Vec<Value>) -> Result<(), String>;
+}
+
+impl<T: IntoValue> IntoValues for T {
+    fn types() -> Option<Vec<ValueType>> {
+        Some(vec![T::TYPE])
+    }
+    fn push_onto(self, stack: &mut Vec<Value>) -> Result<(), String> {
+        stack.push(self.into_value());
+        Ok(())
+    }
+}
+
+impl IntoValues for () {
+    fn types() -> Option<Vec<ValueType>> {
+        Some(vec![])
+    }
+    fn push_onto(self, _stack: &mut Vec<Value>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl<T: IntoValue> IntoValues for Option<T> {
+    fn types() -> Option<Vec<ValueType>> {
+        None
+    }
+    fn push_onto(self, stack: &mut Vec<Value>) -> Result<(), String> {
+        if let Some(v) = self {
+            stack.push(v.into_value());
+        }
+        Ok(())
+    }
+}
+
+impl<T: IntoValues, E: fmt::Display> IntoValues for Result<T, E> {
+    fn types() -> Option<Vec<ValueType>> {
+        T::types()
+    }
+    fn push_onto(self, stack: &mut Vec<Value>) -> Result<(), String> {
+        match self {
+            Ok(v) => v.push_onto(stack),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+macro_rules! tuple_into_values {
+    ($($t:ident $v:ident),+) => {
+        impl<$($t: IntoValue),+> IntoValues for ($($t,)+) {
+            fn types() -> Option<Vec<ValueType>> {
+                Some(vec![$($t::TYPE),+])
+            }
+            fn push_onto(self, stack: &mut Vec<Value>) -> Result<(), String> {
+                let ($($v,)+) = self;
+                $(stack.push($v.into_value());)+
+                Ok(())
+            }
+        }
+    };
+}
+
+tuple_into_values!(A a, B b);
+tuple_into_values!(A a, B b, C c);
+tuple_into_values!(A a, B b, C c, D d);
+
+fn describe(value: &Value) -> String {
+    match value {
+        Value::Number(n) => format!("number {}", n),
+        Value::String(s) => format!("string {}", serde_json::Value::from(s.as_str())),
+    }
+}
+
+// A Rust function or closure taking `Args`, callable from Tzo.
+pub trait TypedFunc<Args>: 'static {
+    fn signature() -> Option<StackSignature>;
+    fn call(&self, name: &str, vm: &mut VM) -> Result<(), VmError>;
+}
+
+macro_rules! typed_func {
+    ($($t:ident $v:ident),*) => {
+        impl<F, R, $($t),*> TypedFunc<($($t,)*)> for F
+        where
+            F: Fn($($t),*) -> R + 'static,
+            R: IntoValues,
+            $($t: FromValue,)*
+        {
+            fn signature() -> Option<StackSignature> {
+                // top of stack first
+                let mut inputs = vec![$($t::TYPE),*];
+                inputs.reverse();
+                Some(StackSignature {
+                    inputs,
+                    outputs: R::types()?,
+                })
+            }
+
+            #[allow(unused_mut, unused_variables)]
+            fn call(&self, name: &str, vm: &mut VM) -> Result<(), VmError> {
+                let arity = <[&str]>::len(&[$(stringify!($t)),*]);
+                if vm.stack.len() < arity {
+                    return Err(vm.error(format!(
+                        "{}: expected {} arguments, got {}",
+                        name,
+                        arity,
+                        vm.stack.len()
+                    )));
+                }
+                let base = vm.stack.len() - arity;
+                let mut args = vm.stack[base..].iter().enumerate();
+                $(
+                    let (i, value) = args.next().unwrap();
+                    let $v = match $t::from_value(value) {
+                        Some(v) => v,
+                        None => {
+                            return Err(vm.error(format!(
+                                "{}: argument {} must be {}, got {}",
+                                name,
+                                i + 1,
+                                $t::EXPECTED,
+                                describe(value)
+                            )))
+                        }
+                    };
+                )*
+                vm.stack.truncate(base);
+                self($($v),*)
+                    .push_onto(&mut vm.stack)
+                    .map_err(|e| vm.error(format!("{}: {}", name, e)))
+            }
+        }
+    };
+}
+
+typed_func!();
+typed_func!(A a);
+typed_func!(A a, B b);
+typed_func!(A a, B b, C c);
+typed_func!(A a, B b, C c, D d);
+typed_func!(A a, B b, C c, D d, E e);
+typed_func!(A a, B b, C c, D d, E e, G g);
+
+// Wraps a Rust function or closure as the foreign function `name`.
+pub fn typed_function<Args, F: TypedFunc<Args>>(name: &str, f: F) -> ForeignFunc {
+    let owned = name.to_string();
+    ForeignFunc {
+        func: Rc::new(move |vm: &mut VM| f.call(&owned, vm)),
+        name: name.to_string(),
+        signature: F::signature(),
+        ..Default::default()
+    }
+}
+
+// Foreign functions grouped under a namespace: the function `play` of the
+// module `audio` is called as `audio.play`. See `stdlib` for bundled ones.
+pub struct Module {
+    pub name: String,
+    // Their names include the namespace.
+    pub functions: Vec<ForeignFunc>,
+}
+
+impl Module {
+    pub fn new(name: &str) -> Module {
+        Module {
+            name: name.to_string(),
+            functions: vec![],
+        }
+    }
+
+    // Adds `ffunc`, whose name is taken to be relative to the module.
+    pub fn register_foreign_function(&mut self, mut ffunc: ForeignFunc) {
+        ffunc.name = format!("{}.{}", self.name, ffunc.name);
+        self.functions.push(ffunc);
+    }
+
+    pub fn register<Args, F: TypedFunc<Args>>(&mut self, name: &str, f: F) -> &mut ForeignFunc {
+        let name = format!("{}.{}", self.name, name);
+        self.functions.push(typed_function(&name, f));
+        self.functions.last_mut().unwrap()
+    }
+}
+
+impl VM {
+    // Registers a Rust function or closure as the foreign function `name`;
+    // see above. Returns it, to add a description and such.
+    pub fn register<Args, F: TypedFunc<Args>>(&mut self, name: &str, f: F) -> &mut ForeignFunc {
+        self.register_foreign_function(typed_function(name, f));
+        self.foreign_functions.get_mut(name).unwrap()
+    }
+
+    // Registers all functions of a module, panicking if any of their names
+    // is taken; see `try_register_module`.
+    pub fn register_module(&mut self, module: Module) {
+        if let Err(e) = self.try_register_module(module) {
+            panic!("{}", e);
+        }
+    }
+
+    // Registers all functions of a module, or none if any of their names is
+    // that of a builtin or of a function registered before.
+    pub fn try_register_module(&mut self, module: Module) -> Result<(), VmError> {
+        for (i, f) in module.functions.iter().enumerate() {
+            self.check_foreign_name(&f.name)?;
+            if module.functions[..i].iter().any(|g| g.name == f.name) {
+                return Err(VmError::Register {
+                    name: f.name.clone(),
+                    message: format!("Cannot register {}: it is already registered", f.name),
+                });
+            }
+        }
+        for f in module.functions {
+            self.foreign_functions.insert(f);
+        }
+        Ok(())
+    }
+}
+
+// The foreign functions registered with a VM, in registration order, with
+// their metadata and lookup by name.
+#[derive(Default)]
+pub struct Registry {
+    functions: Vec<ForeignFunc>,
+    index: HashMap<String, usize>,
+}
+
+fn type_name(t: &ValueType) -> &'static str {
+    match t {
+        ValueType::Number => "number",
+        ValueType::String => "string",
+        ValueType::Any => "value",
+    }
+}
+
+// In stack comment notation, arguments in push order: `( number number --
+// number )`.
+fn stack_comment(signature: &Option<StackSignature>) -> String {
+    match signature {
+        Some(sig) => {
+            let mut words = vec!["("];
+            words.extend(sig.inputs.iter().rev().map(type_name));
+            words.push("--");
+            words.extend(sig.outputs.iter().map(type_name));
+            words.push(")");
+            words.join(" ")
+        }
+        None => "( ? )".to_string(),
+    }
+}
+
+impl Registry {
+    // Adds `ffunc`, replacing a function of the same name. `VM` checks
+    // names before calling this.
+    pub(crate) fn insert(&mut self, ffunc: ForeignFunc) {
+        match self.index.get(&ffunc.name) {
+            Some(&i) => self.functions[i] = ffunc,
+            None => {
+                self.index.insert(ffunc.name.clone(), self.functions.len());
+                self.functions.push(ffunc);
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ForeignFunc> {
+        self.index.get(name).map(|&i| &self.functions[i])
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut ForeignFunc> {
+        self.index.get(name).map(|&i| &mut self.functions[i])
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.index.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, ForeignFunc> {
+        self.functions.iter()
+    }
+
+    // The functions whose names start with `prefix`, sorted by name, for
+    // editor completion.
+    pub fn complete(&self, prefix: &str) -> Vec<&ForeignFunc> {
+        let mut matches: Vec<&ForeignFunc> = self
+            .functions
+            .iter()
+            .filter(|f| f.name.starts_with(prefix))
+            .collect();
+        matches.sort_by(|a, b| a.name.cmp(&b.name));
+        matches
+    }
+
+    // Markdown documentation of all functions, sorted by name.
+    pub fn docs(&self) -> String {
+        let mut out = String::new();
+        for f in self.complete("") {
+            writeln!(out, "### {}\n", f.name).unwrap();
+            write!(out, "`{} {}`", f.name, stack_comment(&f.signature)).unwrap();
+            if f.pure {
+                write!(out, " pure,").unwrap();
+            }
+            writeln!(out, " cost {}\n", f.cost).unwrap();
+            if !f.description.is_empty() {
+                writeln!(out, "{}\n", f.description).unwrap();
+            }
+        }
+        out
+    }
+}
+
+impl<'a> IntoIterator for &'a Registry {
+    type Item = &'a ForeignFunc;
+    type IntoIter = std::slice::Iter<'a, ForeignFunc>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.functions.iter()
+    }
+}
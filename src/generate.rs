@@ -0,0 +1,115 @@
+use rand::seq::IndexedRandom;
+use rand::{Rng, RngExt};
+
+// Random Tzo programs for differential and property-based testing.
+//
+// `goto` is only emitted right after pushing a label that is placed on a
+// later instruction, and never right after a `jz`/`jgz` that could skip the
+// push, so all jumps go forward and every generated program terminates.
+
+// Functions called directly. `randInt` is only added on request since its
+// results differ between runs, and `goto` is emitted with its label.
+const FUNCTIONS: &[&str] = &[
+    "nop",
+    "pop",
+    "plus",
+    "min",
+    "mul",
+    "concat",
+    "rconcat",
+    "charCode",
+    "ppc",
+    "eq",
+    "not",
+    "or",
+    "and",
+    "jgz",
+    "jz",
+    "gt",
+    "lt",
+    "dup",
+    "getContext",
+    "hasContext",
+    "setContext",
+    "delContext",
+    "stacksize",
+    "stdout",
+];
+
+const NUMBERS: &[f64] = &[-2.0, -1.0, -0.5, 0.0, 0.0, 1.0, 1.0, 1.5, 2.0, 3.0, 65.0];
+const STRINGS: &[&str] = &["a", "b", "x", "", "3", "hello"];
+
+fn number(n: f64) -> serde_json::Value {
+    serde_json::json!({ "type": "push-number-instruction", "value": n })
+}
+
+fn string(s: &str) -> serde_json::Value {
+    serde_json::json!({ "type": "push-string-instruction", "value": s })
+}
+
+fn function(name: &str) -> serde_json::Value {
+    serde_json::json!({ "type": "invoke-function-instruction", "functionName": name })
+}
+
+// Generates a program of about `len` instructions, in the JSON form
+// `VM::load` takes, calling `randInt` too if `rand_int` is set. Its context
+// keys are "a" and "b".
+pub fn program(rng: &mut impl Rng, len: usize, rand_int: bool) -> Vec<serde_json::Value> {
+    let mut program: Vec<serde_json::Value> = vec![];
+    let mut depth = 0;
+    // labels that gotos jump to, still to be placed on a later instruction
+    let mut pending: Vec<String> = vec![];
+    let mut labels = 0;
+    let after_branch = |program: &[serde_json::Value]| {
+        program
+            .last()
+            .is_some_and(|i| i["functionName"] == "jz" || i["functionName"] == "jgz")
+    };
+
+    while program.len() < len {
+        let mut instr = match rng.random_range(0..100) {
+            0..30 => number(*NUMBERS.choose(rng).unwrap()),
+            30..45 => string(STRINGS.choose(rng).unwrap()),
+            45..50 if depth > 0 => {
+                depth -= 1;
+                function("}")
+            }
+            45..52 => {
+                depth += 1;
+                function("{")
+            }
+            52..58 if !after_branch(&program) => {
+                let label = format!("l{}", labels);
+                labels += 1;
+                let mut push = string(&label);
+                if !pending.is_empty() && rng.random_bool(0.3) {
+                    push["label"] = serde_json::json!(pending.remove(0));
+                }
+                program.push(push);
+                program.push(function("goto"));
+                pending.push(label);
+                continue;
+            }
+            58 => function(if rng.random_bool(0.5) {
+                "exit"
+            } else {
+                "pause"
+            }),
+            59..61 if rand_int => function("randInt"),
+            _ => function(FUNCTIONS.choose(rng).unwrap()),
+        };
+        if !pending.is_empty() && rng.random_bool(0.3) {
+            instr["label"] = serde_json::json!(pending.remove(0));
+        }
+        program.push(instr);
+    }
+    for _ in 0..depth {
+        program.push(function("}"));
+    }
+    for label in pending {
+        let mut nop = function("nop");
+        nop["label"] = serde_json::json!(label);
+        program.push(nop);
+    }
+    program
+}
@@ -1 +1,31 @@
+pub mod asm;
+pub mod cfg;
+pub mod conformance;
+pub mod context;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+#[cfg(any(test, feature = "differential"))]
+pub mod differential;
+pub mod foreign;
+#[cfg(any(test, feature = "generate"))]
+pub mod generate;
+pub mod observe;
+pub mod optimizer;
+pub mod policy;
+pub mod profile;
+pub mod repl;
+pub mod stdlib;
+pub mod trace;
+pub mod transaction;
+pub mod verifier;
 pub mod vm;
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod test_wasm;
+#[cfg(test)]
+#[allow(non_snake_case, clippy::module_inception)]
+mod tests;
@@ -1,26 +1,424 @@
+use std::env;
 use std::fs;
+use std::io::{self, BufRead, Write};
+use std::process;
 
-mod test_wasm;
-mod tests;
-mod vm;
+use tzo::asm;
+use tzo::cfg::ControlFlowGraph;
+use tzo::coverage::{Coverage, CoverageRecorder};
+use tzo::dap;
+use tzo::debugger::Debugger;
+use tzo::policy::Policy;
+use tzo::profile::Profiler;
+use tzo::repl::Repl;
+use tzo::stdlib;
+use tzo::trace::JsonTracer;
+use tzo::vm;
 
-fn testfn(vm: &mut vm::VM) {
-    println!("\nTest foreign function called! PC: {}\n", vm.pc);
+const USAGE: &str = "usage: tzo run <file> [options]
+       tzo compile <file> <output>
+       tzo cfg <file>
+       tzo coverage <file> <coverage file>
+       tzo debug <file>
+       tzo repl
+       tzo dap
+       tzo docs [module...]
+
+<file> is a JSON instruction list, a JSON test fixture, a text program or
+bytecode, detected from its contents.
+compile writes <file> as bytecode to <output>.
+coverage reports what a coverage file recorded by run --coverage missed.
+dap serves the Debug Adapter Protocol on stdin and stdout for editors.
+docs prints Markdown documentation of the bundled modules (all by default).
+
+options for run:
+  --context key=value  set a context value before running (repeatable)
+  --context-file <file>
+                       set the context values of a JSON object before
+                       running, before any --context
+  --save-context <file>
+                       write the final context to <file> as JSON
+  --seed <n>           seed the random number generator used by randInt
+  --module <name>      enable a bundled function module: math, string, time,
+                       json or transaction (repeatable)
+  --allow <pattern>    only allow calling these functions (repeatable); a
+                       pattern is a name, a module like math.* or *
+  --deny <pattern>     forbid calling these functions (repeatable)
+  --no-goto-number     forbid goto with a number instead of a label
+  --max-steps <n>      stop after executing <n> instructions
+  --trace              write a JSON line per executed instruction to stderr
+  --profile            print execution counts and times to stderr
+  --flamegraph <file>  write a folded-stack profile for flamegraph tools
+  --coverage <file>    record coverage, merged into <file> if it exists
+  --print-stack        print the final stack as JSON
+  --print-context      print the final context as JSON
+
+exit codes:
+  0  the program ran to its end
+  1  runtime error
+  2  usage or load error
+  3  the program called exit
+  4  the program paused
+  5  --max-steps was reached";
+
+const EXIT_FINISHED: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_EXITED: i32 = 3;
+const EXIT_PAUSED: i32 = 4;
+const EXIT_STEP_LIMIT: i32 = 5;
+
+struct RunOptions {
+    context: Vec<(String, vm::Value)>,
+    context_file: Option<String>,
+    save_context: Option<String>,
+    seed: Option<u64>,
+    modules: Vec<String>,
+    policy: Policy,
+    max_steps: Option<usize>,
+    trace: bool,
+    profile: bool,
+    flamegraph: Option<String>,
+    coverage: Option<String>,
+    print_stack: bool,
+    print_context: bool,
 }
 
-fn main() {
-    let contents = fs::read_to_string("test.json").expect("Something went wrong reading the file");
-    let v: serde_json::Value = serde_json::from_str(&contents).unwrap();
-    let instructions = v.as_array().unwrap().to_vec();
+fn usage_error(msg: &str) -> ! {
+    eprintln!("error: {}\n\n{}", msg, USAGE);
+    process::exit(EXIT_USAGE);
+}
+
+fn load(path: &str) -> vm::VM {
+    load_into(vm::VM::new(), path)
+}
+
+fn read_program(path: &str) -> Vec<serde_json::Value> {
+    let contents = fs::read(path).unwrap_or_else(|e| {
+        eprintln!("error: cannot read {}: {}", path, e);
+        process::exit(EXIT_USAGE);
+    });
+    asm::parse_program_bytes(&contents).unwrap_or_else(|e| {
+        eprintln!("error: cannot parse {}: {}", path, e);
+        process::exit(EXIT_USAGE);
+    })
+}
+
+// Loads a program into a VM already set up with the functions it may call
+// and the policy it's checked against.
+fn load_into(mut vm: vm::VM, path: &str) -> vm::VM {
+    let instructions = read_program(path);
+    if let Err(e) = vm.try_load(instructions) {
+        eprintln!("error: cannot load {}: {}", path, e);
+        process::exit(EXIT_USAGE);
+    }
+    vm
+}
+
+fn parse_run_options(args: &[String]) -> RunOptions {
+    let mut options = RunOptions {
+        context: vec![],
+        context_file: None,
+        save_context: None,
+        seed: None,
+        modules: vec![],
+        policy: Policy::default(),
+        max_steps: None,
+        trace: false,
+        profile: false,
+        flamegraph: None,
+        coverage: None,
+        print_stack: false,
+        print_context: false,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| match args.next() {
+            Some(v) => v.clone(),
+            None => usage_error(&format!("{} needs a value", name)),
+        };
+        match arg.as_str() {
+            "--context" => {
+                let kv = value("--context");
+                let (k, v) = kv
+                    .split_once('=')
+                    .unwrap_or_else(|| usage_error("--context expects key=value"));
+                let v = match v.parse::<f64>() {
+                    Ok(n) if n.is_finite() => vm::Value::Number(n),
+                    _ => vm::Value::String(v.to_string()),
+                };
+                options.context.push((k.to_string(), v));
+            }
+            "--context-file" => options.context_file = Some(value("--context-file")),
+            "--save-context" => options.save_context = Some(value("--save-context")),
+            "--seed" => {
+                options.seed = Some(
+                    value("--seed")
+                        .parse()
+                        .unwrap_or_else(|_| usage_error("--seed expects a number")),
+                )
+            }
+            "--module" => options.modules.push(value("--module")),
+            "--allow" => {
+                let pattern = value("--allow");
+                options.policy = options.policy.allow(&pattern)
+            }
+            "--deny" => {
+                let pattern = value("--deny");
+                options.policy = options.policy.deny(&pattern)
+            }
+            "--no-goto-number" => options.policy = options.policy.deny_goto_by_number(),
+            "--max-steps" => {
+                options.max_steps = Some(
+                    value("--max-steps")
+                        .parse()
+                        .unwrap_or_else(|_| usage_error("--max-steps expects a number")),
+                )
+            }
+            "--trace" => options.trace = true,
+            "--profile" => options.profile = true,
+            "--flamegraph" => options.flamegraph = Some(value("--flamegraph")),
+            "--coverage" => options.coverage = Some(value("--coverage")),
+            "--print-stack" => options.print_stack = true,
+            "--print-context" => options.print_context = true,
+            _ => usage_error(&format!("unknown option {}", arg)),
+        }
+    }
+    options
+}
+
+// Steps until the program stops, returning the exit code for how it did.
+fn execute(vm: &mut vm::VM, max_steps: Option<usize>) -> Result<i32, vm::VmError> {
+    let mut steps = 0;
+    vm.running = true;
+    while vm.running && vm.pc < vm.programlist.len() {
+        if max_steps == Some(steps) {
+            return Ok(EXIT_STEP_LIMIT);
+        }
+        vm.step()?;
+        steps += 1;
+    }
+    Ok(if vm.exited {
+        EXIT_EXITED
+    } else if !vm.running {
+        EXIT_PAUSED
+    } else {
+        EXIT_FINISHED
+    })
+}
 
+fn run(path: &str, options: RunOptions) -> i32 {
     let mut vm = vm::VM::new();
-    let test_ff = vm::ForeignFunc {
-        name: String::from("test"),
-        func: testfn,
+    for name in &options.modules {
+        match stdlib::module(name) {
+            Some(module) => vm.register_module(module),
+            None => usage_error(&format!("unknown module {}", name)),
+        }
+    }
+    vm.policy = options.policy;
+    let mut vm = load_into(vm, path);
+    if let Some(path) = &options.context_file {
+        let json = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| serde_json::from_str(&contents).map_err(|e| e.to_string()))
+            .and_then(|json| vm.set_context_from_json(&json).map_err(|e| e.to_string()));
+        if let Err(e) = json {
+            eprintln!("error: cannot read context from {}: {}", path, e);
+            process::exit(EXIT_USAGE);
+        }
+    }
+    for (k, v) in options.context {
+        if let Err(e) = vm.set_context(k.clone(), v) {
+            eprintln!("error: cannot set {}: {}", k, e);
+            process::exit(EXIT_USAGE);
+        }
+    }
+    if let Some(seed) = options.seed {
+        vm.seed(seed);
+    }
+    if options.trace {
+        vm.hooks.push(Box::new(JsonTracer::new(io::stderr())));
+    }
+    let coverage = options
+        .coverage
+        .as_ref()
+        .map(|_| CoverageRecorder::attach(&mut vm));
+    let profile = if options.profile || options.flamegraph.is_some() {
+        Some(Profiler::attach(&mut vm))
+    } else {
+        None
     };
-    vm.register_foreign_function(test_ff);
-    vm.load(instructions);
-    vm.run();
 
-    println!("\nStack at end: {:?}", vm.stack);
+    let code = execute(&mut vm, options.max_steps).unwrap_or_else(|e| {
+        eprintln!("runtime error at pc {}: {}", vm.pc, e);
+        EXIT_ERROR
+    });
+
+    if let Some(profile) = profile {
+        let profile = profile.borrow();
+        if options.profile {
+            eprint!("{}", profile.report(&vm));
+        }
+        if let Some(path) = &options.flamegraph {
+            if let Err(e) = fs::write(path, profile.folded(&vm)) {
+                eprintln!("error: cannot write {}: {}", path, e);
+            }
+        }
+    }
+    if let (Some(coverage), Some(path)) = (coverage, &options.coverage) {
+        let mut merged = if fs::metadata(path).is_ok() {
+            read_coverage(path)
+        } else {
+            Coverage::default()
+        };
+        merged.merge(&coverage.borrow());
+        if let Err(e) = fs::write(path, merged.to_json().to_string()) {
+            eprintln!("error: cannot write {}: {}", path, e);
+        }
+    }
+    if options.print_stack {
+        let stack: Vec<serde_json::Value> = vm.stack.iter().map(|v| v.to_json()).collect();
+        println!("{}", serde_json::Value::Array(stack));
+    }
+    if options.print_context {
+        println!("{}", vm.context_to_json());
+    }
+    if let Some(path) = &options.save_context {
+        let json = serde_json::to_string_pretty(&vm.context_to_json()).unwrap();
+        if let Err(e) = fs::write(path, json + "\n") {
+            eprintln!("error: cannot write {}: {}", path, e);
+        }
+    }
+    code
+}
+
+fn read_coverage(path: &str) -> Coverage {
+    let contents = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("error: cannot read {}: {}", path, e);
+        process::exit(EXIT_USAGE);
+    });
+    serde_json::from_str(&contents)
+        .map_err(|e| e.to_string())
+        .and_then(|v| Coverage::from_json(&v))
+        .unwrap_or_else(|e| {
+            eprintln!("error: cannot parse {}: {}", path, e);
+            process::exit(EXIT_USAGE);
+        })
+}
+
+fn compile(path: &str, output: &str) -> i32 {
+    let bytecode = asm::encode(&read_program(path)).unwrap_or_else(|e| {
+        eprintln!("error: cannot compile {}: {}", path, e);
+        process::exit(EXIT_USAGE);
+    });
+    if let Err(e) = fs::write(output, bytecode) {
+        eprintln!("error: cannot write {}: {}", output, e);
+        process::exit(EXIT_USAGE);
+    }
+    EXIT_FINISHED
+}
+
+fn coverage_report(path: &str, coverage_path: &str) -> i32 {
+    let vm = load(path);
+    let lines = fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| asm::source_lines(&contents));
+    let coverage = read_coverage(coverage_path);
+    print!("{}", coverage.report(&vm, lines.as_deref()));
+    EXIT_FINISHED
+}
+
+// Reads lines from stdin after showing `prompt`, passing them to `handle`
+// until it returns `None` or the input ends.
+fn interact(prompt: &str, mut handle: impl FnMut(&str) -> Option<String>) -> i32 {
+    let stdin = io::stdin();
+    loop {
+        print!("{}", prompt);
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            println!();
+            return EXIT_FINISHED;
+        }
+        match handle(&line) {
+            Some(out) if out.is_empty() => {}
+            Some(out) => println!("{}", out),
+            None => return EXIT_FINISHED,
+        }
+    }
+}
+
+fn debug(path: &str) -> i32 {
+    let mut debugger = Debugger::new(load(path));
+    println!("Tzo debugger, help for commands");
+    println!("{}", debugger.listing(5).trim_end());
+    interact("(tzo) ", |line| debugger.command(line))
+}
+
+fn repl() -> i32 {
+    let mut repl = Repl::new();
+    println!("Tzo REPL, :help for commands");
+    interact("tzo> ", |line| repl.eval(line))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("repl") {
+        let code = repl();
+        process::exit(code);
+    }
+    if args.first().map(String::as_str) == Some("dap") {
+        dap::serve(io::BufReader::new(io::stdin()), io::stdout());
+        process::exit(EXIT_FINISHED);
+    }
+    if args.first().map(String::as_str) == Some("docs") {
+        let names: Vec<&str> = match args.len() {
+            1 => stdlib::MODULES.to_vec(),
+            _ => args[1..].iter().map(String::as_str).collect(),
+        };
+        let mut vm = vm::VM::new();
+        for name in names {
+            match stdlib::module(name) {
+                Some(module) => vm.register_module(module),
+                None => usage_error(&format!("unknown module {}", name)),
+            }
+        }
+        print!("{}", vm.foreign_functions.docs());
+        process::exit(EXIT_FINISHED);
+    }
+    if args.len() < 2 {
+        usage_error("missing command or file");
+    }
+    let code = match args[0].as_str() {
+        "run" => run(&args[1], parse_run_options(&args[2..])),
+        "compile" => {
+            if args.len() != 3 {
+                usage_error("compile takes a program and an output file");
+            }
+            compile(&args[1], &args[2])
+        }
+        "cfg" => {
+            if args.len() > 2 {
+                usage_error("cfg takes no options");
+            }
+            let vm = load(&args[1]);
+            print!("{}", ControlFlowGraph::build(&vm).to_dot(&vm));
+            EXIT_FINISHED
+        }
+        "coverage" => {
+            if args.len() != 3 {
+                usage_error("coverage takes a program and a coverage file");
+            }
+            coverage_report(&args[1], &args[2])
+        }
+        "debug" => {
+            if args.len() > 2 {
+                usage_error("debug takes no options");
+            }
+            debug(&args[1])
+        }
+        cmd => usage_error(&format!("unknown command {}", cmd)),
+    };
+    io::stdout().flush().unwrap();
+    process::exit(code);
 }
@@ -0,0 +1,104 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::policy;
+use crate::vm::{Value, VM};
+
+// Callbacks on context changes, for hosts that mirror script variables:
+//
+//   vm.observe("gold", |change| ui.set_gold(change.new));
+//   vm.observe("player.*", |change| ui.refresh(change.key));
+//
+// Patterns are as in `policy`: a key, a prefix followed by `*`, or `*`.
+// Observers are called when `setContext` or `delContext` (or the host's
+// `VM::set_context` and `VM::delete_context`) change a key; setting a key to
+// the value it has is not a change.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContextChange<'a> {
+    pub key: &'a str,
+    // None if the key was not set before.
+    pub old: Option<&'a Value>,
+    // None if the key was deleted.
+    pub new: Option<&'a Value>,
+}
+
+// Identifies an observer, to remove it with `VM::unobserve`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObserverId(u64);
+
+struct Observer {
+    id: ObserverId,
+    pattern: String,
+    callback: Box<dyn FnMut(&ContextChange)>,
+}
+
+#[derive(Default)]
+pub(crate) struct Observers {
+    observers: Vec<Observer>,
+    next_id: u64,
+}
+
+impl Observers {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+
+    pub(crate) fn notify(&mut self, key: &str, old: Option<&Value>, new: Option<&Value>) {
+        if old == new {
+            return;
+        }
+        let change = ContextChange { key, old, new };
+        for o in self.observers.iter_mut() {
+            if policy::matches(&o.pattern, key) {
+                (o.callback)(&change);
+            }
+        }
+    }
+}
+
+// The value of a context key, kept up to date by an observer.
+#[derive(Debug, Clone)]
+pub struct Binding {
+    value: Rc<RefCell<Option<Value>>>,
+}
+
+impl Binding {
+    // The current value, or None while the key is not set.
+    pub fn get(&self) -> Option<Value> {
+        self.value.borrow().clone()
+    }
+}
+
+impl VM {
+    // Calls `callback` whenever a key matching `pattern` changes.
+    pub fn observe(
+        &mut self,
+        pattern: &str,
+        callback: impl FnMut(&ContextChange) + 'static,
+    ) -> ObserverId {
+        let id = ObserverId(self.observers.next_id);
+        self.observers.next_id += 1;
+        self.observers.observers.push(Observer {
+            id,
+            pattern: pattern.to_string(),
+            callback: Box::new(callback),
+        });
+        id
+    }
+
+    // Removes an observer; does nothing if it was removed before.
+    pub fn unobserve(&mut self, id: ObserverId) {
+        self.observers.observers.retain(|o| o.id != id);
+    }
+
+    // Follows the value of `key`.
+    pub fn bind(&mut self, key: &str) -> Binding {
+        let value = Rc::new(RefCell::new(self.context.get(key)));
+        let shared = value.clone();
+        self.observe(key, move |change| {
+            *shared.borrow_mut() = change.new.cloned();
+        });
+        Binding { value }
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashSet;
+
+use crate::verifier;
+use crate::vm::{Instr, Value, VM};
+
+// Peephole optimizer for loaded programs.
+//
+// Performs, in a single pass over the program list:
+//  - jump threading: `"a" goto` where label `a` holds `"b" goto` becomes `"b" goto`
+//  - constant folding of `plus`/`min`/`mul` on number literals and of
+//    `concat`/`rconcat` on any two literals
+//  - removal of `nop`, `<literal> pop`, and `dup pop` where the verifier
+//    proves the stack is never empty at the `dup` (which would fail)
+//
+// Instructions that are jumped to, or that the VM will resume at, are never
+// merged into a preceding instruction, and the instruction right after
+// `jz`/`jgz` is kept as-is so that the skip still skips the same thing. Labels and numeric `goto`
+// literals are rewritten through the resulting pc remapping table.
+//
+// Programs using `ppc` or a `goto` whose operand is not a literal right in
+// front of it can jump to arbitrary computed pcs, so they are left untouched.
+
+struct Emitted {
+    instr: Instr,
+    // Execution may enter here other than by falling through.
+    target: bool,
+    // Sits right after a `jz`/`jgz`.
+    pinned: bool,
+    // Always runs with at least one value on the stack.
+    nonempty: bool,
+}
+
+fn func_name(instr: &Instr) -> Option<&str> {
+    match instr {
+        Instr::Func(_, name) => Some(name.as_str()),
+        _ => None,
+    }
+}
+
+fn is_literal(instr: &Instr) -> bool {
+    matches!(instr, Instr::Number(_) | Instr::String(_))
+}
+
+fn literal_value(instr: &Instr) -> Option<Value> {
+    match instr {
+        Instr::Number(n) => Some(Value::Number(*n)),
+        Instr::String(s) => Some(Value::String(s.clone())),
+        _ => None,
+    }
+}
+
+// Evaluates `op` the same way the VM would, with `a` on top of the stack.
+fn fold(op: &str, a: &Instr, b: &Instr) -> Option<Instr> {
+    let (a, b) = (literal_value(a)?, literal_value(b)?);
+    match (op, &a, &b) {
+        ("plus" | "+", Value::Number(x), Value::Number(y)) => Some(Instr::Number(x + y)),
+        ("min" | "-", Value::Number(x), Value::Number(y)) => Some(Instr::Number(x - y)),
+        ("mul" | "*", Value::Number(x), Value::Number(y)) => Some(Instr::Number(x * y)),
+        ("concat", _, _) => Some(Instr::String(format!("{}{}", a, b))),
+        ("rconcat", _, _) => Some(Instr::String(format!("{}{}", b, a))),
+        _ => None,
+    }
+}
+
+// Where a `goto` with the literal at `pc` lands, if the literal is a known
+// label or a number.
+fn literal_target(vm: &VM, pc: usize) -> Option<usize> {
+    match &vm.programlist[pc] {
+        Instr::String(label) => vm.labels.get(label).map(|l| *l as usize),
+        Instr::Number(n) => Some(VM::goto_target(*n)),
+        _ => None,
+    }
+}
+
+pub fn optimize(vm: &mut VM) -> Vec<usize> {
+    let len = vm.programlist.len();
+    let identity: Vec<usize> = (0..=len).collect();
+
+    let mut targets = vec![false; len + 1];
+    let mut pinned = vec![false; len + 1];
+    let mut gotos = vec![];
+    for l in vm.labels.values() {
+        targets[(*l as usize).min(len)] = true;
+    }
+    // Where a paused or yielded program carries on, with whatever the host
+    // left on the stack.
+    targets[vm.pc.min(len)] = true;
+    for (pc, instr) in vm.programlist.iter().enumerate() {
+        match instr {
+            Instr::OpenBrace => {
+                if let Some(close) = vm.matching_brace(pc) {
+                    targets[close + 1] = true;
+                }
+            }
+            Instr::Func(_, name) => match name.as_str() {
+                "jz" | "jgz" => {
+                    pinned[(pc + 1).min(len)] = true;
+                    targets[(pc + 2).min(len)] = true;
+                }
+                "ppc" => return identity,
+                "goto" => {
+                    if pc == 0 || !is_literal(&vm.programlist[pc - 1]) {
+                        return identity;
+                    }
+                    if let Some(t) = literal_target(vm, pc - 1) {
+                        targets[t.min(len)] = true;
+                    }
+                    gotos.push(pc);
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    // A goto that can be entered by a jump might see any operand.
+    if gotos.iter().any(|pc| targets[*pc]) {
+        return identity;
+    }
+
+    // Jump threading. Only hops through gotos that can't be reached any other
+    // way than from their own literal.
+    let mut program = vm.programlist.clone();
+    for pc in &gotos {
+        let mut operand = pc - 1;
+        let mut seen = HashSet::new();
+        while let Some(t) = literal_target(vm, operand) {
+            let hop = t + 1;
+            if hop >= len
+                || !seen.insert(t)
+                || func_name(&vm.programlist[hop]) != Some("goto")
+                || !is_literal(&vm.programlist[t])
+                || targets[hop]
+            {
+                break;
+            }
+            operand = t;
+        }
+        program[pc - 1] = vm.programlist[operand].clone();
+    }
+
+    let depths = verifier::min_stack_depths(vm);
+    let mut out: Vec<Emitted> = vec![];
+    let mut new_pc = vec![0; len + 1];
+    let mut pending_target = false;
+    for (pc, instr) in program.into_iter().enumerate() {
+        new_pc[pc] = out.len();
+        let entered = targets[pc] || pending_target;
+        if !pinned[pc] {
+            match func_name(&instr) {
+                Some("nop") => {
+                    pending_target = entered;
+                    continue;
+                }
+                Some("pop") if !entered => {
+                    if let Some(last) = out.last() {
+                        let droppable = is_literal(&last.instr)
+                            || (func_name(&last.instr) == Some("dup") && last.nonempty);
+                        if droppable && !last.pinned {
+                            pending_target = out.pop().unwrap().target;
+                            continue;
+                        }
+                    }
+                }
+                Some(op) if !entered && out.len() >= 2 => {
+                    let a = &out[out.len() - 1];
+                    let b = &out[out.len() - 2];
+                    if !a.target && !a.pinned && !b.pinned {
+                        if let Some(folded) = fold(op, &a.instr, &b.instr) {
+                            out.pop();
+                            out.last_mut().unwrap().instr = folded;
+                            continue;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        out.push(Emitted {
+            instr,
+            target: entered,
+            pinned: pinned[pc],
+            nonempty: depths[pc].is_some_and(|d| d >= 1),
+        });
+        pending_target = false;
+    }
+    new_pc[len] = out.len();
+
+    // Numeric goto literals are never folded or dropped (they are always
+    // followed by their goto), so they can be looked up directly.
+    for pc in &gotos {
+        if let Instr::Number(n) = &out[new_pc[pc - 1]].instr {
+            let t = new_pc[VM::goto_target(*n).min(len)];
+            if t == 0 {
+                return identity; // not expressible: a numeric goto can't land on 0
+            }
+            out[new_pc[pc - 1]].instr = Instr::Number(t as f64);
+        }
+    }
+
+    vm.programlist = out.into_iter().map(|e| e.instr).collect();
+    for l in vm.labels.values_mut() {
+        *l = new_pc[(*l as usize).min(len)] as i64;
+    }
+    vm.pc = new_pc[vm.pc.min(len)];
+    new_pc
+}
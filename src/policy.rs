@@ -0,0 +1,90 @@
+// Which functions a program may call, for running untrusted scripts:
+//
+//   vm.policy = Policy::default().deny("stdout").deny("audio.*");
+//   vm.policy = Policy::deny_all().allow("plus").allow("math.*");
+//
+// Patterns name a builtin or foreign function (`stdout`, `audio.play`), all
+// functions of a module (`audio.*`) or every function (`*`). Denying wins
+// over allowing. `VM::try_load` rejects programs calling functions the
+// policy doesn't allow, and calls are checked again when they execute, in
+// case the policy changed after loading.
+//
+// Separately, `goto` with a number instead of a label can be forbidden, as
+// it can jump anywhere in the program: loading rejects a number pushed right
+// before `goto`, and `goto` fails on computed numbers.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    // None allows everything not denied.
+    pub allow: Option<Vec<String>>,
+    pub deny: Vec<String>,
+    pub goto_by_number: bool,
+}
+
+impl Default for Policy {
+    // Allows everything.
+    fn default() -> Self {
+        Policy {
+            allow: None,
+            deny: vec![],
+            goto_by_number: true,
+        }
+    }
+}
+
+pub(crate) fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+impl Policy {
+    // Allows nothing until functions are allowed.
+    pub fn deny_all() -> Policy {
+        Policy {
+            allow: Some(vec![]),
+            ..Default::default()
+        }
+    }
+
+    pub fn allow(mut self, pattern: &str) -> Policy {
+        self.allow
+            .get_or_insert_with(Vec::new)
+            .push(pattern.to_string());
+        self
+    }
+
+    pub fn deny(mut self, pattern: &str) -> Policy {
+        self.deny.push(pattern.to_string());
+        self
+    }
+
+    pub fn deny_goto_by_number(mut self) -> Policy {
+        self.goto_by_number = false;
+        self
+    }
+
+    // Whether this is the default policy, which doesn't need checking.
+    pub fn allows_everything(&self) -> bool {
+        self.allow.is_none() && self.deny.is_empty() && self.goto_by_number
+    }
+
+    pub fn allows(&self, name: &str) -> bool {
+        if self.deny.iter().any(|p| matches(p, name)) {
+            return false;
+        }
+        match &self.allow {
+            Some(allow) => allow.iter().any(|p| matches(p, name)),
+            None => true,
+        }
+    }
+
+    pub fn check(&self, name: &str) -> Result<(), String> {
+        if self.allows(name) {
+            Ok(())
+        } else {
+            Err(format!("{} is not allowed", name))
+        }
+    }
+}
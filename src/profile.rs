@@ -0,0 +1,150 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::trace::Hooks;
+use crate::vm::{Instr, VM};
+
+// Execution counts and wall time per pc, collected by `Profiler` hooks and
+// summarised per label region and per function.
+//
+// Tzo has no calls, so the "stack" of a folded-stack line is the label region
+// an instruction is in followed by the instruction itself.
+
+// Region name for instructions before the first label.
+const NO_LABEL: &str = "main";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl Stats {
+    fn add(&mut self, other: Stats) {
+        self.count += other.count;
+        self.time += other.time;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub pcs: BTreeMap<usize, Stats>,
+}
+
+// Name an instruction is profiled under: the function name as written, or
+// `push` for literals.
+fn op_name(instr: &Instr) -> String {
+    match instr {
+        Instr::Number(_) | Instr::String(_) => "push".to_string(),
+        other => other.to_string(),
+    }
+}
+
+// Sums `stats` by key, most expensive first.
+fn totals(stats: impl Iterator<Item = (String, Stats)>) -> Vec<(String, Stats)> {
+    let mut by_key: HashMap<String, Stats> = HashMap::new();
+    for (key, s) in stats {
+        by_key.entry(key).or_default().add(s);
+    }
+    let mut totals: Vec<(String, Stats)> = by_key.into_iter().collect();
+    totals.sort_by(|(ka, a), (kb, b)| b.time.cmp(&a.time).then(ka.cmp(kb)));
+    totals
+}
+
+impl Profile {
+    fn instructions<'a>(&'a self, vm: &'a VM) -> impl Iterator<Item = (usize, &'a Instr, Stats)> {
+        self.pcs
+            .iter()
+            .filter_map(|(pc, s)| vm.programlist.get(*pc).map(|i| (*pc, i, *s)))
+    }
+
+    pub fn by_label(&self, vm: &VM) -> Vec<(String, Stats)> {
+        totals(
+            self.instructions(vm)
+                .map(|(pc, _, s)| (vm.label_region(pc).unwrap_or(NO_LABEL).to_string(), s)),
+        )
+    }
+
+    // Built-in and foreign functions, leaving out literals and braces.
+    pub fn by_function(&self, vm: &VM) -> Vec<(String, Stats)> {
+        totals(self.instructions(vm).filter_map(|(_, i, s)| match i {
+            Instr::Func(_, name) => Some((name.clone(), s)),
+            _ => None,
+        }))
+    }
+
+    // Folded stacks (`region;op nanoseconds`) for flamegraph tools.
+    pub fn folded(&self, vm: &VM) -> String {
+        let stacks = totals(self.instructions(vm).map(|(pc, i, s)| {
+            let region = vm.label_region(pc).unwrap_or(NO_LABEL);
+            (format!("{};{}", region, op_name(i)), s)
+        }));
+        let mut out = String::new();
+        for (stack, s) in stacks {
+            writeln!(out, "{} {}", stack, s.time.as_nanos()).unwrap();
+        }
+        out
+    }
+
+    pub fn report(&self, vm: &VM) -> String {
+        let mut out = String::new();
+        let mut table = |title: &str, rows: Vec<(String, Stats)>| {
+            writeln!(out, "{:<24} {:>10} {:>12}", title, "count", "time (us)").unwrap();
+            for (name, s) in rows {
+                let micros = s.time.as_secs_f64() * 1e6;
+                writeln!(out, "{:<24} {:>10} {:>12.1}", name, s.count, micros).unwrap();
+            }
+            out.push('\n');
+        };
+        let mut pcs: Vec<(String, Stats)> = self
+            .instructions(vm)
+            .map(|(pc, i, s)| (format!("{:>4}  {}", pc, i), s))
+            .collect();
+        pcs.sort_by_key(|(_, s)| std::cmp::Reverse(s.time));
+        table("pc", pcs);
+        table("label", self.by_label(vm));
+        table("function", self.by_function(vm));
+        out.trim_end().to_string() + "\n"
+    }
+}
+
+// Hooks that time every instruction into a shared `Profile`.
+pub struct Profiler {
+    profile: Rc<RefCell<Profile>>,
+    started: Option<Instant>,
+}
+
+impl Profiler {
+    // Adds a profiler to the VM's hooks, returning the profile it fills in.
+    pub fn attach(vm: &mut VM) -> Rc<RefCell<Profile>> {
+        let profile = Rc::new(RefCell::new(Profile::default()));
+        vm.hooks.push(Box::new(Profiler {
+            profile: profile.clone(),
+            started: None,
+        }));
+        profile
+    }
+}
+
+impl Hooks for Profiler {
+    fn before_instruction(&mut self, _vm: &VM, _pc: usize) {
+        self.started = Some(Instant::now());
+    }
+
+    fn after_instruction(&mut self, _vm: &VM, pc: usize) {
+        if let Some(started) = self.started.take() {
+            self.profile
+                .borrow_mut()
+                .pcs
+                .entry(pc)
+                .or_default()
+                .add(Stats {
+                    count: 1,
+                    time: started.elapsed(),
+                });
+        }
+    }
+}
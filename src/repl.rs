@@ -0,0 +1,138 @@
+use std::fmt::Write;
+use std::fs;
+
+use crate::asm;
+use crate::vm::VM;
+
+pub const HELP: &str = "Enter instructions in text syntax, e.g. `1 2 plus \"x\" concat`.
+Each line is appended to the program and run from the current pc.
+
+  :stack        show the stack
+  :ctx          show the context
+  :dis          disassemble the program, marking the current pc
+  :step         execute a single instruction
+  :run          run from the current pc until the end, a pause or an exit
+  :load <file>  append a program from a file without running it
+  :reset        start over with an empty VM
+  :help         show this help
+  :quit         leave the REPL";
+
+// Interactive session on a single, persistent VM.
+pub struct Repl {
+    pub vm: VM,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Repl::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl { vm: VM::new() }
+    }
+
+    pub fn stack(&self) -> String {
+        let stack: Vec<serde_json::Value> = self.vm.stack.iter().map(|v| v.to_json()).collect();
+        serde_json::Value::Array(stack).to_string()
+    }
+
+    pub fn context(&self) -> String {
+        let context: serde_json::Map<String, serde_json::Value> = self
+            .vm
+            .context
+            .iter()
+            .map(|(k, v)| (k, v.to_json()))
+            .collect();
+        serde_json::Value::Object(context).to_string()
+    }
+
+    fn state(&self) -> String {
+        format!("stack: {}\ncontext: {}", self.stack(), self.context())
+    }
+
+    fn load(&mut self, source: &str) -> Result<(), String> {
+        let instructions = asm::parse_program(source)?;
+        self.vm.try_load(instructions).map_err(|e| e.to_string())
+    }
+
+    // Steps until the program ends, pauses or exits; `limit` caps the number
+    // of instructions.
+    fn execute(&mut self, limit: Option<usize>) -> String {
+        if self.vm.exited {
+            return "error: the program has exited, use :reset to start over".to_string();
+        }
+        let vm = &mut self.vm;
+        let mut steps = 0;
+        let mut error = None;
+        vm.running = true;
+        while vm.running && vm.pc < vm.programlist.len() && limit != Some(steps) {
+            if let Err(e) = vm.step() {
+                error = Some(e);
+                break;
+            }
+            steps += 1;
+        }
+        let mut out = String::new();
+        if let Some(e) = error {
+            writeln!(out, "error at pc {}: {}", self.vm.pc, e).unwrap();
+            // skip whatever is left of the failed input
+            self.vm.pc = self.vm.programlist.len();
+        } else if self.vm.exited {
+            out.push_str("(exited)\n");
+        } else if !self.vm.running {
+            out.push_str("(paused)\n");
+        }
+        out.push_str(&self.state());
+        out
+    }
+
+    // Handles one line of input and returns the text to show, or `None` when
+    // the session should end.
+    pub fn eval(&mut self, line: &str) -> Option<String> {
+        let line = line.trim();
+        if !line.starts_with(':') {
+            if line.is_empty() {
+                return Some(String::new());
+            }
+            return Some(match self.load(line) {
+                Ok(()) => self.execute(None),
+                Err(e) => format!("error: {}", e),
+            });
+        }
+        let (command, arg) = match line.split_once(char::is_whitespace) {
+            Some((c, a)) => (c, a.trim()),
+            None => (line, ""),
+        };
+        Some(match command {
+            ":quit" | ":q" => return None,
+            ":help" => HELP.to_string(),
+            ":stack" => self.stack(),
+            ":ctx" => self.context(),
+            ":dis" => asm::disassemble(&self.vm, 0..self.vm.programlist.len())
+                .trim_end()
+                .to_string(),
+            ":step" => {
+                if self.vm.pc >= self.vm.programlist.len() {
+                    "error: nothing left to execute".to_string()
+                } else {
+                    self.execute(Some(1))
+                }
+            }
+            ":run" => self.execute(None),
+            ":load" => match fs::read_to_string(arg) {
+                Ok(source) => match self.load(&source) {
+                    Ok(()) => format!("loaded {}", arg),
+                    Err(e) => format!("error: {}", e),
+                },
+                Err(e) => format!("error: cannot read {}: {}", arg, e),
+            },
+            ":reset" => {
+                self.vm = VM::new();
+                "reset".to_string()
+            }
+            _ => format!("error: unknown command {}, try :help", command),
+        })
+    }
+}
@@ -0,0 +1,253 @@
+use std::rc::Rc;
+
+use crate::foreign::Module;
+use crate::vm::{ForeignFunc, StackSignature, Value, VmError, VM};
+
+// Bundled foreign function modules, for hosts to enable individually:
+//
+//   vm.register_module(stdlib::math());
+//
+// Functions take their arguments in push order, see `foreign`.
+
+pub const MODULES: &[&str] = &["math", "string", "time", "json", "transaction"];
+
+// The bundled module called `name`.
+pub fn module(name: &str) -> Option<Module> {
+    match name {
+        "math" => Some(math()),
+        "string" => Some(string()),
+        "time" => Some(time()),
+        "json" => Some(json()),
+        "transaction" => Some(transaction()),
+        _ => None,
+    }
+}
+
+pub fn math() -> Module {
+    let mut m = Module::new("math");
+    m.register("floor", f64::floor)
+        .describe("Rounds down to a whole number.")
+        .mark_pure();
+    m.register("ceil", f64::ceil)
+        .describe("Rounds up to a whole number.")
+        .mark_pure();
+    m.register("round", f64::round)
+        .describe("Rounds to the nearest whole number, halves away from zero.")
+        .mark_pure();
+    m.register("abs", f64::abs)
+        .describe("The absolute value.")
+        .mark_pure();
+    m.register("sqrt", f64::sqrt)
+        .describe("The square root.")
+        .mark_pure();
+    m.register("sin", f64::sin)
+        .describe("The sine of an angle in radians.")
+        .mark_pure();
+    m.register("cos", f64::cos)
+        .describe("The cosine of an angle in radians.")
+        .mark_pure();
+    m.register("pow", f64::powf)
+        .describe("The first number raised to the power of the second.")
+        .mark_pure();
+    m.register("min", f64::min)
+        .describe("The smaller of two numbers.")
+        .mark_pure();
+    m.register("max", f64::max)
+        .describe("The larger of two numbers.")
+        .mark_pure();
+    m.register("mod", f64::rem_euclid)
+        .describe("The remainder of dividing the first number by the second, non-negative if the second is positive.")
+        .mark_pure();
+    m.register("pi", || std::f64::consts::PI)
+        .describe("The number π.")
+        .mark_pure();
+    m
+}
+
+pub fn string() -> Module {
+    let mut m = Module::new("string");
+    m.register("length", |s: String| s.chars().count() as i64)
+        .describe("The number of characters.")
+        .mark_pure();
+    m.register("upper", |s: String| s.to_uppercase())
+        .describe("In upper case.")
+        .mark_pure();
+    m.register("lower", |s: String| s.to_lowercase())
+        .describe("In lower case.")
+        .mark_pure();
+    m.register("trim", |s: String| s.trim().to_string())
+        .describe("Without leading and trailing whitespace.")
+        .mark_pure();
+    m.register("slice", |s: String, start: i64, end: i64| {
+        let start = start.max(0) as usize;
+        let end = end.max(0) as usize;
+        s.chars()
+            .skip(start)
+            .take(end.saturating_sub(start))
+            .collect::<String>()
+    })
+    .describe("The characters of the string from the first index up to the second, clamped to the string.")
+    .mark_pure();
+    m.register("indexOf", |s: String, sub: String| match s.find(&sub) {
+        Some(i) => s[..i].chars().count() as i64,
+        None => -1,
+    })
+    .describe("Where the second string first occurs in the first, in characters, or -1.")
+    .mark_pure();
+    m.register("contains", |s: String, sub: String| s.contains(&sub))
+        .describe("Whether the second string occurs in the first.")
+        .mark_pure();
+    m.register("startsWith", |s: String, prefix: String| {
+        s.starts_with(&prefix)
+    })
+    .describe("Whether the first string starts with the second.")
+    .mark_pure();
+    m.register("endsWith", |s: String, suffix: String| s.ends_with(&suffix))
+        .describe("Whether the first string ends with the second.")
+        .mark_pure();
+    m.register("replace", |s: String, from: String, to: String| {
+        s.replace(&from, &to)
+    })
+    .describe("The first string with every occurrence of the second replaced by the third.")
+    .mark_pure();
+    m.register("repeat", |s: String, n: i64| s.repeat(n.max(0) as usize))
+        .describe("The string repeated the given number of times.")
+        .mark_pure();
+    m.register("toNumber", |s: String| {
+        s.trim().parse::<f64>().unwrap_or(f64::NAN)
+    })
+    .describe("The number the string spells, or NaN.")
+    .mark_pure();
+    m.register("fromNumber", |n: f64| Value::Number(n).to_string())
+        .describe("The number as a string, as `concat` shows it.")
+        .mark_pure();
+    m
+}
+
+// Milliseconds since the Unix epoch.
+fn now() -> f64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        js_sys::Date::now()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let elapsed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        elapsed.as_secs_f64() * 1000.0
+    }
+}
+
+pub fn time() -> Module {
+    let mut m = Module::new("time");
+    m.register("now", now)
+        .describe("Milliseconds since the Unix epoch.");
+    m.register("since", |then: f64| now() - then)
+        .describe("Milliseconds since an earlier `time.now`.");
+    m
+}
+
+// "a.b.0" to the JSON pointer "/a/b/0".
+fn pointer(path: &str) -> String {
+    path.split('.')
+        .map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+// Whole numbers are written without a fraction, as JavaScript does.
+fn to_json(v: &Value) -> serde_json::Value {
+    match v {
+        Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => serde_json::json!(*n as i64),
+        v => v.to_json(),
+    }
+}
+
+fn parse(doc: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(doc).map_err(|e| format!("invalid JSON: {}", e))
+}
+
+// Documents are passed around as JSON strings; paths are keys and array
+// indices separated by dots.
+pub fn json() -> Module {
+    let mut m = Module::new("json");
+    m.register("encode", |v: Value| to_json(&v).to_string())
+        .describe("The value as JSON.")
+        .mark_pure();
+    m.register("get", |doc: String, path: String| {
+        parse(&doc)?
+            .pointer(&pointer(&path))
+            .and_then(Value::from_json)
+            .ok_or_else(|| format!("no number or string at {}", path))
+    })
+    .describe("The number or string at a path like `a.b.0` in a JSON document.")
+    .mark_pure();
+    m.register("has", |doc: String, path: String| {
+        parse(&doc).map(|doc| doc.pointer(&pointer(&path)).is_some())
+    })
+    .describe("Whether a JSON document has a value at a path.")
+    .mark_pure();
+    m.register("set", |doc: String, path: String, value: Value| {
+        let mut doc = parse(&doc)?;
+        let mut node = &mut doc;
+        for key in path.split('.') {
+            node = match node {
+                serde_json::Value::Object(map) => map.entry(key).or_insert(serde_json::json!({})),
+                serde_json::Value::Array(items) => match key.parse::<usize>() {
+                    Ok(i) if i < items.len() => &mut items[i],
+                    _ => return Err(format!("no index {} in {}", key, path)),
+                },
+                _ => return Err(format!("cannot set {} in a number or string", path)),
+            };
+        }
+        *node = to_json(&value);
+        Ok(doc.to_string())
+    })
+    .describe(
+        "The JSON document with the value stored at the path, adding objects for missing keys.",
+    )
+    .mark_pure();
+    m
+}
+
+// A function that only acts on the VM, leaving the stack alone.
+fn vm_function(
+    name: &str,
+    func: fn(&mut VM) -> Result<(), VmError>,
+    description: &str,
+) -> ForeignFunc {
+    ForeignFunc {
+        func: Rc::new(func),
+        name: name.to_string(),
+        signature: Some(StackSignature {
+            inputs: vec![],
+            outputs: vec![],
+        }),
+        description: description.to_string(),
+        ..Default::default()
+    }
+}
+
+// Context transactions for scripts, see `transaction`.
+pub fn transaction() -> Module {
+    let mut m = Module::new("transaction");
+    m.register_foreign_function(vm_function(
+        "begin",
+        |vm| {
+            vm.begin();
+            Ok(())
+        },
+        "Starts recording context changes, to keep or undo them later.",
+    ));
+    m.register_foreign_function(vm_function(
+        "commit",
+        VM::commit,
+        "Keeps the context changes since the matching `transaction.begin`.",
+    ));
+    m.register_foreign_function(vm_function(
+        "rollback",
+        VM::rollback,
+        "Undoes the context changes since the matching `transaction.begin`.",
+    ));
+    m
+}
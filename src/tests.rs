@@ -6,470 +6,66 @@ mod tests {
   // Note this useful idiom: importing names from outer (for mod tests) scope.
   use super::*;
 
-  fn test(location: &str) {
-    let contents = fs::read_to_string(location).expect("Something went wrong reading the file");
-    let v: serde_json::Value = serde_json::from_str(&contents).unwrap();
-    let instructions = v.as_object().unwrap()["input_program"]
-      .as_array()
-      .unwrap()
-      .to_vec();
-
-    let mut vm = crate::vm::VM::new();
-    vm.load(instructions);
-
-    if v.as_object().unwrap().contains_key("initial_context") {
-      let ctx = v.as_object().unwrap()["initial_context"]
-        .as_object()
-        .unwrap();
-      for k in ctx.keys() {
-        let v = ctx.get(k).unwrap();
-        if v.is_string() {
-          vm.stack
-            .push(crate::vm::Value::String(v.as_str().unwrap().to_string()));
-          vm.stack
-            .push(crate::vm::Value::String(k.as_str().to_string()));
-          vm.i_setcontext();
-        } else if v.is_number() {
-          vm.stack.push(crate::vm::Value::Number(v.as_f64().unwrap()));
-          vm.stack
-            .push(crate::vm::Value::String(k.as_str().to_string()));
-          vm.i_setcontext();
-        }
-      }
-    }
-
-    vm.run();
-
-    println!("\nStack at end: {:?}", vm.stack);
-
-    let expected = v.as_object().unwrap()["expected"].as_object().unwrap();
-    if expected.contains_key("stack") {
-      let stack = expected["stack"].as_array().unwrap();
-      let mut i = 0;
-      for s in stack {
-        match &vm.stack[i as usize] {
-          crate::vm::Value::Number(val) => {
-            assert_eq!(*val, s.as_f64().unwrap());
-          }
-          crate::vm::Value::String(val) => {
-            assert_eq!(val, s.as_str().unwrap());
-          }
-        }
-        i += 1;
-      }
-    }
-
-    if expected.contains_key("context") {
-      let exp_ctx = expected["context"].as_object().unwrap();
-      assert_eq!(vm.context.len(), exp_ctx.len(), "context size mismatch");
-      for (k, v) in exp_ctx {
-        let actual = vm
-          .context
-          .get(k)
-          .unwrap_or_else(|| panic!("expected context key missing: {}", k));
-        match actual {
-          crate::vm::Value::Number(n) => assert_eq!(*n, v.as_f64().unwrap()),
-          crate::vm::Value::String(s) => assert_eq!(s, v.as_str().unwrap()),
-        }
+  // One test per fixture in src/tests, see build.rs.
+  macro_rules! fixture_test {
+    ($test:ident, $name:literal) => {
+      #[test]
+      fn $test() {
+        let report = crate::conformance::check_fixture($name);
+        assert!(report.passed() && report.results.len() == 1, "{}", report);
       }
-    }
+    };
   }
+  include!(concat!(env!("OUT_DIR"), "/fixture_tests.rs"));
 
   #[test]
-  fn test_and_0() {
-    test("./src/tests/and_0.json");
-  }
-  #[test]
-  fn test_and_1() {
-    test("./src/tests/and_1.json");
-  }
-  #[test]
-  fn test_and_2() {
-    test("./src/tests/and_2.json");
-  }
-  #[test]
-  fn test_and_3() {
-    test("./src/tests/and_3.json");
-  }
-  #[test]
-  fn test_and_4() {
-    test("./src/tests/and_4.json");
-  }
-  #[test]
-  fn test_and_5() {
-    test("./src/tests/and_5.json");
-  }
-  #[test]
-  fn test_braces() {
-    test("./src/tests/braces.json");
-  }
-  #[test]
-  fn test_braces_nested() {
-    test("./src/tests/braces_nested.json");
-  }
-  #[test]
-  fn test_concat_0() {
-    test("./src/tests/concat_0.json");
-  }
-  #[test]
-  fn test_concat_1() {
-    test("./src/tests/concat_1.json");
-  }
-  #[test]
-  fn test_concat_2() {
-    test("./src/tests/concat_2.json");
-  }
-  #[test]
-  fn test_concat_3() {
-    test("./src/tests/concat_3.json");
-  }
-  #[test]
-  fn test_delContext() {
-    test("./src/tests/delContext.json");
-  }
-  #[test]
-  fn test_dup_0() {
-    test("./src/tests/dup_0.json");
-  }
-  #[test]
-  fn test_dup_1() {
-    test("./src/tests/dup_1.json");
-  }
-  #[test]
-  fn test_dup_2() {
-    test("./src/tests/dup_2.json");
-  }
-  #[test]
-  fn test_dup_3() {
-    test("./src/tests/dup_3.json");
-  }
-  #[test]
-  fn test_eq_0() {
-    test("./src/tests/eq_0.json");
-  }
-  #[test]
-  fn test_eq_1() {
-    test("./src/tests/eq_1.json");
-  }
-  #[test]
-  fn test_eq_2() {
-    test("./src/tests/eq_2.json");
-  }
-  #[test]
-  fn test_eq_3() {
-    test("./src/tests/eq_3.json");
-  }
-  #[test]
-  fn test_eq_4() {
-    test("./src/tests/eq_4.json");
-  }
-  #[test]
-  fn test_eq_5() {
-    test("./src/tests/eq_5.json");
-  }
-  #[test]
-  fn test_eq_6() {
-    test("./src/tests/eq_6.json");
-  }
-  #[test]
-  fn test_example_0() {
-    test("./src/tests/example_0.json");
-  }
-  #[test]
-  fn test_example_1() {
-    test("./src/tests/example_1.json");
-  }
-  #[test]
-  fn test_example_2() {
-    test("./src/tests/example_2.json");
-  }
-  #[test]
-  fn test_example_3() {
-    test("./src/tests/example_3.json");
-  }
-  #[test]
-  fn test_example_4() {
-    test("./src/tests/example_4.json");
-  }
-  #[test]
-  fn test_getContext_1() {
-    test("./src/tests/getContext_1.json");
-  }
-  #[test]
-  fn test_getContext_2() {
-    test("./src/tests/getContext_2.json");
-  }
-  #[test]
-  fn test_getContext_3() {
-    test("./src/tests/getContext_3.json");
-  }
-  #[test]
-  fn test_goto_by_label() {
-    test("./src/tests/goto_by_label.json");
-  }
-  #[test]
-  fn test_goto_by_number() {
-    test("./src/tests/goto_by_number.json");
-  }
-  #[test]
-  fn test_gt_0() {
-    test("./src/tests/gt_0.json");
-  }
-  #[test]
-  fn test_gt_1() {
-    test("./src/tests/gt_1.json");
-  }
-  #[test]
-  fn test_gt_2() {
-    test("./src/tests/gt_2.json");
-  }
-  #[test]
-  fn test_gt_3() {
-    test("./src/tests/gt_3.json");
-  }
-  #[test]
-  fn test_gt_4() {
-    test("./src/tests/gt_4.json");
-  }
-  #[test]
-  fn test_gt_5() {
-    test("./src/tests/gt_5.json");
-  }
-  #[test]
-  fn test_gt_6() {
-    test("./src/tests/gt_6.json");
-  }
-  #[test]
-  fn test_gt_7() {
-    test("./src/tests/gt_7.json");
-  }
-  #[test]
-  fn test_hasContext_1() {
-    test("./src/tests/hasContext_1.json");
-  }
-  #[test]
-  fn test_hasContext_2() {
-    test("./src/tests/hasContext_2.json");
-  }
-  #[test]
-  fn test_hasContext_3() {
-    test("./src/tests/hasContext_3.json");
-  }
-  #[test]
-  fn test_if_jgz_0() {
-    test("./src/tests/if_jgz_0.json");
-  }
-  #[test]
-  fn test_if_jgz_1() {
-    test("./src/tests/if_jgz_1.json");
-  }
-  #[test]
-  fn test_jgz_0() {
-    test("./src/tests/jgz_0.json");
-  }
-  #[test]
-  fn test_jgz_1() {
-    test("./src/tests/jgz_1.json");
-  }
-  #[test]
-  fn test_jgz_2() {
-    test("./src/tests/jgz_2.json");
-  }
-  #[test]
-  fn test_jz_0() {
-    test("./src/tests/jz_0.json");
-  }
-  #[test]
-  fn test_jz_1() {
-    test("./src/tests/jz_1.json");
-  }
-  #[test]
-  fn test_jz_2() {
-    test("./src/tests/jz_2.json");
-  }
-  #[test]
-  fn test_lt_0() {
-    test("./src/tests/lt_0.json");
-  }
-  #[test]
-  fn test_lt_1() {
-    test("./src/tests/lt_1.json");
-  }
-  #[test]
-  fn test_lt_2() {
-    test("./src/tests/lt_2.json");
-  }
-  #[test]
-  fn test_lt_3() {
-    test("./src/tests/lt_3.json");
-  }
-  #[test]
-  fn test_lt_4() {
-    test("./src/tests/lt_4.json");
-  }
-  #[test]
-  fn test_lt_5() {
-    test("./src/tests/lt_5.json");
-  }
-  #[test]
-  fn test_lt_6() {
-    test("./src/tests/lt_6.json");
-  }
-  #[test]
-  fn test_lt_7() {
-    test("./src/tests/lt_7.json");
-  }
-  #[test]
-  fn test_min_0() {
-    test("./src/tests/min_0.json");
-  }
-  #[test]
-  fn test_min_1() {
-    test("./src/tests/min_1.json");
-  }
-  #[test]
-  fn test_min_2() {
-    test("./src/tests/min_2.json");
-  }
-  #[test]
-  fn test_multiply_0() {
-    test("./src/tests/multiply_0.json");
-  }
-  #[test]
-  fn test_multiply_1() {
-    test("./src/tests/multiply_1.json");
-  }
-  #[test]
-  fn test_nop_0() {
-    test("./src/tests/nop_0.json");
-  }
-  #[test]
-  fn test_nop_1() {
-    test("./src/tests/nop_1.json");
-  }
-  #[test]
-  fn test_not_0() {
-    test("./src/tests/not_0.json");
-  }
-  #[test]
-  fn test_not_1() {
-    test("./src/tests/not_1.json");
-  }
-  #[test]
-  fn test_not_2() {
-    test("./src/tests/not_2.json");
-  }
-  #[test]
-  fn test_or_0() {
-    test("./src/tests/or_0.json");
-  }
-  #[test]
-  fn test_or_1() {
-    test("./src/tests/or_1.json");
-  }
-  #[test]
-  fn test_or_2() {
-    test("./src/tests/or_2.json");
-  }
-  #[test]
-  fn test_or_3() {
-    test("./src/tests/or_3.json");
-  }
-  #[test]
-  fn test_or_4() {
-    test("./src/tests/or_4.json");
-  }
-  #[test]
-  fn test_or_5() {
-    test("./src/tests/or_5.json");
-  }
-  #[test]
-  fn test_plus_0() {
-    test("./src/tests/plus_0.json");
-  }
-  #[test]
-  fn test_plus_1() {
-    test("./src/tests/plus_1.json");
-  }
-  #[test]
-  fn test_poor_mans_function() {
-    test("./src/tests/poor_mans_function.json");
-  }
-  #[test]
-  fn test_pop_0() {
-    test("./src/tests/pop_0.json");
-  }
-  #[test]
-  fn test_pop_1() {
-    test("./src/tests/pop_1.json");
-  }
-  #[test]
-  fn test_pop_2() {
-    test("./src/tests/pop_2.json");
-  }
-  #[test]
-  fn test_pop_3() {
-    test("./src/tests/pop_3.json");
-  }
-  #[test]
-  fn test_ppc_0() {
-    test("./src/tests/ppc_0.json");
-  }
-  #[test]
-  fn test_ppc_1() {
-    test("./src/tests/ppc_1.json");
-  }
-  #[test]
-  fn test_push_negative_numbers() {
-    test("./src/tests/push_negative_numbers.json");
-  }
-  #[test]
-  fn test_push_numbers() {
-    test("./src/tests/push_numbers.json");
-  }
-  #[test]
-  fn test_push_strings() {
-    test("./src/tests/push_strings.json");
-  }
-  #[test]
-  fn test_rconcat_0() {
-    test("./src/tests/rconcat_0.json");
-  }
-  #[test]
-  fn test_rconcat_1() {
-    test("./src/tests/rconcat_1.json");
-  }
-  #[test]
-  fn test_rconcat_2() {
-    test("./src/tests/rconcat_2.json");
-  }
-  #[test]
-  fn test_rconcat_3() {
-    test("./src/tests/rconcat_3.json");
-  }
-  #[test]
-  fn test_setContext_number() {
-    test("./src/tests/setContext_number.json");
-  }
-  #[test]
-  fn test_setContext_string() {
-    test("./src/tests/setContext_string.json");
-  }
-  #[test]
-  fn test_stacksize_0() {
-    test("./src/tests/stacksize_0.json");
-  }
-  #[test]
-  fn test_stacksize_1() {
-    test("./src/tests/stacksize_1.json");
-  }
-  #[test]
-  fn test_stdout_0() {
-    test("./src/tests/stdout_0.json");
-  }
-  #[test]
-  fn test_stdout_1() {
-    test("./src/tests/stdout_1.json");
+  fn test_conformance_reports_mismatches() {
+    use crate::conformance::{check, check_all};
+    let fixture = |program: &str, expected: serde_json::Value| {
+      serde_json::json!({
+        "input_program": crate::asm::parse(program).unwrap(),
+        "initial_context": { "x": 2 },
+        "expected": expected,
+      })
+      .to_string()
+    };
+    assert_eq!(check(&fixture("1 2", serde_json::json!({ "stack": [1, 2] }))), Ok(()));
+    // extra and missing stack entries both fail
+    assert_eq!(
+      check(&fixture("1 2 3", serde_json::json!({ "stack": [1, 2] }))),
+      Err(vec!["stack: expected [1,2], got [1.0,2.0,3.0]".to_string()])
+    );
+    assert!(check(&fixture("1", serde_json::json!({ "stack": [1, 2] }))).is_err());
+    assert!(check(&fixture("\"1\"", serde_json::json!({ "stack": [1] }))).is_err());
+    assert_eq!(check(&fixture("", serde_json::json!({ "context": { "x": 2 } }))), Ok(()));
+    assert!(check(&fixture("3 \"y\" setContext", serde_json::json!({ "context": { "x": 2 } }))).is_err());
+    assert_eq!(
+      check(&fixture("\"a\" stdout 1 stdout exit", serde_json::json!({ "stdout": "a1", "exited": true, "paused": false }))),
+      Ok(())
+    );
+    assert_eq!(
+      check(&fixture("pause", serde_json::json!({ "exited": true }))),
+      Err(vec!["exited: expected true, got false".to_string()])
+    );
+    assert_eq!(
+      check(&fixture("1 \"a\" plus", serde_json::json!({}))),
+      Err(vec!["error: expected none, got \"+: operands must be numbers\"".to_string()])
+    );
+    assert_eq!(
+      check(&fixture("1", serde_json::json!({ "error": "boom" }))),
+      Err(vec!["error: expected \"boom\", got no error".to_string()])
+    );
+    assert_eq!(check("{}"), Err(vec!["input_program is not an array".to_string()]));
+
+    let pass = fixture("1", serde_json::json!({ "stack": [1] }));
+    let fail = fixture("1", serde_json::json!({ "stack": [] }));
+    let report = check_all(vec![("pass", pass.as_str()), ("fail", fail.as_str())]);
+    assert!(!report.passed());
+    assert_eq!(report.failures(), vec!["fail"]);
+    assert_eq!(
+      report.to_string(),
+      "ok    pass\nFAIL  fail\n      stack: expected [], got [1.0]\n1 passed, 1 failed"
+    );
   }
 
   fn run_with_numeric_key(functionName: &str) {
@@ -662,4 +258,1698 @@ mod tests {
     ]);
     assert_eq!(vm.stack.len(), 0);
   }
+
+  fn load_program(instrs: Vec<serde_json::Value>) -> VM {
+    let mut vm = VM::new();
+    vm.load(instrs);
+    vm
+  }
+
+  #[test]
+  fn test_verify_fixtures_have_no_errors() {
+    for (name, contents) in crate::conformance::FIXTURES {
+      let v: serde_json::Value = serde_json::from_str(contents).unwrap();
+      if v["expected"].get("error").is_some() {
+        continue;
+      }
+      let vm = crate::conformance::load(&v).unwrap();
+      let errors: Vec<_> = vm.verify().into_iter().filter(|d| d.is_error()).collect();
+      assert!(errors.is_empty(), "{}: {:?}", name, errors);
+    }
+  }
+
+  #[test]
+  fn test_verify_reports_type_mismatch() {
+    use crate::verifier::DiagnosticKind;
+    use crate::vm::ValueType;
+    let vm = load_program(vec![
+      serde_json::json!({ "type": "push-string-instruction", "value": "foo" }),
+      serde_json::json!({ "type": "push-number-instruction", "value": 1 }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "plus" }),
+    ]);
+    let diagnostics = vm.verify();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].pc, 2);
+    assert_eq!(
+      diagnostics[0].kind,
+      DiagnosticKind::TypeMismatch {
+        function: "plus".to_string(),
+        expected: ValueType::Number,
+        found: ValueType::String,
+      }
+    );
+  }
+
+  #[test]
+  fn test_verify_reports_definite_underflow_only() {
+    use crate::verifier::DiagnosticKind;
+    let vm = load_program(vec![
+      serde_json::json!({ "type": "push-number-instruction", "value": 1 }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "dup" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "*" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "-" }),
+    ]);
+    let diagnostics = vm.verify();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].pc, 3);
+    assert_eq!(
+      diagnostics[0].kind,
+      DiagnosticKind::StackUnderflow {
+        function: "-".to_string(),
+        needed: 2,
+        available: 1,
+      }
+    );
+
+    // depending on an unknown context value, the stack may or may not be deep enough
+    let vm = load_program(vec![
+      serde_json::json!({ "type": "push-number-instruction", "value": 1 }),
+      serde_json::json!({ "type": "push-string-instruction", "value": "flag" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "getContext" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "jz" }),
+      serde_json::json!({ "type": "push-number-instruction", "value": 2 }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "plus" }),
+    ]);
+    assert!(vm.verify().iter().all(|d| !d.is_error()));
+  }
+
+  #[test]
+  fn test_verify_computed_goto_may_land_anywhere() {
+    use crate::verifier::DiagnosticKind;
+    // the goto may land on `plus` with both numbers still on the stack
+    let source = "1 2 \"t\" getContext goto l: pop plus";
+    let vm = load_program(crate::asm::parse(source).unwrap());
+    assert!(vm.verify().iter().all(|d| !d.is_error()));
+
+    // unless gotos by number are forbidden, leaving only the label
+    let mut vm = crate::vm::VM::new();
+    vm.policy = crate::policy::Policy::default().deny_goto_by_number();
+    vm.load(crate::asm::parse(source).unwrap());
+    let diagnostics = vm.verify();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].pc, 6);
+    assert!(matches!(diagnostics[0].kind, DiagnosticKind::StackUnderflow { .. }));
+  }
+
+  #[test]
+  fn test_verify_follows_label_gotos() {
+    use crate::verifier::DiagnosticKind;
+    let vm = load_program(vec![
+      serde_json::json!({ "type": "push-string-instruction", "value": "end" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "goto" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "plus" }),
+      serde_json::json!({ "type": "push-string-instruction", "value": "done", "label": "end" }),
+      serde_json::json!({ "type": "push-string-instruction", "value": "nowhere" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "goto" }),
+    ]);
+    let diagnostics = vm.verify();
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].pc, 2);
+    assert_eq!(diagnostics[0].kind, DiagnosticKind::Unreachable { end: 3 });
+    assert_eq!(diagnostics[1].pc, 5);
+    assert_eq!(
+      diagnostics[1].kind,
+      DiagnosticKind::UnknownLabel("nowhere".to_string())
+    );
+  }
+
+  #[test]
+  fn test_verify_uses_foreign_function_signatures() {
+    use crate::verifier::DiagnosticKind;
+    use crate::vm::{ForeignFunc, StackSignature, ValueType};
+    use std::rc::Rc;
+    let mut vm = VM::new();
+    vm.register_foreign_function(ForeignFunc {
+      name: "playSound".to_string(),
+      func: Rc::new(|vm: &mut VM| {
+        vm.stack.pop();
+        Ok(())
+      }),
+      signature: Some(StackSignature {
+        inputs: vec![ValueType::String],
+        outputs: vec![],
+      }),
+      ..Default::default()
+    });
+    vm.register_foreign_function(ForeignFunc {
+      name: "mystery".to_string(),
+      func: Rc::new(|_vm: &mut VM| Ok(())),
+      ..Default::default()
+    });
+    vm.load(vec![
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "mystery" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "plus" }),
+      serde_json::json!({ "type": "push-number-instruction", "value": 3 }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "playSound" }),
+    ]);
+    // `mystery` has no signature, so `plus` may well have its operands
+    let diagnostics = vm.verify();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].pc, 3);
+    assert_eq!(
+      diagnostics[0].kind,
+      DiagnosticKind::TypeMismatch {
+        function: "playSound".to_string(),
+        expected: ValueType::String,
+        found: ValueType::Number,
+      }
+    );
+  }
+
+  #[test]
+  fn test_cfg_splits_blocks_at_jumps_and_labels() {
+    use crate::cfg::{ControlFlowGraph, EdgeKind, Target};
+    let vm = load_program(vec![
+      serde_json::json!({ "type": "push-number-instruction", "value": 1 }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "jz" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "{" }),
+      serde_json::json!({ "type": "push-string-instruction", "value": "end" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "goto" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "}" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "exit" }),
+      serde_json::json!({ "type": "push-string-instruction", "value": "done", "label": "end" }),
+    ]);
+    let cfg = ControlFlowGraph::build(&vm);
+    let ranges: Vec<(usize, usize)> = cfg.blocks.iter().map(|b| (b.start, b.end)).collect();
+    assert_eq!(ranges, vec![(0, 2), (2, 3), (3, 5), (5, 6), (6, 7), (7, 8)]);
+    assert_eq!(cfg.blocks[5].labels, vec!["end".to_string()]);
+
+    let edges: Vec<(usize, Target, EdgeKind)> =
+      cfg.edges.iter().map(|e| (e.from, e.to, e.kind)).collect();
+    assert_eq!(
+      edges,
+      vec![
+        (0, Target::Block(1), EdgeKind::Fallthrough),
+        (0, Target::Block(2), EdgeKind::Skip),
+        (1, Target::Block(4), EdgeKind::SkipBlock),
+        (2, Target::Block(5), EdgeKind::Goto),
+        (3, Target::Block(4), EdgeKind::Fallthrough),
+        (4, Target::End, EdgeKind::Exit),
+        (5, Target::End, EdgeKind::Fallthrough),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_cfg_to_dot_annotates_labels_and_foreign_calls() {
+    use crate::cfg::ControlFlowGraph;
+    use crate::vm::ForeignFunc;
+    use std::rc::Rc;
+    let mut vm = VM::new();
+    vm.register_foreign_function(ForeignFunc {
+      name: "playSound".to_string(),
+      func: Rc::new(|_vm: &mut VM| Ok(())),
+      ..Default::default()
+    });
+    vm.load(vec![
+      serde_json::json!({ "type": "push-string-instruction", "value": "say \"hi\"", "label": "start" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "playSound" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "getContext" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "goto" }),
+    ]);
+    let dot = ControlFlowGraph::build(&vm).to_dot(&vm);
+    assert!(dot.starts_with("digraph tzo {"));
+    assert!(dot.contains(r"start:\l"));
+    // the JSON-quoted string, escaped once more for DOT
+    assert!(dot.contains(r#"\"say \\\"hi\\\"\""#));
+    assert!(dot.contains("playSound  (foreign)"));
+    assert!(dot.contains("fillcolor=\"lightblue\""));
+    // a computed goto may land on any instruction, so each is a block
+    assert!(dot.contains("b3 -> b0 [label=\"goto?\", color=\"blue\", style=\"dashed\"];"));
+    assert!(dot.contains("b3 -> b2 [label=\"goto?\", color=\"blue\", style=\"dashed\"];"));
+  }
+
+  #[test]
+  fn test_cfg_computed_gotos() {
+    use crate::cfg::{ControlFlowGraph, EdgeKind, Target};
+    let targets = |vm: &VM, pc: usize| -> Vec<Target> {
+      let cfg = ControlFlowGraph::build(vm);
+      let from = cfg.block_at(pc).unwrap();
+      cfg.edges.iter().filter(|e| e.from == from && e.kind == EdgeKind::DynamicGoto).map(|e| e.to).collect()
+    };
+    // `"a" goto` at pc 3 can be jumped to by `jz`, with any operand
+    let source = "\"t\" getContext jz \"a\" goto a: 1 2";
+    let vm = load_program(crate::asm::parse(source).unwrap());
+    let blocks: Vec<Target> = (1..7).map(|pc| Target::Block(ControlFlowGraph::build(&vm).block_at(pc).unwrap())).collect();
+    assert_eq!(targets(&vm, 4), blocks);
+
+    // without gotos by number, only labels
+    let mut vm = VM::new();
+    vm.policy = crate::policy::Policy::default().deny_goto_by_number();
+    vm.load(crate::asm::parse(source).unwrap());
+    let cfg = ControlFlowGraph::build(&vm);
+    assert_eq!(targets(&vm, 4), vec![Target::Block(cfg.block_at(5).unwrap())]);
+  }
+
+  #[test]
+  fn test_optimize_preserves_fixture_behavior() {
+    use crate::conformance::{load, run};
+    for (name, contents) in crate::conformance::FIXTURES {
+      let v: serde_json::Value = serde_json::from_str(contents).unwrap();
+      let plain = load(&v).unwrap();
+      let mut optimized = load(&v).unwrap();
+      optimized.optimize();
+      assert!(optimized.programlist.len() <= plain.programlist.len());
+      assert_eq!(run(plain), run(optimized), "{}", name);
+    }
+  }
+
+  #[test]
+  fn test_optimize_folds_constants_and_drops_dead_code() {
+    let mut vm = load_program(vec![
+      serde_json::json!({ "type": "push-number-instruction", "value": 2 }),
+      serde_json::json!({ "type": "push-number-instruction", "value": 3 }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "plus" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "nop" }),
+      serde_json::json!({ "type": "push-number-instruction", "value": 4 }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "mul" }),
+      serde_json::json!({ "type": "push-string-instruction", "value": "x" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "concat" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "dup" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "pop" }),
+    ]);
+    vm.optimize();
+    assert_eq!(vm.programlist.len(), 1);
+    assert_eq!(vm.programlist[0].to_string(), "\"x20\"");
+  }
+
+  #[test]
+  fn test_optimize_keeps_failing_dup_and_resume_point() {
+    // `dup` on an empty stack fails, so `dup pop` has to stay
+    let mut vm = load_program(crate::asm::parse("dup pop 1").unwrap());
+    vm.optimize();
+    assert_eq!(vm.programlist.len(), 3);
+    assert!(vm.try_run().is_err());
+
+    // a program stopped midway resumes with what the host left on the stack
+    let mut vm = load_program(crate::asm::parse("1 2 plus").unwrap());
+    vm.step().unwrap();
+    vm.stack = vec![crate::vm::Value::Number(10.0)];
+    vm.optimize();
+    assert_eq!(vm.programlist.len(), 3);
+    vm.run();
+    assert_eq!(vm.stack, vec![crate::vm::Value::Number(12.0)]);
+  }
+
+  #[test]
+  fn test_optimize_keeps_jump_targets_and_skips_intact() {
+    let mut vm = load_program(vec![
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "nop" }),
+      serde_json::json!({ "type": "push-number-instruction", "value": 7 }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "goto" }),
+      serde_json::json!({ "type": "push-number-instruction", "value": 1 }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "jz" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "nop" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "nop" }),
+      serde_json::json!({ "type": "push-number-instruction", "value": 1 }),
+      serde_json::json!({ "type": "push-number-instruction", "value": 2, "label": "two" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "plus" }),
+    ]);
+    let remap = vm.optimize();
+    let listing: Vec<String> = vm.programlist.iter().map(|i| i.to_string()).collect();
+    // the goto now lands on the first kept instruction at or after pc 7,
+    // the nop right after jz stays, and the label blocks folding of `1 2 plus`
+    assert_eq!(listing, vec!["5", "goto", "1", "jz", "nop", "1", "2", "plus"]);
+    assert_eq!(remap[7], 5);
+    assert_eq!(vm.labels["two"], 6);
+  }
+
+  #[test]
+  fn test_optimize_threads_goto_chains() {
+    let mut vm = load_program(vec![
+      serde_json::json!({ "type": "push-string-instruction", "value": "a" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "goto" }),
+      serde_json::json!({ "type": "push-string-instruction", "value": "b", "label": "a" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "goto" }),
+      serde_json::json!({ "type": "push-string-instruction", "value": "done", "label": "b" }),
+    ]);
+    vm.optimize();
+    assert_eq!(vm.programlist[0].to_string(), "\"b\"");
+    vm.run();
+    assert_eq!(vm.stack.len(), 1);
+    assert_eq!(vm.stack[0].as_string(), "done");
+  }
+
+  #[test]
+  fn test_optimize_leaves_computed_jumps_alone() {
+    let mut vm = load_program(vec![
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "nop" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "ppc" }),
+      serde_json::json!({ "type": "push-number-instruction", "value": 3 }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "plus" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "goto" }),
+    ]);
+    let remap = vm.optimize();
+    assert_eq!(vm.programlist.len(), 5);
+    assert_eq!(remap, vec![0, 1, 2, 3, 4, 5]);
+  }
+
+  #[test]
+  fn test_goto_label_at_pc_zero() {
+    let mut vm = load_program(vec![
+      serde_json::json!({ "type": "push-number-instruction", "value": 1, "label": "top" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "stacksize" }),
+      serde_json::json!({ "type": "push-number-instruction", "value": 3 }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "gt" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "jgz" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "exit" }),
+      serde_json::json!({ "type": "push-string-instruction", "value": "top" }),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "goto" }),
+    ]);
+    vm.run();
+    assert!(vm.exited);
+    assert_eq!(vm.stack.len(), 3);
+  }
+
+  #[test]
+  fn test_seeded_randint_is_deterministic() {
+    let program = || {
+      (0..5)
+        .flat_map(|_| {
+          vec![
+            serde_json::json!({ "type": "push-number-instruction", "value": 1000 }),
+            serde_json::json!({ "type": "invoke-function-instruction", "functionName": "randInt" }),
+          ]
+        })
+        .collect::<Vec<_>>()
+    };
+    let mut a = load_program(program());
+    let mut b = load_program(program());
+    a.seed(42);
+    b.seed(42);
+    a.run();
+    b.run();
+    assert_eq!(format!("{:?}", a.stack), format!("{:?}", b.stack));
+
+    // the sequence is StdRng's, and a cloned generator continues it
+    use crate::vm::Value;
+    use rand::{RngExt, SeedableRng};
+    let mut std_rng = rand::rngs::StdRng::seed_from_u64(42);
+    let expected: Vec<Value> = (0..5).map(|_| Value::Number((std_rng.random::<f64>() * 1000.0).floor())).collect();
+    assert_eq!(a.stack, expected);
+    let mut rng = crate::vm::Random::seed_from_u64(7);
+    rng.next_f64();
+    let mut clone = rng.clone();
+    assert_eq!(rng.next_f64(), clone.next_f64());
+  }
+
+  #[test]
+  fn test_asm_parse() {
+    let instrs = crate::asm::parse(
+      "start: 1 -2.5 plus  # comment \"not a string\"\n\"say \\\"hi\\\" #\" { } -\nend: exit",
+    )
+    .unwrap();
+    assert_eq!(
+      serde_json::Value::Array(instrs),
+      serde_json::json!([
+        { "type": "push-number-instruction", "value": 1.0, "label": "start" },
+        { "type": "push-number-instruction", "value": -2.5 },
+        { "type": "invoke-function-instruction", "functionName": "plus" },
+        { "type": "push-string-instruction", "value": "say \"hi\" #" },
+        { "type": "invoke-function-instruction", "functionName": "{" },
+        { "type": "invoke-function-instruction", "functionName": "}" },
+        { "type": "invoke-function-instruction", "functionName": "-" },
+        { "type": "invoke-function-instruction", "functionName": "exit", "label": "end" },
+      ])
+    );
+
+    assert_eq!(
+      crate::asm::parse("1\n\"oops").unwrap_err(),
+      "line 2: unterminated string"
+    );
+    assert!(crate::asm::parse("a: b: nop").is_err());
+    assert!(crate::asm::parse("nop dangling:").is_err());
+    assert_eq!(crate::asm::parse("1e999999").unwrap_err(), "line 1: number 1e999999 is out of range");
+  }
+
+  #[test]
+  fn test_asm_parse_program_detects_format() {
+    let text = crate::asm::parse_program("1 2 plus").unwrap();
+    let json = crate::asm::parse_program(&serde_json::json!(text).to_string()).unwrap();
+    assert_eq!(text, json);
+    let fixture = fs::read_to_string("./src/tests/goto_by_label.json").unwrap();
+    assert_eq!(crate::asm::parse_program(&fixture).unwrap().len(), 7);
+
+    let mut vm = load_program(crate::asm::parse_program("\"a\" x: 1").unwrap());
+    assert_eq!(crate::asm::disassemble(&vm, 0..10), ">    0  \"a\"\nx:\n     1  1\n");
+    vm.run();
+    assert_eq!(vm.stack.len(), 2);
+  }
+
+  #[test]
+  fn test_asm_bytecode_round_trips() {
+    let instrs = crate::asm::parse("start: 1.5 \"hé\" x: concat\n_skipped").unwrap();
+    let bytecode = crate::asm::encode(&instrs).unwrap();
+    assert!(bytecode.starts_with(crate::asm::BYTECODE_MAGIC));
+    assert_eq!(crate::asm::parse_program_bytes(&bytecode).unwrap(), instrs);
+    assert_eq!(crate::asm::parse_program_bytes(b"1 2 plus").unwrap().len(), 3);
+
+    assert!(crate::asm::encode(&[serde_json::json!(1)]).is_err());
+    // unknown instruction types are kept, and skipped when loaded
+    let instrs = vec![
+      serde_json::json!({ "type": "push-number-instruction", "value": 1 }),
+      serde_json::json!({ "type": "nope", "label": "one" }),
+      serde_json::json!({}),
+      serde_json::json!({ "type": "invoke-function-instruction", "functionName": "plus", "label": "plus" }),
+    ];
+    let decoded = crate::asm::decode(&crate::asm::encode(&instrs).unwrap()).unwrap();
+    assert_eq!(decoded[1], serde_json::json!({ "type": "nope", "label": "one" }));
+    let (original, round_tripped) = (load_program(instrs), load_program(decoded));
+    assert_eq!(crate::asm::disassemble(&round_tripped, 0..2), crate::asm::disassemble(&original, 0..2));
+    assert_eq!(round_tripped.labels, original.labels);
+    assert_eq!(round_tripped.labels["one"], 0);
+    assert_eq!(
+      crate::asm::decode(&bytecode[..bytecode.len() - 1]).unwrap_err(),
+      "bytecode ends in the middle of an instruction"
+    );
+    assert!(crate::asm::decode(b"TZO\x01\x09").is_err());
+    assert!(crate::asm::parse_program_bytes(&[0xff, 0xfe]).is_err());
+  }
+
+  #[test]
+  fn test_goto_huge_number_ends_the_program() {
+    let mut vm = load_program(crate::asm::parse("1e30 goto 1").unwrap());
+    vm.run();
+    assert!(!vm.exited);
+    assert_eq!(vm.pc, 3);
+    assert!(vm.stack.is_empty());
+  }
+
+  #[test]
+  fn test_repl_keeps_state_between_lines() {
+    let mut repl = crate::repl::Repl::new();
+    assert_eq!(repl.eval("1 2 plus").unwrap(), "stack: [3.0]\ncontext: {}");
+    assert_eq!(
+      repl.eval("\"hp\" setContext \"x\"").unwrap(),
+      "stack: [\"x\"]\ncontext: {\"hp\":3.0}"
+    );
+    assert_eq!(repl.eval(":ctx").unwrap(), "{\"hp\":3.0}");
+    assert_eq!(repl.eval("pause 4").unwrap(), "(paused)\nstack: [\"x\"]\ncontext: {\"hp\":3.0}");
+    assert_eq!(repl.eval(":step").unwrap(), "stack: [\"x\",4.0]\ncontext: {\"hp\":3.0}");
+    assert!(repl.eval(":dis").unwrap().ends_with("     6  pause\n     7  4"));
+    assert_eq!(repl.eval(":reset").unwrap(), "reset");
+    assert_eq!(repl.eval(":stack").unwrap(), "[]");
+    assert!(repl.eval(":quit").is_none());
+  }
+
+  #[test]
+  fn test_repl_recovers_from_errors() {
+    let mut repl = crate::repl::Repl::new();
+    assert_eq!(repl.eval("1 nosuchfunction").unwrap(), "error: Function not found: nosuchfunction");
+    assert_eq!(repl.vm.programlist.len(), 0);
+    assert_eq!(
+      repl.eval("\"a\" 1 plus 5").unwrap(),
+      "error at pc 2: +: operands must be numbers\nstack: []\ncontext: {}"
+    );
+    assert_eq!(repl.eval("6").unwrap(), "stack: [6.0]\ncontext: {}");
+    assert!(repl.eval("exit").unwrap().starts_with("(exited)"));
+    assert!(repl.eval("7").unwrap().starts_with("error: the program has exited"));
+  }
+
+  fn debugger_for(source: &str) -> crate::debugger::Debugger {
+    crate::debugger::Debugger::new(load_program(crate::asm::parse(source).unwrap()))
+  }
+
+  #[test]
+  fn test_debugger_breakpoints() {
+    use crate::debugger::{Condition, Location, StopReason};
+    let mut dbg = debugger_for("0 loop: 1 plus dup 3 gt jgz exit \"loop\" goto");
+    let by_fn = dbg.add_breakpoint(Location::Function("plus".to_string()), None);
+    assert_eq!(dbg.cont(), StopReason::Breakpoint(by_fn));
+    assert_eq!(dbg.vm.pc, 2);
+    assert!(dbg.remove(by_fn));
+
+    let by_label = dbg.add_breakpoint(
+      Location::Label("loop".to_string()),
+      Some(Condition::parse("top == 2").unwrap()),
+    );
+    assert_eq!(dbg.cont(), StopReason::Breakpoint(by_label));
+    assert_eq!(dbg.vm.stack, vec![crate::vm::Value::Number(2.0)]);
+    dbg.remove(by_label);
+
+    let by_pc = dbg.add_breakpoint(Location::Pc(7), None);
+    assert_eq!(dbg.cont(), StopReason::Breakpoint(by_pc));
+    assert_eq!(dbg.step(), StopReason::Exited);
+    assert_eq!(dbg.cont(), StopReason::Exited);
+  }
+
+  #[test]
+  fn test_debugger_watchpoints() {
+    use crate::debugger::{Condition, StopReason, Watchpoint};
+    use crate::vm::Value;
+    let mut dbg = debugger_for("5 \"hp\" setContext 1 2 3 \"hp\" delContext");
+    let on_hp = dbg.add_watchpoint(Watchpoint::Context("hp".to_string()));
+    let on_depth = dbg.add_watchpoint(Watchpoint::Condition(Condition::parse("depth >= 3").unwrap()));
+    assert_eq!(
+      dbg.cont(),
+      StopReason::Watchpoint { id: on_hp, old: None, new: Some(Value::Number(5.0)) }
+    );
+    assert_eq!(dbg.cont(), StopReason::Watchpoint { id: on_depth, old: None, new: None });
+    assert_eq!(dbg.vm.stack.len(), 3);
+    assert_eq!(
+      dbg.cont(),
+      StopReason::Watchpoint { id: on_hp, old: Some(Value::Number(5.0)), new: None }
+    );
+    assert_eq!(dbg.cont(), StopReason::Finished);
+  }
+
+  #[test]
+  fn test_debugger_step_over_runs_brace_blocks() {
+    use crate::debugger::StopReason;
+    let mut dbg = debugger_for("0 jz { 1 2 3 } 4 1 jz { 5 } 6 \"x\" 1 plus");
+    dbg.step();
+    assert_eq!(dbg.step_over(), StopReason::Step);
+    assert_eq!(dbg.vm.pc, 7);
+    assert_eq!(dbg.vm.stack.len(), 3);
+    // the second block is skipped by `{` itself
+    dbg.step();
+    dbg.step();
+    assert_eq!(dbg.step_over(), StopReason::Step);
+    assert_eq!(dbg.vm.pc, 13);
+    match dbg.cont() {
+      StopReason::Error(e) => assert_eq!(e, "+: operands must be numbers"),
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_debugger_commands() {
+    let mut dbg = debugger_for("1 \"a\" setContext 2 3 plus");
+    assert_eq!(dbg.command("b plus").unwrap(), "breakpoint 1 at Function(\"plus\")");
+    assert_eq!(
+      dbg.command("c").unwrap(),
+      "breakpoint 1 hit\n     2  setContext\n     3  2\n     4  3\n>    5  plus\nstack: [2.0,3.0]"
+    );
+    assert_eq!(dbg.command("ctx").unwrap(), "{\"a\":1.0}");
+    assert_eq!(dbg.command("s").unwrap(), "     3  2\n     4  3\n     5  plus\nstack: [5.0]");
+    assert_eq!(dbg.command("c").unwrap(), "program finished");
+    assert!(dbg.command("b 3 if nonsense").unwrap().starts_with("error:"));
+    assert!(dbg.command("d 9").unwrap().starts_with("error:"));
+    assert!(dbg.command("q").is_none());
+  }
+
+  fn dap_program(name: &str, source: &str) -> String {
+    let path = std::env::temp_dir().join(format!("tzo-dap-{}-{}.tzo", std::process::id(), name));
+    fs::write(&path, source).unwrap();
+    path.to_string_lossy().into_owned()
+  }
+
+  fn dap_requests(requests: Vec<(&str, serde_json::Value)>) -> Vec<u8> {
+    let mut input = vec![];
+    for (seq, (command, arguments)) in requests.into_iter().enumerate() {
+      let request = serde_json::json!({
+        "seq": seq + 1, "type": "request", "command": command, "arguments": arguments
+      });
+      crate::dap::write_message(&mut input, &request).unwrap();
+    }
+    input
+  }
+
+  fn dap_messages(output: Vec<u8>) -> Vec<serde_json::Value> {
+    let mut output = std::io::Cursor::new(output);
+    let mut messages = vec![];
+    while let Some(message) = crate::dap::read_message(&mut output).unwrap() {
+      messages.push(message);
+    }
+    messages
+  }
+
+  // "response <command>" or "event <event>" for each message.
+  fn dap_kinds(messages: &[serde_json::Value]) -> Vec<String> {
+    messages
+      .iter()
+      .map(|m| match m["type"].as_str().unwrap() {
+        "response" => format!("response {}", m["command"].as_str().unwrap()),
+        _ => format!("event {}", m["event"].as_str().unwrap()),
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_dap_session() {
+    use serde_json::json;
+    let program = dap_program("session", "\"hi\" stdout 1 \"a\" setContext\nloop: 2 3 plus\n");
+    let input = dap_requests(vec![
+      ("initialize", json!({ "adapterID": "tzo" })),
+      ("launch", json!({ "program": program })),
+      ("setBreakpoints", json!({ "source": { "sourceReference": 1 }, "breakpoints": [{ "line": 7 }, { "line": 50 }] })),
+      ("setFunctionBreakpoints", json!({ "breakpoints": [{ "name": "loop" }] })),
+      ("configurationDone", json!({})),
+      ("stackTrace", json!({ "threadId": 1 })),
+      ("variables", json!({ "variablesReference": 2 })),
+      ("continue", json!({ "threadId": 1 })),
+      ("variables", json!({ "variablesReference": 1 })),
+      ("source", json!({ "sourceReference": 1 })),
+      ("next", json!({ "threadId": 1 })),
+      ("variables", json!({ "variablesReference": 1 })),
+      ("continue", json!({ "threadId": 1 })),
+      ("disconnect", json!({})),
+    ]);
+    let messages = dap_messages(crate::dap::serve(std::io::Cursor::new(input), vec![]));
+    fs::remove_file(&program).unwrap();
+    assert_eq!(
+      dap_kinds(&messages),
+      vec![
+        "response initialize", "event initialized", "response launch", "response setBreakpoints",
+        "response setFunctionBreakpoints", "response configurationDone", "event output", "event stopped",
+        "response stackTrace", "response variables", "response continue", "event stopped",
+        "response variables", "response source", "response next", "event stopped", "response variables",
+        "response continue", "event exited", "event terminated", "response disconnect",
+      ]
+    );
+    assert!(messages.iter().all(|m| m["type"] != "response" || m["success"] == true));
+
+    let breakpoints = &messages[3]["body"]["breakpoints"];
+    assert_eq!(breakpoints[0]["line"], 7);
+    assert_eq!(breakpoints[0]["verified"], true);
+    assert_eq!(breakpoints[1]["verified"], false);
+    assert_eq!(messages[4]["body"]["breakpoints"][0]["line"], 6);
+    assert_eq!(messages[6]["body"]["output"], "hi");
+    let label_breakpoint = messages[4]["body"]["breakpoints"][0]["id"].clone();
+    assert_eq!(messages[7]["body"]["reason"], "breakpoint");
+    assert_eq!(messages[7]["body"]["hitBreakpointIds"], json!([label_breakpoint]));
+    let frame = &messages[8]["body"]["stackFrames"][0];
+    assert_eq!(frame["name"], "loop");
+    assert_eq!(frame["line"], 6);
+    assert_eq!(frame["instructionPointerReference"], "5");
+    assert_eq!(messages[9]["body"]["variables"][0]["name"], "a");
+    assert_eq!(messages[9]["body"]["variables"][0]["value"], "1.0");
+    assert_eq!(messages[11]["body"]["hitBreakpointIds"], json!([breakpoints[0]["id"]]));
+    assert_eq!(messages[12]["body"]["variables"].as_array().unwrap().len(), 1);
+    assert_eq!(
+      messages[13]["body"]["content"],
+      "\"hi\"\nstdout\n1\n\"a\"\nsetContext\nloop: 2\n3\nplus\n"
+    );
+    assert_eq!(messages[15]["body"]["reason"], "step");
+    let stack = &messages[16]["body"]["variables"];
+    assert_eq!(stack[0]["name"], "0");
+    assert_eq!(stack[0]["value"], "3.0");
+    assert_eq!(stack[1]["value"], "2.0");
+  }
+
+  #[test]
+  fn test_dap_pause_and_errors() {
+    use serde_json::json;
+    let request = |seq: i64, command: &str, arguments: serde_json::Value| {
+      json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })
+    };
+    let program = dap_program("pause", "loop: 1 pop \"loop\" goto");
+    let mut server = crate::dap::Server::new(vec![]);
+    server.handle(&request(1, "continue", json!({})));
+    server.handle(&request(2, "launch", json!({ "program": program })));
+    server.handle(&request(3, "configurationDone", json!({})));
+    assert!(server.is_running());
+    server.poll();
+    server.poll();
+    assert!(server.is_running());
+    server.handle(&request(4, "next", json!({})));
+    server.handle(&request(5, "pause", json!({})));
+    assert!(!server.is_running());
+    server.handle(&request(6, "stepOut", json!({})));
+    server.handle(&request(7, "evaluate", json!({})));
+    fs::write(&program, "1 \"x\" plus").unwrap();
+    server.handle(&request(8, "launch", json!({ "program": program })));
+    server.handle(&request(9, "configurationDone", json!({})));
+    server.poll();
+    server.handle(&request(10, "launch", json!({ "program": "/nonexistent/tzo/program" })));
+    fs::remove_file(&program).unwrap();
+
+    let messages = dap_messages(server.into_inner());
+    assert_eq!(
+      dap_kinds(&messages),
+      vec![
+        "response continue", "response launch", "response configurationDone", "response next",
+        "response pause", "event stopped", "response stepOut", "response evaluate", "response launch",
+        "response configurationDone", "event stopped", "response launch",
+      ]
+    );
+    assert_eq!(messages[0]["success"], false);
+    assert_eq!(messages[0]["message"], "no program launched");
+    assert_eq!(messages[3]["success"], false);
+    assert_eq!(messages[3]["message"], "the program is running");
+    assert_eq!(messages[5]["body"]["reason"], "pause");
+    assert_eq!(messages[6]["success"], false);
+    assert_eq!(messages[7]["message"], "unsupported request evaluate");
+    assert_eq!(messages[10]["body"]["reason"], "exception");
+    assert_eq!(messages[10]["body"]["text"], "+: operands must be numbers");
+    assert_eq!(messages[11]["success"], false);
+  }
+
+  #[test]
+  fn test_hooks() {
+    use crate::trace::Hooks;
+    use crate::vm::{ForeignFunc, Value, VmError};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+    impl Hooks for Recorder {
+      fn before_instruction(&mut self, vm: &VM, pc: usize) {
+        assert_eq!(vm.pc, pc);
+        self.0.borrow_mut().push(format!("before {}", pc));
+      }
+      fn after_instruction(&mut self, _vm: &VM, pc: usize) {
+        self.0.borrow_mut().push(format!("after {}", pc));
+      }
+      fn foreign_call(&mut self, _vm: &VM, pc: usize, name: &str) {
+        self.0.borrow_mut().push(format!("foreign {} {}", pc, name));
+      }
+      fn context_write(&mut self, vm: &VM, key: &str, old: Option<&Value>, new: Option<&Value>) {
+        assert_eq!(vm.context.get(key).as_ref(), new);
+        self.0.borrow_mut().push(format!("context {} {:?} {:?}", key, old, new));
+      }
+      fn jump(&mut self, _vm: &VM, from: usize, to: usize) {
+        self.0.borrow_mut().push(format!("jump {} {}", from, to));
+      }
+    }
+    fn double(vm: &mut VM) -> Result<(), VmError> {
+      let n = vm.stack.pop().unwrap().as_number();
+      vm.put_f64(n * 2.0);
+      Ok(())
+    }
+
+    let mut vm = VM::new();
+    vm.register_foreign_function(ForeignFunc { func: Rc::new(double), name: "double".to_string(), ..Default::default() });
+    vm.load(crate::asm::parse("2 double \"a\" setContext \"a\" delContext 0 jz 7 \"end\" goto 8 end: 9").unwrap());
+    let events = Rc::new(RefCell::new(vec![]));
+    vm.hooks.push(Box::new(Recorder(events.clone())));
+    vm.run();
+    assert_eq!(
+      *events.borrow(),
+      vec![
+        "before 0", "after 0",
+        "before 1", "foreign 1 double", "after 1",
+        "before 2", "after 2",
+        "before 3", "context a None Some(Number(4.0))", "after 3",
+        "before 4", "after 4",
+        "before 5", "context a Some(Number(4.0)) None", "after 5",
+        "before 6", "after 6",
+        "before 7", "jump 7 9", "after 7",
+        "before 9", "after 9",
+        "before 10", "jump 10 12", "after 10",
+        "before 12", "after 12",
+      ]
+    );
+    assert_eq!(vm.stack, vec![Value::Number(9.0)]);
+  }
+
+  #[test]
+  fn test_json_tracer() {
+    let out = crate::vm::SharedBuffer::default();
+    let mut vm = load_program(crate::asm::parse("1 \"a\" setContext 0 jz 5 \"b\" stdout").unwrap());
+    vm.stdout = Box::new(crate::vm::SharedBuffer::default());
+    vm.hooks.push(Box::new(crate::trace::JsonTracer::new(out.clone())));
+    vm.run();
+    let lines: Vec<serde_json::Value> =
+      out.take_string().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(lines.len(), 7);
+    assert_eq!(
+      lines[2],
+      serde_json::json!({ "pc": 2, "instr": "setContext", "before": [1.0, "a"], "after": [], "context": { "a": 1.0 } })
+    );
+    assert_eq!(lines[4], serde_json::json!({ "pc": 4, "instr": "jz", "before": [0.0], "after": [], "jump": 6 }));
+    assert_eq!(lines[5]["instr"], "\"b\"");
+    assert_eq!(lines[6]["after"], serde_json::json!([]));
+  }
+
+  #[test]
+  fn test_profiler() {
+    use crate::profile::Profiler;
+    let mut vm = load_program(
+      crate::asm::parse("3 \"n\" setContext loop: \"n\" getContext -1 plus dup \"n\" setContext jz { \"end\" goto } \"loop\" goto end: 0 pop")
+        .unwrap(),
+    );
+    let profile = Profiler::attach(&mut vm);
+    vm.run();
+    let profile = profile.borrow();
+    assert_eq!(profile.pcs[&0].count, 1);
+    assert_eq!(profile.pcs[&3].count, 3);
+    assert_eq!(profile.pcs[&11].count, 2);
+    assert_eq!(profile.pcs[&12].count, 1);
+    assert!(!profile.pcs.contains_key(&14));
+
+    let counts = |rows: Vec<(String, crate::profile::Stats)>| {
+      let mut rows: Vec<(String, u64)> = rows.into_iter().map(|(k, s)| (k, s.count)).collect();
+      rows.sort();
+      rows
+    };
+    assert_eq!(
+      counts(profile.by_label(&vm)),
+      vec![("end".to_string(), 2), ("loop".to_string(), 32), ("main".to_string(), 3)]
+    );
+    let functions = counts(profile.by_function(&vm));
+    assert!(functions.contains(&("setContext".to_string(), 4)));
+    assert!(functions.contains(&("goto".to_string(), 3)));
+    assert!(!functions.iter().any(|(f, _)| f == "push"));
+
+    let folded = profile.folded(&vm);
+    let mut stacks: Vec<&str> = folded.lines().map(|l| l.rsplit_once(' ').unwrap().0).collect();
+    stacks.sort();
+    assert_eq!(
+      stacks,
+      vec![
+        "end;pop", "end;push", "loop;dup", "loop;getContext", "loop;goto", "loop;jz", "loop;plus",
+        "loop;push", "loop;setContext", "loop;{", "main;push", "main;setContext",
+      ]
+    );
+    let report = profile.report(&vm);
+    assert!(report.starts_with("pc "));
+    assert!(report.contains("\nlabel "));
+    assert!(report.contains("\nfunction "));
+  }
+
+  #[test]
+  fn test_coverage() {
+    use crate::coverage::{Coverage, CoverageRecorder};
+    use crate::vm::Value;
+    let source = "\"n\" getContext\nloop: dup jz { pop \"done\" goto }\n  -1 plus \"loop\" goto\ndone: \"end\"\n";
+    let lines = crate::asm::source_lines(source).unwrap();
+    assert_eq!(lines, vec![1, 1, 2, 2, 2, 2, 2, 2, 2, 3, 3, 3, 3, 4]);
+    assert_eq!(crate::asm::source_lines("[]"), None);
+    let run = |n: f64| {
+      let mut vm = load_program(crate::asm::parse(source).unwrap());
+      vm.context.set("n", Value::Number(n)).unwrap();
+      let coverage = CoverageRecorder::attach(&mut vm);
+      vm.run();
+      let coverage = coverage.borrow().clone();
+      (vm, coverage)
+    };
+
+    let (vm, mut coverage) = run(0.0);
+    assert_eq!(coverage.executed.keys().copied().collect::<Vec<usize>>(), vec![0, 1, 2, 3, 5, 6, 7, 13]);
+    assert_eq!(coverage.uncovered(&vm), vec![4..5, 8..13]);
+    assert_eq!(
+      coverage.report(&vm, Some(&lines)),
+      "instructions: 8/14 (57.1%), branch outcomes: 1/2 (50.0%)
+uncovered 4 (loop+2, line 2)
+uncovered 8-12 (loop+6 to loop+10, lines 2-3)
+branch 3 jz (loop+1, line 2): never fell through
+"
+    );
+    assert_eq!(
+      coverage.report(&vm, None).lines().nth(1).unwrap(),
+      "uncovered 4 (loop+2)"
+    );
+
+    let (_, other) = run(1.0);
+    coverage.merge(&other);
+    assert_eq!(coverage.executed[&3], 3);
+    assert_eq!(coverage.branches[&3].skipped, 2);
+    assert_eq!(coverage.branches[&3].fell_through, 1);
+    assert_eq!(
+      coverage.report(&vm, Some(&lines)),
+      "instructions: 13/14 (92.9%), branch outcomes: 2/2 (100.0%)\nuncovered 8 (loop+6, line 2)\n"
+    );
+    assert_eq!(Coverage::from_json(&coverage.to_json()).unwrap(), coverage);
+    assert!(Coverage::from_json(&serde_json::json!({ "executed": { "x": 1 }, "branches": {} })).is_err());
+  }
+
+  fn generated_fixtures(count: u64) -> Vec<serde_json::Value> {
+    use rand::SeedableRng;
+    (0..count)
+      .map(|seed| {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        serde_json::json!({
+          "input_program": crate::generate::program(&mut rng, 40, false),
+          "initial_context": { "a": 1, "b": "x" },
+        })
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_generated_programs_terminate() {
+    for fixture in generated_fixtures(300) {
+      let mut vm = crate::conformance::load(&fixture).unwrap();
+      // every jump goes forward, so no instruction runs twice
+      let mut executed = std::collections::HashSet::new();
+      vm.running = true;
+      while vm.running && vm.pc < vm.programlist.len() {
+        assert!(executed.insert(vm.pc), "pc {} ran twice in {}", vm.pc, fixture);
+        if vm.step().is_err() {
+          break;
+        }
+      }
+    }
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn test_differential_diff() {
+    use crate::differential::{compare, Reference};
+    // stands in for a reference implementation that always leaves [1] on the stack
+    let reference = Reference {
+      program: "sh".to_string(),
+      args: vec![
+        "-c".to_string(),
+        "cat > /dev/null; echo '{\"stack\": [1], \"context\": {\"a\": 1}, \"stdout\": \"1\"}'".to_string(),
+      ],
+    };
+    let fixture = |program: &str| {
+      serde_json::json!({ "input_program": crate::asm::parse(program).unwrap(), "initial_context": { "a": 1 } })
+    };
+    assert_eq!(compare(&reference, &fixture("1 dup stdout")), Ok(vec![]));
+    assert_eq!(
+      compare(&reference, &fixture("2 1 stdout \"b\" delContext")),
+      Ok(vec!["stack: here [2.0], reference [1.0]".to_string()])
+    );
+    assert_eq!(
+      compare(&reference, &fixture("1 \"1\" stdout \"a\" plus")),
+      Ok(vec!["error: here true, reference false".to_string()])
+    );
+    let broken = Reference { program: "sh".to_string(), args: vec!["-c".to_string(), "exit 3".to_string()] };
+    assert!(compare(&broken, &fixture("1")).unwrap_err().starts_with("reference failed"));
+  }
+
+  #[test]
+  fn test_differential_against_reference() {
+    let reference = match crate::differential::Reference::from_env() {
+      Some(reference) => reference,
+      None => {
+        println!("skipped: set TZO_REFERENCE to a node script running the reference implementation");
+        return;
+      }
+    };
+    let mut fixtures: Vec<(String, serde_json::Value)> = crate::conformance::FIXTURES
+      .iter()
+      .map(|(name, contents)| (name.to_string(), serde_json::from_str(contents).unwrap()))
+      .collect();
+    for (seed, fixture) in generated_fixtures(100).into_iter().enumerate() {
+      fixtures.push((format!("generated {}", seed), fixture));
+    }
+    let mut failures = vec![];
+    for (name, fixture) in fixtures {
+      match crate::differential::compare(&reference, &fixture) {
+        Ok(diffs) if diffs.is_empty() => {}
+        Ok(diffs) => failures.push(format!("{}: {}", name, diffs.join("; "))),
+        Err(e) => failures.push(format!("{}: {}", name, e)),
+      }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+  }
+
+  fn generated_vm(seed: u64) -> (crate::vm::VM, crate::vm::SharedBuffer, Vec<serde_json::Value>) {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    let program = crate::generate::program(&mut rng, 60, true);
+    let mut vm = crate::vm::VM::new();
+    vm.try_load(program.clone()).unwrap();
+    vm.context.set("a", crate::vm::Value::Number(1.0)).unwrap();
+    vm.seed(seed);
+    let stdout = crate::vm::SharedBuffer::default();
+    vm.stdout = Box::new(stdout.clone());
+    (vm, stdout, program)
+  }
+
+  #[test]
+  fn test_property_run_only_returns_errors() {
+    use crate::vm::VmError;
+    for seed in 0..300 {
+      let (mut vm, _, program) = generated_vm(seed);
+      let len = vm.programlist.len();
+      let failure = format!("seed {}: {}", seed, serde_json::json!(program));
+      // try_run catches no panics, so any instruction that panics fails this
+      match vm.try_run() {
+        Ok(_) => assert!(vm.pc >= len || !vm.running, "{}", failure),
+        Err(VmError::Runtime { pc, .. }) => assert!(pc == vm.pc && pc < len, "{}", failure),
+        Err(e) => panic!("{}: {:?}", failure, e),
+      }
+      if vm.exited {
+        assert!(vm.try_run().is_err(), "{}", failure);
+      }
+    }
+  }
+
+  #[test]
+  fn test_property_snapshot_restore() {
+    use rand::{RngExt, SeedableRng};
+    for seed in 0..300 {
+      let (mut vm, stdout, program) = generated_vm(seed);
+      let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+      let steps = rng.random_range(0..vm.programlist.len());
+      let failure = format!("seed {}, {} steps: {}", seed, steps, serde_json::json!(program));
+      vm.running = true;
+      for _ in 0..steps {
+        if !vm.running || vm.pc >= vm.programlist.len() || vm.step().is_err() {
+          break;
+        }
+      }
+      let snapshot = vm.snapshot();
+      stdout.take();
+      let finish = |vm: &mut crate::vm::VM| {
+        let result = vm.try_run();
+        (result, vm.pc, vm.stack.clone(), vm.context.to_map(), vm.exited, stdout.take_string())
+      };
+      let first = finish(&mut vm);
+      vm.restore(&snapshot).unwrap();
+      assert_eq!(first, finish(&mut vm), "{}", failure);
+    }
+  }
+
+  #[test]
+  fn test_property_load_never_panics() {
+    use rand::seq::IndexedRandom;
+    use rand::{RngExt, SeedableRng};
+    let values = [
+      serde_json::json!(null),
+      serde_json::json!(1.5),
+      serde_json::json!("a"),
+      serde_json::json!("_x"),
+      serde_json::json!("{"),
+      serde_json::json!("}"),
+      serde_json::json!("plus"),
+      serde_json::json!("nosuchfunction"),
+      serde_json::json!([1]),
+      serde_json::json!({ "x": 1 }),
+      serde_json::json!("push-number-instruction"),
+      serde_json::json!("push-string-instruction"),
+      serde_json::json!("invoke-function-instruction"),
+    ];
+    for seed in 0..1000 {
+      let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+      let instructions: Vec<serde_json::Value> = (0..rng.random_range(0..6))
+        .map(|_| {
+          if rng.random_bool(0.1) {
+            return values.choose(&mut rng).unwrap().clone();
+          }
+          let mut instruction = serde_json::json!({});
+          for key in ["type", "value", "functionName", "label"] {
+            if rng.random_bool(0.7) {
+              instruction[key] = values.choose(&mut rng).unwrap().clone();
+            }
+          }
+          instruction
+        })
+        .collect();
+      let new_vm = || {
+        let mut vm = crate::vm::VM::new();
+        vm.load(vec![serde_json::json!({ "type": "push-number-instruction", "value": 1 })]);
+        vm
+      };
+      let mut vm = new_vm();
+      let result = vm.try_load(instructions.clone());
+      let failure = format!("seed {}: {}", seed, serde_json::json!(instructions));
+      if result.is_err() {
+        assert_eq!(vm.programlist.len(), 1, "{}", failure);
+        assert_eq!(vm.labels.len(), 0, "{}", failure);
+      }
+      let mut fresh = new_vm();
+      assert_eq!(crate::vm::catch_panic(|| fresh.load(instructions)).is_ok(), result.is_ok(), "{}", failure);
+    }
+  }
+
+  #[test]
+  fn test_yielding_foreign_functions() {
+    use crate::vm::{catch_panic, ForeignFunc, Status, Ticket, Value};
+    use std::future::Future;
+    use std::rc::Rc;
+    use std::task::{Context, Poll, Waker};
+    let mut vm = VM::new();
+    vm.register_foreign_function(ForeignFunc {
+      func: Rc::new(|vm: &mut VM| {
+        vm.yield_pending()?;
+        Ok(())
+      }),
+      name: "lookup".to_string(),
+      ..Default::default()
+    });
+    vm.register_foreign_function(ForeignFunc {
+      func: Rc::new(|vm: &mut VM| {
+        let key = vm.stack.pop().unwrap().as_string();
+        let mut polls = 0;
+        // ready on the second poll, like work finishing in the background
+        vm.await_future(std::future::poll_fn(move |_| {
+          polls += 1;
+          if polls < 2 {
+            Poll::Pending
+          } else {
+            Poll::Ready(vec![Value::String(format!("{}!", key))])
+          }
+        }))?;
+        Ok(())
+      }),
+      name: "fetch".to_string(),
+      ..Default::default()
+    });
+    vm.load(crate::asm::parse("1 lookup 2 plus").unwrap());
+    let ticket = match vm.run() {
+      Status::Yielded(ticket) => ticket,
+      status => panic!("{:?}", status),
+    };
+    assert_eq!(vm.stack, vec![Value::Number(1.0)]);
+    // running again keeps waiting
+    assert_eq!(vm.run(), Status::Yielded(ticket));
+    assert!(catch_panic(|| vm.complete(Ticket(ticket.0 + 1), vec![])).is_err());
+    assert_eq!(vm.complete(ticket, vec![Value::Number(40.0)]), Status::Finished);
+    assert_eq!(vm.stack, vec![Value::Number(1.0), Value::Number(42.0)]);
+
+    vm.load(crate::asm::parse("\"a\" fetch \"b\" fetch concat pause lookup").unwrap());
+    let status = {
+      let mut run = std::pin::pin!(vm.run_async());
+      let mut cx = Context::from_waker(Waker::noop());
+      loop {
+        if let Poll::Ready(status) = run.as_mut().poll(&mut cx) {
+          break status;
+        }
+      }
+    };
+    assert_eq!(status, Status::Paused);
+    assert_eq!(vm.stack.last(), Some(&Value::String("b!a!".to_string())));
+    let ticket = match vm.run() {
+      Status::Yielded(ticket) => ticket,
+      status => panic!("{:?}", status),
+    };
+    assert!(vm.futures.is_empty());
+    assert_eq!(vm.complete(ticket, vec![]), Status::Finished);
+
+    // restoring a snapshot from before a yield drops the future it waits for
+    vm.load(crate::asm::parse("\"c\" fetch").unwrap());
+    let before = vm.snapshot();
+    let ticket = match vm.run() {
+      Status::Yielded(ticket) => ticket,
+      status => panic!("{:?}", status),
+    };
+    assert!(vm.futures.contains_key(&ticket));
+    vm.restore(&before).unwrap();
+    assert!(vm.futures.is_empty());
+    assert!(matches!(vm.run(), Status::Yielded(again) if again != ticket));
+    assert_eq!(vm.futures.len(), 1);
+  }
+
+  #[test]
+  fn test_register_typed_functions() {
+    use crate::vm::{catch_panic, StackSignature, Value, ValueType};
+    let mut vm = VM::new();
+    vm.register("dist", |x: f64, y: f64| (x * x + y * y).sqrt());
+    vm.register("divmod", |a: i64, b: i64| (a / b, a % b));
+    vm.register("find", |s: String, c: String| s.find(&c).map(|i| i as i64));
+    vm.register("shout", |s: String, loud: bool| if loud { s.to_uppercase() } else { s });
+    vm.register("count", |v: Value| v.as_string().len() as f64);
+    vm.register("noop", || {});
+    assert_eq!(
+      vm.foreign_functions.get("dist").unwrap().signature,
+      Some(StackSignature { inputs: vec![ValueType::Number, ValueType::Number], outputs: vec![ValueType::Number] })
+    );
+    assert_eq!(
+      vm.foreign_functions.get("divmod").unwrap().signature.as_ref().unwrap().outputs,
+      vec![ValueType::Number, ValueType::Number]
+    );
+    assert_eq!(vm.foreign_functions.get("find").unwrap().signature, None);
+    assert_eq!(
+      vm.foreign_functions.get("shout").unwrap().signature.as_ref().unwrap().inputs,
+      vec![ValueType::Number, ValueType::String]
+    );
+
+    vm.load(crate::asm::parse("3 4 dist 7 2 divmod \"hello\" \"l\" find \"hello\" \"z\" find \"hi\" 1 shout \"abc\" count noop").unwrap());
+    vm.run();
+    assert_eq!(
+      vm.stack,
+      vec![
+        Value::Number(5.0),
+        Value::Number(3.0),
+        Value::Number(1.0),
+        Value::Number(2.0),
+        Value::String("HI".to_string()),
+        Value::Number(3.0),
+      ]
+    );
+
+    let error = |program: &str| {
+      let mut vm = VM::new();
+      vm.register("dist", |x: f64, y: f64| (x * x + y * y).sqrt());
+      vm.register("divmod", |a: i64, b: i64| (a / b, a % b));
+      vm.load(crate::asm::parse(program).unwrap());
+      let error = catch_panic(|| vm.run()).unwrap_err();
+      (error, vm.stack.len())
+    };
+    assert_eq!(error("3 dist"), ("dist: expected 2 arguments, got 1".to_string(), 1));
+    assert_eq!(error("3 \"4\" dist"), ("dist: argument 2 must be a number, got string \"4\"".to_string(), 2));
+    assert_eq!(error("1.5 2 divmod"), ("divmod: argument 1 must be an integer, got number 1.5".to_string(), 2));
+    assert!(error("1e19 2 divmod").0.starts_with("divmod: argument 1 must be an integer"));
+    assert!(error("-1e19 2 divmod").0.starts_with("divmod: argument 1 must be an integer"));
+  }
+
+  #[test]
+  fn test_modules() {
+    use crate::foreign::Module;
+    use crate::vm::{catch_panic, ForeignFunc, Value, VmError};
+    use std::rc::Rc;
+    let mut audio = Module::new("audio");
+    audio.register("volume", |v: f64| v / 10.0);
+    audio.register_foreign_function(ForeignFunc {
+      func: Rc::new(|vm: &mut VM| {
+        vm.put_string("beep".to_string());
+        Ok(())
+      }),
+      name: "play".to_string(),
+      ..Default::default()
+    });
+    let mut vm = VM::new();
+    vm.register_module(audio);
+    vm.load(crate::asm::parse("5 audio.volume audio.play").unwrap());
+    vm.run();
+    assert_eq!(vm.stack, vec![Value::Number(0.5), Value::String("beep".to_string())]);
+    assert!(catch_panic(|| vm.load(crate::asm::parse("play").unwrap())).is_err());
+
+    let mut error = |name: &str| match vm.try_register_foreign_function(ForeignFunc {
+      func: Rc::new(|_vm: &mut VM| Ok(())),
+      name: name.to_string(),
+      ..Default::default()
+    }) {
+      Err(VmError::Register { message, .. }) => message,
+      result => panic!("{:?}", result),
+    };
+    assert_eq!(error("pop"), "Cannot register pop: it is a builtin");
+    assert_eq!(error("{"), "Cannot register {: it is a builtin");
+    assert_eq!(error("audio.play"), "Cannot register audio.play: it is already registered");
+
+    // a module with a taken name registers nothing
+    let mut clash = Module::new("audio");
+    clash.register("stop", || {});
+    clash.register("play", || {});
+    assert!(vm.try_register_module(clash).is_err());
+    assert!(!vm.foreign_functions.contains("audio.stop"));
+  }
+
+  #[test]
+  fn test_stdlib_modules() {
+    use crate::vm::{catch_panic, Value};
+    let run = |program: &str| {
+      let mut vm = VM::new();
+      for name in crate::stdlib::MODULES {
+        vm.register_module(crate::stdlib::module(name).unwrap());
+      }
+      vm.load(crate::asm::parse(program).unwrap());
+      catch_panic(|| vm.run()).map(|_| vm.stack)
+    };
+    let n = |n: f64| Value::Number(n);
+    let s = |s: &str| Value::String(s.to_string());
+    assert_eq!(
+      run("2.5 math.floor 2 10 math.pow -7 3 math.mod 3 9 math.max").unwrap(),
+      vec![n(2.0), n(1024.0), n(2.0), n(9.0)]
+    );
+    assert_eq!(
+      run("\"héllo\" string.length \"héllo\" 1 3 string.slice \"héllo\" \"l\" string.indexOf \"x\" \"y\" string.indexOf").unwrap(),
+      vec![n(5.0), s("él"), n(2.0), n(-1.0)]
+    );
+    assert_eq!(
+      run("\" 12 \" string.toNumber 12.5 string.fromNumber \"ab\" 2 string.repeat \"abc\" \"a\" string.startsWith").unwrap(),
+      vec![n(12.0), s("12.5"), s("abab"), n(1.0)]
+    );
+    assert_eq!(
+      run("\"{}\" \"a.b\" 3 json.set dup \"a.b\" json.get \"[1]\" \"0\" json.has").unwrap(),
+      vec![s("{\"a\":{\"b\":3}}"), n(3.0), n(1.0)]
+    );
+    assert_eq!(run("\"x\" json.encode").unwrap(), vec![s("\"x\"")]);
+    assert_eq!(run("\"{}\" \"a\" json.get").unwrap_err(), "json.get: no number or string at a");
+    let now = run("time.now").unwrap()[0].as_number();
+    assert!(now > 1.6e12);
+    assert!(run(&format!("{} time.since", now)).unwrap()[0].as_number() >= 0.0);
+  }
+
+  #[test]
+  fn test_foreign_function_registry() {
+    use crate::vm::ForeignFunc;
+    let mut vm = VM::new();
+    vm.register_module(crate::stdlib::math());
+    vm.register("greet", |name: String| format!("hi {}", name))
+      .describe("Greets someone.")
+      .set_cost(5);
+    vm.register_foreign_function(ForeignFunc { name: "mystery".to_string(), ..Default::default() });
+
+    let pow = vm.foreign_functions.get("math.pow").unwrap();
+    assert_eq!(pow.arity(), Some(2));
+    assert!(pow.pure);
+    assert_eq!(pow.description, "The first number raised to the power of the second.");
+    let greet = vm.foreign_functions.get("greet").unwrap();
+    assert_eq!((greet.arity(), greet.pure, greet.cost), (Some(1), false, 5));
+    assert_eq!(vm.foreign_functions.get("mystery").unwrap().arity(), None);
+    assert!(vm.foreign_functions.get("pow").is_none());
+    assert_eq!(vm.foreign_functions.len(), 14);
+    assert_eq!(
+      vm.foreign_functions.complete("math.m").iter().map(|f| f.name.as_str()).collect::<Vec<_>>(),
+      vec!["math.max", "math.min", "math.mod"]
+    );
+
+    let docs = vm.foreign_functions.docs();
+    assert!(docs.starts_with("### greet\n\n`greet ( string -- string )` cost 5\n\nGreets someone.\n\n"));
+    assert!(docs.contains("### math.pi\n\n`math.pi ( -- number )` pure, cost 1\n\nThe number π.\n\n"));
+    assert!(docs.ends_with("### mystery\n\n`mystery ( ? )` cost 1\n\n"));
+  }
+
+  #[test]
+  fn test_policy() {
+    use crate::policy::Policy;
+    use crate::vm::{catch_panic, Value, VmError};
+    let load = |policy: Policy, program: &str| {
+      let mut vm = VM::new();
+      vm.register("audio.play", || "beep");
+      vm.register("audio.stop", || {});
+      vm.policy = policy;
+      match vm.try_load(crate::asm::parse(program).unwrap()) {
+        Ok(()) => Ok(vm),
+        Err(VmError::Load { index, message }) => Err((index, message)),
+        Err(e) => panic!("{:?}", e),
+      }
+    };
+    assert!(load(Policy::default(), "\"a\" stdout audio.play").is_ok());
+    assert_eq!(
+      load(Policy::default().deny("stdout"), "1 \"a\" stdout").err(),
+      Some((2, "stdout is not allowed".to_string()))
+    );
+    assert_eq!(
+      load(Policy::default().deny("audio.*"), "audio.stop").err(),
+      Some((0, "audio.stop is not allowed".to_string()))
+    );
+    let sandbox = || Policy::deny_all().allow("plus").allow("audio.*").deny("audio.stop");
+    assert!(load(sandbox(), "1 2 plus audio.play").is_ok());
+    assert!(load(sandbox(), "audio.stop").is_err());
+    assert!(load(sandbox(), "1 2 minus").is_err());
+
+    // calls are checked again when the policy changes after loading
+    let mut vm = load(Policy::default(), "audio.play audio.play").unwrap();
+    vm.step().unwrap();
+    vm.policy = Policy::default().deny("audio.play");
+    assert_eq!(catch_panic(|| vm.run()).unwrap_err(), "audio.play is not allowed");
+    assert_eq!((vm.pc, vm.stack.clone()), (1, vec![Value::String("beep".to_string())]));
+
+    let no_goto = || Policy::default().deny_goto_by_number();
+    assert_eq!(
+      load(no_goto(), "3 goto").err(),
+      Some((1, "goto by number is not allowed".to_string()))
+    );
+    assert!(load(no_goto(), "\"end\" goto end: 1").is_ok());
+    let mut vm = load(no_goto(), "1 2 plus goto").unwrap();
+    assert_eq!(catch_panic(|| vm.run()).unwrap_err(), "goto by number is not allowed");
+  }
+
+  #[test]
+  fn test_foreign_panics() {
+    use crate::vm::{catch_panic, ForeignFunc, Status, Value, VmError};
+    use std::rc::Rc;
+    let mut vm = VM::new();
+    vm.register("divide", |a: f64, b: f64| {
+      if b == 0.0 {
+        panic!("division by zero");
+      }
+      a / b
+    });
+    // pops, writes the context and jumps before failing, without a signature
+    vm.register_foreign_function(ForeignFunc {
+      func: Rc::new(|vm: &mut VM| {
+        vm.stack.pop();
+        vm.stack.pop();
+        vm.set_context("gold".to_string(), Value::Number(0.0))?;
+        vm.delete_context("name")?;
+        vm.yield_pending()?;
+        vm.pc = 0;
+        panic!("oops");
+      }),
+      name: "sloppy".to_string(),
+      ..Default::default()
+    });
+    vm.load(crate::asm::parse("1 6 0 divide 8 sloppy").unwrap());
+    let n = |n: f64| Value::Number(n);
+    vm.set_context("gold".to_string(), n(10.0)).unwrap();
+    vm.set_context("name".to_string(), Value::String("hero".to_string())).unwrap();
+    let context = vm.context.to_map();
+
+    let error = vm.try_run().unwrap_err();
+    assert_eq!(
+      error,
+      VmError::ForeignPanic { name: "divide".to_string(), message: "division by zero".to_string(), pc: 3 }
+    );
+    assert_eq!((vm.pc, vm.stack.clone()), (3, vec![n(1.0), n(6.0), n(0.0)]));
+    let snapshot = vm.snapshot();
+    vm.stack[2] = n(2.0);
+    assert_eq!(vm.step(), Ok(()));
+    assert_eq!(vm.stack, vec![n(1.0), n(3.0)]);
+    vm.restore(&snapshot).unwrap();
+    assert_eq!(vm.stack, vec![n(1.0), n(6.0), n(0.0)]);
+    vm.stack[2] = n(3.0);
+
+    assert_eq!(
+      vm.try_run().unwrap_err(),
+      VmError::ForeignPanic { name: "sloppy".to_string(), message: "oops".to_string(), pc: 5 }
+    );
+    assert_eq!((vm.pc, vm.stack.clone()), (5, vec![n(1.0), n(2.0), n(8.0)]));
+    assert_eq!(vm.context.to_map(), context);
+    assert_eq!(vm.status(), Status::Paused);
+    assert_eq!(catch_panic(|| vm.run()).unwrap_err(), "oops");
+
+    // typed functions returning an `Option` have no signature, and get their
+    // arguments back all the same
+    let mut vm = VM::new();
+    vm.register("maybe", |a: f64| -> Option<f64> { panic!("no {}", a) });
+    vm.load(crate::asm::parse("1 2 maybe").unwrap());
+    assert!(vm.foreign_functions.get("maybe").unwrap().signature.is_none());
+    assert!(matches!(vm.try_run(), Err(VmError::ForeignPanic { pc: 2, .. })));
+    assert_eq!(vm.stack, vec![n(1.0), n(2.0)]);
+
+    // builtins still fail as runtime errors
+    vm.load(crate::asm::parse("\"a\" 1 plus").unwrap());
+    vm.pc = 3;
+    assert!(matches!(vm.try_run(), Err(VmError::Runtime { pc: 5, .. })));
+  }
+
+  #[test]
+  fn test_context_observers() {
+    use crate::vm::Value;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    let mut vm = VM::new();
+    let changes = Rc::new(RefCell::new(vec![]));
+    let log = |tag: &'static str| {
+      let changes = changes.clone();
+      move |change: &crate::observe::ContextChange| {
+        changes.borrow_mut().push(format!("{} {} {:?} -> {:?}", tag, change.key, change.old, change.new))
+      }
+    };
+    vm.observe("gold", log("gold"));
+    let player = vm.observe("player.*", log("player"));
+    vm.set_context("gold".to_string(), Value::Number(5.0)).unwrap();
+    let gold = vm.bind("gold");
+    let health = vm.bind("player.health");
+    assert_eq!((gold.get(), health.get()), (Some(Value::Number(5.0)), None));
+
+    vm.load(crate::asm::parse(
+      "7 \"gold\" setContext 7 \"gold\" setContext 100 \"player.health\" setContext \"gold\" delContext \"gold\" delContext 1 \"other\" setContext"
+    ).unwrap());
+    vm.run();
+    assert_eq!(
+      *changes.borrow(),
+      vec![
+        "gold gold None -> Some(Number(5.0))",
+        "gold gold Some(Number(5.0)) -> Some(Number(7.0))",
+        "player player.health None -> Some(Number(100.0))",
+        "gold gold Some(Number(7.0)) -> None",
+      ]
+    );
+    assert_eq!((gold.get(), health.get()), (None, Some(Value::Number(100.0))));
+
+    vm.unobserve(player);
+    changes.borrow_mut().clear();
+    assert_eq!(vm.delete_context("player.health"), Ok(Some(Value::Number(100.0))));
+    assert!(changes.borrow().is_empty());
+    assert_eq!(health.get(), None);
+  }
+
+  #[test]
+  fn test_context_stores() {
+    use crate::context::{Access, ContextStore, FileStore, Layered, Recording};
+    use crate::vm::{Value, VmError};
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    let n = |n: f64| Value::Number(n);
+    let program = || crate::asm::parse("\"gold\" getContext 1 plus \"gold\" setContext \"name\" delContext").unwrap();
+
+    let mut defaults = HashMap::new();
+    defaults.insert("gold".to_string(), n(10.0));
+    defaults.insert("name".to_string(), Value::String("hero".to_string()));
+    let defaults: Rc<dyn ContextStore> = Rc::new(defaults);
+    let mut vm = VM::new();
+    vm.context = Box::new(Layered::new(defaults.clone()));
+    vm.load(program());
+    let snapshot = vm.snapshot();
+    vm.run();
+    assert_eq!(vm.context.to_map(), HashMap::from([("gold".to_string(), n(11.0))]));
+    assert_eq!(defaults.get("gold"), Some(n(10.0)));
+    assert!(defaults.has("name"));
+    vm.restore(&snapshot).unwrap();
+    assert_eq!(vm.context.to_map(), defaults.to_map());
+
+    let path = std::env::temp_dir().join(format!("tzo-context-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut vm = VM::new();
+    vm.context = Box::new(FileStore::open(&path).unwrap());
+    vm.set_context("gold".to_string(), n(1.0)).unwrap();
+    vm.load(program());
+    vm.run();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\n  \"gold\": 2.0\n}\n");
+    assert_eq!(FileStore::open(&path).unwrap().get("gold"), Some(n(2.0)));
+    std::fs::write(&path, "[1]").unwrap();
+    assert!(FileStore::open(&path).unwrap_err().ends_with("not a JSON object"));
+    std::fs::remove_file(&path).unwrap();
+    // a file that can't be written fails the instruction, keeping the old value
+    let mut vm = VM::new();
+    vm.context = Box::new(FileStore::open(path.join("missing.json")).unwrap());
+    vm.load(crate::asm::parse("1 \"gold\" setContext").unwrap());
+    assert!(matches!(vm.try_run(), Err(VmError::Runtime { pc: 2, .. })));
+    assert_eq!(vm.context.get("gold"), None);
+
+    let mut vm = VM::new();
+    vm.set_context("gold".to_string(), n(0.0)).unwrap();
+    let log = Recording::attach(&mut vm);
+    vm.load(program());
+    vm.run();
+    assert_eq!(
+      *log.borrow(),
+      vec![Access::Get("gold".to_string()), Access::Set("gold".to_string(), n(1.0)), Access::Delete("name".to_string())]
+    );
+  }
+
+  #[test]
+  fn test_transactions() {
+    use crate::vm::{Status, Value, VmError};
+    use std::collections::HashMap;
+    let n = |n: f64| Value::Number(n);
+    let context = |vm: &VM| {
+      let mut keys: Vec<(String, Value)> = vm.context.iter().collect();
+      keys.sort_by(|a, b| a.0.cmp(&b.0));
+      keys
+    };
+    let mut vm = VM::new();
+    vm.set_context("gold".to_string(), n(10.0)).unwrap();
+    vm.set_context("name".to_string(), Value::String("hero".to_string())).unwrap();
+    let before = context(&vm);
+
+    vm.begin();
+    vm.set_context("gold".to_string(), n(5.0)).unwrap();
+    vm.begin();
+    vm.delete_context("name").unwrap();
+    vm.set_context("new".to_string(), n(1.0)).unwrap();
+    vm.commit().unwrap();
+    vm.set_context("gold".to_string(), n(0.0)).unwrap();
+    assert_eq!(vm.transaction_depth(), 1);
+    vm.rollback().unwrap();
+    assert_eq!(context(&vm), before);
+    assert_eq!(vm.transaction_depth(), 0);
+
+    vm.begin();
+    vm.set_context("gold".to_string(), n(7.0)).unwrap();
+    vm.commit().unwrap();
+    assert_eq!(vm.context.get("gold"), Some(n(7.0)));
+
+    // a failing run leaves the context as it was, observers included
+    let gold = vm.bind("gold");
+    vm.load(crate::asm::parse("1 \"gold\" setContext \"x\" \"y\" setContext \"a\" 1 plus").unwrap());
+    assert!(matches!(vm.try_run_atomic(), Err(VmError::Runtime { .. })));
+    assert_eq!(vm.context.get("gold"), Some(n(7.0)));
+    assert!(!vm.context.has("y"));
+    assert_eq!(gold.get(), Some(n(7.0)));
+
+    let mut vm = VM::new();
+    vm.register_module(crate::stdlib::transaction());
+    vm.load(crate::asm::parse(
+      "1 \"a\" setContext transaction.begin 2 \"a\" setContext 3 \"b\" setContext transaction.rollback transaction.begin 4 \"c\" setContext transaction.commit transaction.begin 5 \"d\" setContext"
+    ).unwrap());
+    assert_eq!(vm.try_run_atomic(), Ok(Status::Finished));
+    assert_eq!(vm.context.to_map(), HashMap::from([("a".to_string(), n(1.0)), ("c".to_string(), n(4.0)), ("d".to_string(), n(5.0))]));
+    assert_eq!(vm.transaction_depth(), 0);
+
+    // rolling back an inner transaction isn't recorded in the outer one
+    let x = vm.bind("x");
+    vm.begin();
+    vm.begin();
+    vm.set_context("x".to_string(), n(1.0)).unwrap();
+    vm.rollback().unwrap();
+    assert_eq!(x.get(), None);
+    vm.set_context("x".to_string(), n(2.0)).unwrap();
+    vm.rollback().unwrap();
+    assert!(!vm.context.has("x"));
+    assert_eq!(x.get(), None);
+
+    // nor is one the script did in a failing atomic run
+    let before = vm.context.to_map();
+    vm.load(crate::asm::parse("transaction.begin 3 \"b\" setContext transaction.rollback \"a\" 1 plus").unwrap());
+    assert!(vm.try_run_atomic().is_err());
+    assert_eq!(vm.context.to_map(), before);
+    vm.pc = vm.programlist.len();
+    vm.load(crate::asm::parse("transaction.commit").unwrap());
+    assert_eq!(
+      vm.try_run(),
+      Err(VmError::Runtime { pc: 28, message: "commit: no transaction to commit".to_string() })
+    );
+    assert_eq!(
+      vm.rollback(),
+      Err(VmError::Runtime { pc: 28, message: "rollback: no transaction to roll back".to_string() })
+    );
+  }
+
+  #[test]
+  fn test_context_json_and_diff() {
+    use crate::context::{ContextDiff, ContextStore};
+    use crate::vm::{Value, VmError};
+    use std::collections::{BTreeMap, HashMap};
+    let n = |n: f64| Value::Number(n);
+    let s = |s: &str| Value::String(s.to_string());
+    let mut vm = VM::new();
+    vm.set_context_from_json(&serde_json::json!({ "gold": 10, "name": "hero", "tmp": 1 })).unwrap();
+    assert_eq!(vm.context.get("name"), Some(s("hero")));
+    assert_eq!(
+      vm.set_context_from_json(&serde_json::json!([1])),
+      Err(VmError::Runtime { pc: 0, message: "not a JSON object".to_string() })
+    );
+    assert_eq!(
+      vm.set_context_from_json(&serde_json::json!({ "a": 1, "b": [] })),
+      Err(VmError::Runtime { pc: 0, message: "b is not a number or string".to_string() })
+    );
+    assert!(!vm.context.has("a"));
+    // a store that fails on the second key keeps neither
+    struct FailsOn(HashMap<String, Value>, &'static str);
+    impl ContextStore for FailsOn {
+      fn get(&self, key: &str) -> Option<Value> {
+        ContextStore::get(&self.0, key)
+      }
+      fn set(&mut self, key: &str, value: Value) -> Result<Option<Value>, String> {
+        if key == self.1 {
+          return Err(format!("can't write {}", key));
+        }
+        self.0.set(key, value)
+      }
+      fn delete(&mut self, key: &str) -> Result<Option<Value>, String> {
+        self.0.delete(key)
+      }
+      fn iter(&self) -> Box<dyn Iterator<Item = (String, Value)> + '_> {
+        ContextStore::iter(&self.0)
+      }
+    }
+    let mut failing = VM::new();
+    failing.context = Box::new(FailsOn(HashMap::from([("a".to_string(), n(0.0))]), "b"));
+    assert_eq!(
+      failing.set_context_from_json(&serde_json::json!({ "a": 1, "b": 2 })),
+      Err(VmError::Runtime { pc: 0, message: "can't write b".to_string() })
+    );
+    assert_eq!(failing.context_to_json(), serde_json::json!({ "a": 0.0 }));
+
+    let before = vm.snapshot();
+    vm.load(crate::asm::parse("\"gold\" getContext 5 plus \"gold\" setContext \"tmp\" delContext 1 \"level\" setContext").unwrap());
+    vm.run();
+    assert_eq!(vm.context_to_json(), serde_json::json!({ "gold": 15.0, "level": 1.0, "name": "hero" }));
+
+    let diff = ContextDiff::between(&before.context, &vm.snapshot().context);
+    assert_eq!(
+      diff,
+      ContextDiff {
+        added: BTreeMap::from([("level".to_string(), n(1.0))]),
+        removed: BTreeMap::from([("tmp".to_string(), n(1.0))]),
+        changed: BTreeMap::from([("gold".to_string(), (n(10.0), n(15.0)))]),
+      }
+    );
+    assert!(ContextDiff::between(&before.context, &before.context).is_empty());
+
+    let mut old_save = VM::new();
+    old_save.set_context_from_json(&serde_json::json!({ "gold": 10, "tmp": 1, "other": "x" })).unwrap();
+    diff.apply(&mut old_save).unwrap();
+    assert_eq!(old_save.context_to_json(), serde_json::json!({ "gold": 15.0, "level": 1.0, "other": "x" }));
+  }
 }
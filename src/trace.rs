@@ -0,0 +1,81 @@
+use std::io::Write;
+
+use serde_json::json;
+
+use crate::vm::{Value, VM};
+
+// Callbacks into `VM::step`, registered by pushing onto `VM::hooks`. All
+// methods do nothing by default.
+pub trait Hooks {
+    // Before the instruction at `pc` executes.
+    fn before_instruction(&mut self, _vm: &VM, _pc: usize) {}
+
+    // After the instruction at `pc` executed without an error; the VM's pc
+    // is already at the next instruction.
+    fn after_instruction(&mut self, _vm: &VM, _pc: usize) {}
+
+    // Before a foreign function is called, after `before_instruction`.
+    fn foreign_call(&mut self, _vm: &VM, _pc: usize, _name: &str) {}
+
+    // `setContext` or `delContext` changed `key`; `new` is `None` when it
+    // was deleted.
+    fn context_write(&mut self, _vm: &VM, _key: &str, _old: Option<&Value>, _new: Option<&Value>) {}
+
+    // The instruction at `from` moved the pc to `to` instead of the next
+    // instruction: a `goto`, a skip by `jz`/`jgz`, or a skipped brace block.
+    fn jump(&mut self, _vm: &VM, _from: usize, _to: usize) {}
+}
+
+// Writes one JSON object per executed instruction:
+//
+//   {"after":[],"before":[1.0,"a"],"context":{"a":1.0},"instr":"setContext","pc":4}
+//
+// with `foreign: true` for foreign calls, `context` holding the keys written
+// (null for deleted ones) and `jump` the pc jumped to, when they apply.
+pub struct JsonTracer<W: Write> {
+    out: W,
+    record: serde_json::Value,
+}
+
+impl<W: Write> JsonTracer<W> {
+    pub fn new(out: W) -> JsonTracer<W> {
+        JsonTracer {
+            out,
+            record: json!({}),
+        }
+    }
+}
+
+fn stack_json(vm: &VM) -> serde_json::Value {
+    serde_json::Value::Array(vm.stack.iter().map(|v| v.to_json()).collect())
+}
+
+impl<W: Write> Hooks for JsonTracer<W> {
+    fn before_instruction(&mut self, vm: &VM, pc: usize) {
+        self.record = json!({
+            "pc": pc,
+            "instr": vm.programlist[pc].to_string(),
+            "before": stack_json(vm),
+        });
+    }
+
+    fn after_instruction(&mut self, vm: &VM, _pc: usize) {
+        self.record["after"] = stack_json(vm);
+        writeln!(self.out, "{}", self.record).unwrap();
+    }
+
+    fn foreign_call(&mut self, _vm: &VM, _pc: usize, _name: &str) {
+        self.record["foreign"] = json!(true);
+    }
+
+    fn context_write(&mut self, _vm: &VM, key: &str, _old: Option<&Value>, new: Option<&Value>) {
+        if !self.record["context"].is_object() {
+            self.record["context"] = json!({});
+        }
+        self.record["context"][key] = new.map_or(serde_json::Value::Null, |v| v.to_json());
+    }
+
+    fn jump(&mut self, _vm: &VM, _from: usize, to: usize) {
+        self.record["jump"] = json!(to);
+    }
+}
@@ -0,0 +1,106 @@
+use crate::vm::{Status, Value, VmError, VM};
+
+// Context transactions, so that a script that fails halfway through a scene
+// doesn't leave half of its changes behind:
+//
+//   vm.begin();
+//   match vm.try_run() {
+//       Ok(_) => vm.commit()?,
+//       Err(_) => vm.rollback()?,
+//   }
+//
+// or just `vm.try_run_atomic()`. While a transaction is open, the VM records
+// the value each context key had before it changed; rolling back puts those
+// values back, telling observers as `setContext` and `delContext` would.
+// Transactions nest: committing an inner one leaves its changes for the
+// outer one to roll back. Scripts can use transactions through the
+// `transaction` module in `stdlib`.
+
+// The old values of the keys changed since a transaction began, oldest
+// first; None for keys that were not set.
+pub type Journal = Vec<(String, Option<Value>)>;
+
+impl VM {
+    pub fn begin(&mut self) {
+        self.transactions.push(Journal::new());
+    }
+
+    // Keeps the changes of the innermost transaction.
+    pub fn commit(&mut self) -> Result<(), VmError> {
+        let journal = match self.transactions.pop() {
+            Some(journal) => journal,
+            None => return Err(self.error("commit: no transaction to commit")),
+        };
+        if let Some(outer) = self.transactions.last_mut() {
+            outer.extend(journal);
+        }
+        Ok(())
+    }
+
+    // Undoes the changes of the innermost transaction. If the context store
+    // fails to undo one, the others are still undone and the first failure
+    // is returned.
+    pub fn rollback(&mut self) -> Result<(), VmError> {
+        let journal = match self.transactions.pop() {
+            Some(journal) => journal,
+            None => return Err(self.error("rollback: no transaction to roll back")),
+        };
+        let mut result = Ok(());
+        for (key, old) in journal.into_iter().rev() {
+            result = result.and(self.undo(&key, old));
+        }
+        result
+    }
+
+    // Puts back the value `key` had. Unlike `set_context`, this isn't
+    // recorded in the enclosing transaction, which never saw the change.
+    pub(crate) fn undo(&mut self, key: &str, old: Option<Value>) -> Result<(), VmError> {
+        let replaced = match &old {
+            Some(value) => self.context.set(key, value.clone()),
+            None => self.context.delete(key),
+        }
+        .map_err(|e| self.error(e))?;
+        if replaced != old {
+            if let Some(journal) = self.call_journal.as_mut() {
+                journal.push((key.to_string(), replaced.clone()));
+            }
+            self.context_changed(key, replaced.as_ref(), old.as_ref());
+        }
+        Ok(())
+    }
+
+    // How many transactions are open.
+    pub fn transaction_depth(&self) -> usize {
+        self.transactions.len()
+    }
+
+    // Runs in a transaction that is committed unless the run fails, in which
+    // case the context is left as it was. Transactions the program began and
+    // didn't end are committed or rolled back along with it. If the context
+    // can't be rolled back, that error is returned instead of the run's.
+    pub fn try_run_atomic(&mut self) -> Result<Status, VmError> {
+        let depth = self.transactions.len();
+        self.begin();
+        let mut result = self.try_run();
+        while self.transactions.len() > depth {
+            let ended = if result.is_ok() {
+                self.commit()
+            } else {
+                self.rollback()
+            };
+            if let Err(e) = ended {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    pub(crate) fn journal(&mut self, key: &str, old: &Option<Value>) {
+        if let Some(journal) = self.transactions.last_mut() {
+            journal.push((key.to_string(), old.clone()));
+        }
+        if let Some(journal) = self.call_journal.as_mut() {
+            journal.push((key.to_string(), old.clone()));
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+
+use crate::vm::{Instr, StackSignature, Value, ValueType, VM};
+
+// Static verifier for loaded programs.
+//
+// Runs an abstract interpretation of the program that tracks, for every
+// instruction, the possible stack depth and (where known) the type and value
+// of the topmost stack entries. Only *definite* problems are reported: an
+// underflow is only flagged if every path reaching the instruction leaves too
+// few values on the stack, and a type mismatch only if the operand is known to
+// have the wrong type on every path. A `goto` whose operand isn't known may
+// land on any label, or on any pc if it may be a number.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub pc: usize,
+    pub kind: DiagnosticKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiagnosticKind {
+    StackUnderflow {
+        function: String,
+        needed: usize,
+        available: usize,
+    },
+    TypeMismatch {
+        function: String,
+        expected: ValueType,
+        found: ValueType,
+    },
+    UnknownLabel(String),
+    UnmatchedBrace,
+    // Instructions `pc..end` can never be executed.
+    Unreachable {
+        end: usize,
+    },
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        !matches!(self.kind, DiagnosticKind::Unreachable { .. })
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            DiagnosticKind::StackUnderflow {
+                function,
+                needed,
+                available,
+            } => write!(
+                f,
+                "{}: stack underflow in {}: needs {} value(s), at most {} available",
+                self.pc, function, needed, available
+            ),
+            DiagnosticKind::TypeMismatch {
+                function,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: type mismatch in {}: expected {:?}, found {:?}",
+                self.pc, function, expected, found
+            ),
+            DiagnosticKind::UnknownLabel(label) => {
+                write!(f, "{}: goto to unknown label {:?}", self.pc, label)
+            }
+            DiagnosticKind::UnmatchedBrace => write!(f, "{}: no matching brace found", self.pc),
+            DiagnosticKind::Unreachable { end } => {
+                write!(f, "{}..{}: unreachable code", self.pc, end)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum AbstractValue {
+    Number(Option<f64>),
+    String(Option<String>),
+    Any,
+}
+
+impl PartialEq for AbstractValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            // compare bit patterns so that a NaN constant still reaches a fixpoint
+            (AbstractValue::Number(a), AbstractValue::Number(b)) => {
+                a.map(f64::to_bits) == b.map(f64::to_bits)
+            }
+            (AbstractValue::String(a), AbstractValue::String(b)) => a == b,
+            (AbstractValue::Any, AbstractValue::Any) => true,
+            _ => false,
+        }
+    }
+}
+
+impl AbstractValue {
+    fn from_value(v: &Value) -> AbstractValue {
+        match v {
+            Value::Number(n) => AbstractValue::Number(Some(*n)),
+            Value::String(s) => AbstractValue::String(Some(s.clone())),
+        }
+    }
+
+    fn of_type(t: ValueType) -> AbstractValue {
+        match t {
+            ValueType::Number => AbstractValue::Number(None),
+            ValueType::String => AbstractValue::String(None),
+            ValueType::Any => AbstractValue::Any,
+        }
+    }
+
+    fn value_type(&self) -> ValueType {
+        match self {
+            AbstractValue::Number(_) => ValueType::Number,
+            AbstractValue::String(_) => ValueType::String,
+            AbstractValue::Any => ValueType::Any,
+        }
+    }
+
+    fn join(&self, other: &AbstractValue) -> AbstractValue {
+        if self == other {
+            return self.clone();
+        }
+        match (self, other) {
+            (AbstractValue::Number(_), AbstractValue::Number(_)) => AbstractValue::Number(None),
+            (AbstractValue::String(_), AbstractValue::String(_)) => AbstractValue::String(None),
+            _ => AbstractValue::Any,
+        }
+    }
+}
+
+// Abstract stack: the depth lies in `min..=max` (`max == None` meaning
+// unbounded), and the topmost `top.len()` entries are known. `top` never
+// holds more entries than `min`.
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    min: usize,
+    max: Option<usize>,
+    top: Vec<AbstractValue>,
+}
+
+impl State {
+    fn from_stack(stack: &[Value]) -> State {
+        State {
+            min: stack.len(),
+            max: Some(stack.len()),
+            top: stack.iter().map(AbstractValue::from_value).collect(),
+        }
+    }
+
+    fn unknown() -> State {
+        State {
+            min: 0,
+            max: None,
+            top: vec![],
+        }
+    }
+
+    fn push(&mut self, v: AbstractValue) {
+        self.min += 1;
+        self.max = self.max.map(|m| m + 1);
+        self.top.push(v);
+    }
+
+    // Pops a value that may or may not be present.
+    fn pop(&mut self) -> AbstractValue {
+        self.min = self.min.saturating_sub(1);
+        self.max = self.max.map(|m| m.saturating_sub(1));
+        self.top.pop().unwrap_or(AbstractValue::Any)
+    }
+
+    fn may_have(&self, n: usize) -> bool {
+        match self.max {
+            Some(m) => m >= n,
+            None => true,
+        }
+    }
+
+    fn join(&self, other: &State) -> State {
+        let k = self.top.len().min(other.top.len());
+        let a = &self.top[self.top.len() - k..];
+        let b = &other.top[other.top.len() - k..];
+        State {
+            min: self.min.min(other.min),
+            max: match (self.max, other.max) {
+                (Some(x), Some(y)) => Some(x.max(y)),
+                _ => None,
+            },
+            top: a.iter().zip(b).map(|(x, y)| x.join(y)).collect(),
+        }
+    }
+
+    // Like `join`, but gives up on an upper bound for the depth as soon as it
+    // grows, so that loops which keep pushing still reach a fixpoint.
+    fn widen(&self, other: &State) -> State {
+        let mut joined = self.join(other);
+        if joined.max != self.max {
+            joined.max = None;
+        }
+        joined
+    }
+}
+
+struct Analysis<'a> {
+    vm: &'a VM,
+    diagnostics: Vec<Diagnostic>,
+    // Set when a `goto` target could not be determined statically.
+    dynamic_goto: bool,
+}
+
+impl<'a> Analysis<'a> {
+    fn report(&mut self, pc: usize, kind: DiagnosticKind) {
+        self.diagnostics.push(Diagnostic { pc, kind });
+    }
+
+    // Pops the operands described by `inputs` (top of stack first), checking
+    // depth and types. Returns `None` if the instruction definitely fails.
+    fn pop_operands(
+        &mut self,
+        pc: usize,
+        function: &str,
+        inputs: &[ValueType],
+        state: &mut State,
+    ) -> Option<Vec<AbstractValue>> {
+        if !state.may_have(inputs.len()) {
+            self.report(
+                pc,
+                DiagnosticKind::StackUnderflow {
+                    function: function.to_string(),
+                    needed: inputs.len(),
+                    available: state.max.unwrap_or(0),
+                },
+            );
+            return None;
+        }
+        let mut popped = vec![];
+        for expected in inputs {
+            let v = state.pop();
+            let found = v.value_type();
+            if *expected != ValueType::Any && found != ValueType::Any && found != *expected {
+                self.report(
+                    pc,
+                    DiagnosticKind::TypeMismatch {
+                        function: function.to_string(),
+                        expected: *expected,
+                        found,
+                    },
+                );
+                return None;
+            }
+            popped.push(v);
+        }
+        Some(popped)
+    }
+
+    fn apply_signature(
+        &mut self,
+        pc: usize,
+        function: &str,
+        signature: &StackSignature,
+        state: &mut State,
+    ) -> bool {
+        if self
+            .pop_operands(pc, function, &signature.inputs, state)
+            .is_none()
+        {
+            return false;
+        }
+        for t in &signature.outputs {
+            state.push(AbstractValue::of_type(*t));
+        }
+        true
+    }
+
+    // Computes the states flowing out of the instruction at `pc` together with
+    // the pc they flow to.
+    fn transfer(&mut self, pc: usize, mut state: State) -> Vec<(usize, State)> {
+        use ValueType::{Any, Number, String};
+        let next = vec![(pc + 1, state.clone())];
+        let name = match &self.vm.programlist[pc] {
+            Instr::Number(n) => {
+                state.push(AbstractValue::Number(Some(*n)));
+                return vec![(pc + 1, state)];
+            }
+            Instr::String(s) => {
+                state.push(AbstractValue::String(Some(s.clone())));
+                return vec![(pc + 1, state)];
+            }
+            Instr::CloseBrace => return next,
+            Instr::OpenBrace => match self.vm.matching_brace(pc) {
+                Some(close) => return vec![(close + 1, state)],
+                None => {
+                    self.report(pc, DiagnosticKind::UnmatchedBrace);
+                    return vec![];
+                }
+            },
+            Instr::Func(_, name) => name.as_str(),
+        };
+
+        let signature =
+            |inputs: Vec<ValueType>, outputs: Vec<ValueType>| StackSignature { inputs, outputs };
+        let sig = match name {
+            "nop" => return next,
+            "exit" => return vec![],
+            "pause" => return next,
+            "pop" | "stdout" => {
+                // both tolerate an empty stack
+                state.pop();
+                return vec![(pc + 1, state)];
+            }
+            "dup" => {
+                return match self.pop_operands(pc, name, &[Any], &mut state) {
+                    Some(v) => {
+                        state.push(v[0].clone());
+                        state.push(v[0].clone());
+                        vec![(pc + 1, state)]
+                    }
+                    None => vec![],
+                };
+            }
+            "jz" | "jgz" => {
+                let v = match self.pop_operands(pc, name, &[Number], &mut state) {
+                    Some(v) => v,
+                    None => return vec![],
+                };
+                return match v[0] {
+                    AbstractValue::Number(Some(n)) => {
+                        let skip = if name == "jz" { n == 0.0 } else { n > 0.0 };
+                        vec![(if skip { pc + 2 } else { pc + 1 }, state)]
+                    }
+                    _ => vec![(pc + 1, state.clone()), (pc + 2, state)],
+                };
+            }
+            "goto" => {
+                let v = match self.pop_operands(pc, name, &[Any], &mut state) {
+                    Some(v) => v,
+                    None => return vec![],
+                };
+                return match &v[0] {
+                    AbstractValue::String(Some(label)) => match self.vm.labels.get(label) {
+                        Some(target) => vec![(*target as usize, state)],
+                        None => {
+                            self.report(pc, DiagnosticKind::UnknownLabel(label.clone()));
+                            vec![]
+                        }
+                    },
+                    AbstractValue::Number(Some(n)) => {
+                        vec![(VM::goto_target(*n), state)]
+                    }
+                    v => {
+                        // a string can only name a label, but a number can
+                        // land on any pc past the first
+                        self.dynamic_goto = true;
+                        let mut targets: Vec<usize> =
+                            self.vm.labels.values().map(|l| *l as usize).collect();
+                        if !matches!(v, AbstractValue::String(_)) && self.vm.policy.goto_by_number {
+                            targets.extend(1..self.vm.programlist.len());
+                        }
+                        targets.sort();
+                        targets.dedup();
+                        targets.into_iter().map(|t| (t, state.clone())).collect()
+                    }
+                };
+            }
+            "ppc" | "stacksize" => signature(vec![], vec![Number]),
+            "plus" | "+" | "min" | "-" | "mul" | "*" | "or" | "and" | "gt" | "lt" => {
+                signature(vec![Number, Number], vec![Number])
+            }
+            "concat" | "rconcat" => signature(vec![Any, Any], vec![String]),
+            "eq" => signature(vec![Any, Any], vec![Number]),
+            "randInt" | "not" => signature(vec![Number], vec![Number]),
+            "charCode" => signature(vec![Number], vec![String]),
+            "getContext" => signature(vec![String], vec![Any]),
+            "hasContext" => signature(vec![String], vec![Number]),
+            "delContext" => signature(vec![String], vec![]),
+            "setContext" => signature(vec![String, Any], vec![]),
+            _ => match self.vm.foreign_functions.get(name) {
+                Some(ff) => match &ff.signature {
+                    Some(sig) => sig.clone(),
+                    None => return vec![(pc + 1, State::unknown())],
+                },
+                None => return vec![(pc + 1, State::unknown())],
+            },
+        };
+        if self.apply_signature(pc, name, &sig, &mut state) {
+            vec![(pc + 1, state)]
+        } else {
+            vec![]
+        }
+    }
+}
+
+// Runs the abstract interpretation from the VM's pc to a fixpoint, returning
+// the entry state of every instruction, `None` for those never reached.
+fn analyze(vm: &VM) -> (Analysis<'_>, Vec<Option<State>>) {
+    let len = vm.programlist.len();
+    let mut analysis = Analysis {
+        vm,
+        diagnostics: vec![],
+        dynamic_goto: false,
+    };
+
+    // Fixpoint over the per-instruction entry states.
+    let mut states: Vec<Option<State>> = vec![None; len];
+    let mut worklist = VecDeque::new();
+    if vm.pc < len {
+        states[vm.pc] = Some(State::from_stack(&vm.stack));
+        worklist.push_back(vm.pc);
+    }
+    while let Some(pc) = worklist.pop_front() {
+        let state = states[pc].clone().unwrap();
+        for (succ, out) in analysis.transfer(pc, state) {
+            if succ >= len {
+                continue; // falls off the end of the program
+            }
+            let merged = match &states[succ] {
+                Some(existing) => existing.widen(&out),
+                None => out,
+            };
+            if states[succ].as_ref() != Some(&merged) {
+                states[succ] = Some(merged);
+                worklist.push_back(succ);
+            }
+        }
+    }
+
+    (analysis, states)
+}
+
+// The fewest values on the stack on entry to each instruction, on any path
+// from the VM's pc; `None` for instructions that are never reached.
+pub fn min_stack_depths(vm: &VM) -> Vec<Option<usize>> {
+    let (_, states) = analyze(vm);
+    states.into_iter().map(|s| s.map(|s| s.min)).collect()
+}
+
+pub fn verify(vm: &VM) -> Vec<Diagnostic> {
+    let len = vm.programlist.len();
+    let (mut analysis, states) = analyze(vm);
+
+    // Diagnostics are only collected from the final states, since
+    // intermediate states may be more precise than what actually flows in.
+    analysis.diagnostics.clear();
+    for (pc, state) in states.iter().enumerate() {
+        if let Some(state) = state {
+            analysis.transfer(pc, state.clone());
+        }
+    }
+
+    // With a computed goto any instruction might be a target, so nothing can
+    // be called unreachable.
+    if !analysis.dynamic_goto {
+        let mut pc = 0;
+        while pc < len {
+            if states[pc].is_none() {
+                let start = pc;
+                while pc < len && states[pc].is_none() {
+                    pc += 1;
+                }
+                analysis.report(start, DiagnosticKind::Unreachable { end: pc });
+            } else {
+                pc += 1;
+            }
+        }
+    }
+
+    // Deduplicate (goto may report the same problem for every path) and sort by pc.
+    let mut unique: BTreeMap<usize, Vec<DiagnosticKind>> = BTreeMap::new();
+    for d in analysis.diagnostics {
+        let kinds = unique.entry(d.pc).or_default();
+        if !kinds.contains(&d.kind) {
+            kinds.push(d.kind);
+        }
+    }
+    unique
+        .into_iter()
+        .flat_map(|(pc, kinds)| kinds.into_iter().map(move |kind| Diagnostic { pc, kind }))
+        .collect()
+}
@@ -1,39 +1,277 @@
 use rand::rng;
+use rand::rngs::ChaCha12Rng;
 use rand::RngExt;
+use rand::SeedableRng;
 use serde_json;
+use std::any::Any;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, Write};
+use std::panic;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::context::ContextStore;
+use crate::foreign::Registry;
+use crate::observe::Observers;
+use crate::optimizer;
+use crate::policy::Policy;
+use crate::trace::Hooks;
+use crate::transaction::Journal;
+use crate::verifier;
 
 pub struct VM {
     pub pc: usize,
     pub stack: Vec<Value>,
     pub programlist: Vec<Instr>,
-    pub context: HashMap<String, Value>,
+    // Where `setContext` and friends keep values, see `context`.
+    pub context: Box<dyn ContextStore>,
     pub labels: HashMap<String, i64>,
-    pub foreign_functions: Vec<ForeignFunc>,
+    pub foreign_functions: Registry,
+    pub(crate) observers: Observers,
+    // Open transactions, innermost last; see `transaction`.
+    pub(crate) transactions: Vec<Journal>,
+    // Context changes made by the foreign function being called, to undo if
+    // it panics.
+    pub(crate) call_journal: Option<Journal>,
+    // What the program may call, see `policy`.
+    pub policy: Policy,
     pub running: bool,
     pub exited: bool,
+    pub rng: Random,
+    // Where `stdout` writes; the process's stdout unless replaced.
+    pub stdout: Box<dyn Write>,
+    // Called around every instruction, see `trace::Hooks`.
+    pub hooks: Vec<Box<dyn Hooks>>,
+    // The host work the VM waits for, see `yield_pending`.
+    pub pending: Option<Ticket>,
+    pub futures: HashMap<Ticket, PendingFuture>,
+    next_ticket: u64,
+}
+
+// Identifies host work a foreign function yielded on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ticket(pub u64);
+
+pub type PendingFuture = Pin<Box<dyn Future<Output = Vec<Value>>>>;
+
+// Why `run` returned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    // The pc ran past the end of the program.
+    Finished,
+    // `pause` was called, or the VM was suspended.
+    Paused,
+    Exited,
+    // A foreign function waits for host work; see `complete`.
+    Yielded(Ticket),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Number(f64),
     String(String),
 }
 
+#[derive(Clone)]
 pub enum Instr {
     Number(f64),
     String(String),
-    Func(Func),
+    Func(Call, String),
     OpenBrace,
     CloseBrace,
 }
 
+// What a function instruction calls, resolved when it is loaded.
+#[derive(Clone)]
+pub enum Call {
+    Builtin(Builtin),
+    Foreign(Rc<ForeignCall>),
+}
+
+pub struct ForeignCall {
+    pub func: Func,
+    pub name: String,
+    // The number of values it pops, if its signature says.
+    pub arity: Option<usize>,
+}
+
 pub struct ForeignFunc {
-    pub func: fn(&mut VM),
+    pub func: Func,
     pub name: String,
+    // Optional stack effect, used by the static verifier and to save only the
+    // arguments for putting the stack back when the function panics.
+    // Functions without one are treated as having an unknown effect on the
+    // stack.
+    pub signature: Option<StackSignature>,
+    // What the function does, for generated documentation and completion.
+    pub description: String,
+    // Whether the function only depends on its arguments and has no other
+    // effect than pushing its results.
+    pub pure: bool,
+    // Rough cost of a call, in builtin instructions.
+    pub cost: u32,
+}
+
+impl Default for ForeignFunc {
+    fn default() -> Self {
+        ForeignFunc {
+            func: Rc::new(|_vm: &mut VM| Ok(())),
+            name: String::new(),
+            signature: None,
+            description: String::new(),
+            pure: false,
+            cost: 1,
+        }
+    }
 }
 
-type Func = fn(&mut VM);
+impl ForeignFunc {
+    // The number of values it pops, if its signature is known.
+    pub fn arity(&self) -> Option<usize> {
+        self.signature.as_ref().map(|s| s.inputs.len())
+    }
+
+    pub fn describe(&mut self, description: &str) -> &mut Self {
+        self.description = description.to_string();
+        self
+    }
+
+    pub fn mark_pure(&mut self) -> &mut Self {
+        self.pure = true;
+        self
+    }
+
+    pub fn set_cost(&mut self, cost: u32) -> &mut Self {
+        self.cost = cost;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueType {
+    Number,
+    String,
+    Any,
+}
+
+// Stack effect of a function: `inputs` are the values it pops, top of stack
+// first; `outputs` are the values it pushes, in push order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackSignature {
+    pub inputs: Vec<ValueType>,
+    pub outputs: Vec<ValueType>,
+}
+
+// Builtins are plain functions; foreign functions can be closures over host
+// state.
+pub type Builtin = fn(&mut VM) -> Result<(), VmError>;
+pub type Func = Rc<dyn Fn(&mut VM) -> Result<(), VmError>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    // Instruction `index` of the list given to `try_load` is invalid.
+    Load {
+        index: usize,
+        message: String,
+    },
+    // The instruction at `pc` failed.
+    Runtime {
+        pc: usize,
+        message: String,
+    },
+    // A foreign function can't be registered under `name`.
+    Register {
+        name: String,
+        message: String,
+    },
+    // The foreign function `name` called at `pc` panicked. The pc, and the
+    // stack if the function has a signature, are as they were before the
+    // call, so the call can be retried with `run`.
+    ForeignPanic {
+        name: String,
+        message: String,
+        pc: usize,
+    },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::Load { message, .. } => write!(f, "{}", message),
+            VmError::Runtime { message, .. } => write!(f, "{}", message),
+            VmError::Register { message, .. } => write!(f, "{}", message),
+            VmError::ForeignPanic { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+// The execution state of a VM, to return to with `restore`. The program,
+// labels and registered functions are not part of it, and neither are the
+// futures of `await_future`: a snapshot taken while waiting for one restores
+// to waiting for its ticket, which the host has to `complete` if the future
+// is gone by then.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub pc: usize,
+    pub stack: Vec<Value>,
+    pub context: HashMap<String, Value>,
+    pub running: bool,
+    pub exited: bool,
+    pub rng: Random,
+    pub pending: Option<Ticket>,
+    pub transactions: Vec<Journal>,
+}
+
+// The generator behind `randInt`. It is the one `StdRng` uses, so seeded
+// sequences are those of `StdRng::seed_from_u64` on every platform, but unlike
+// `StdRng` it can be cloned into a snapshot.
+pub struct Random(ChaCha12Rng);
+
+impl Random {
+    pub fn seed_from_u64(seed: u64) -> Random {
+        Random(ChaCha12Rng::seed_from_u64(seed))
+    }
+
+    pub fn next_f64(&mut self) -> f64 {
+        self.0.random::<f64>()
+    }
+}
+
+impl Clone for Random {
+    fn clone(&self) -> Random {
+        Random(ChaCha12Rng::deserialize_state(&self.0.serialize_state()))
+    }
+}
+
+// A writer whose clones all append to the same buffer, to capture what a VM
+// writes to a writer it owns, like `stdout`.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    // Empties the buffer, returning its contents.
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.borrow_mut())
+    }
+
+    pub fn take_string(&self) -> String {
+        String::from_utf8_lossy(&self.take()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
 impl Value {
     pub fn is_string(&self) -> bool {
@@ -64,10 +302,18 @@ impl Value {
         }
     }
 
-    pub fn to_string(&self) -> String {
+    pub fn to_json(&self) -> serde_json::Value {
         match self {
-            Value::Number(a) => format!("{}", a),
-            Value::String(a) => a.to_string(),
+            Value::Number(a) => serde_json::json!(a),
+            Value::String(a) => serde_json::json!(a),
+        }
+    }
+
+    pub fn from_json(v: &serde_json::Value) -> Option<Value> {
+        match v {
+            serde_json::Value::Number(a) => a.as_f64().map(Value::Number),
+            serde_json::Value::String(a) => Some(Value::String(a.clone())),
+            _ => None,
         }
     }
 
@@ -95,6 +341,33 @@ impl Value {
     }
 }
 
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(a) => write!(f, "{}", a),
+            Value::String(a) => write!(f, "{}", a),
+        }
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instr::Number(a) => write!(f, "{}", a),
+            Instr::String(a) => write!(f, "{}", serde_json::Value::from(a.as_str())),
+            Instr::Func(_, name) => write!(f, "{}", name),
+            Instr::OpenBrace => write!(f, "{{"),
+            Instr::CloseBrace => write!(f, "}}"),
+        }
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
+}
+
 impl VM {
     pub fn put(&mut self, value: Value) {
         self.stack.push(value);
@@ -108,29 +381,73 @@ impl VM {
         self.stack.push(Value::String(value));
     }
 
-    pub fn i_plus(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        if a.is_number() && b.is_number() {
-            self.stack
-                .push(Value::Number(a.as_number() + b.as_number()));
-        } else {
-            panic!("+: operands must be numbers");
+    // A runtime error at the current instruction, for builtins and foreign
+    // functions to return.
+    pub fn error(&self, message: impl Into<String>) -> VmError {
+        VmError::Runtime {
+            pc: self.pc,
+            message: message.into(),
         }
     }
 
-    pub fn i_nop(&mut self) {}
+    fn pop(&mut self, name: &str) -> Result<Value, VmError> {
+        match self.stack.pop() {
+            Some(v) => Ok(v),
+            None => Err(self.error(format!("{}: the stack is empty", name))),
+        }
+    }
+
+    fn pop_number(&mut self, name: &str) -> Result<f64, VmError> {
+        match self.pop(name)? {
+            Value::Number(n) => Ok(n),
+            Value::String(_) => Err(self.error(format!("{}: operand must be a number", name))),
+        }
+    }
 
-    pub fn i_closebrace(&mut self) {}
-    pub fn i_openbrace(&mut self) {
+    fn push_bool(&mut self, b: bool) {
+        self.stack.push(Value::Number(if b { 1.0 } else { 0.0 }));
+    }
+
+    pub fn i_plus(&mut self) -> Result<(), VmError> {
+        let a = self.pop("+")?;
+        let b = self.pop("+")?;
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => {
+                self.stack.push(Value::Number(a + b));
+                Ok(())
+            }
+            _ => Err(self.error("+: operands must be numbers")),
+        }
+    }
+
+    pub fn i_nop(&mut self) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    pub fn i_closebrace(&mut self) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    pub fn i_openbrace(&mut self) -> Result<(), VmError> {
+        match self.matching_brace(self.pc) {
+            Some(ppc) => {
+                self.pc = ppc; // will be incremented later!
+                Ok(())
+            }
+            None => Err(self.error("No matching brace found!")),
+        }
+    }
+
+    // Finds the `}` that closes the `{` at `pc`, if any.
+    pub fn matching_brace(&self, pc: usize) -> Option<usize> {
         let mut i = 1;
-        let mut ppc = self.pc + 1;
+        let mut ppc = pc + 1;
         while ppc < self.programlist.len() {
             let v = self.programlist.get(ppc).unwrap();
             match v {
                 Instr::Number(_) => {}
                 Instr::String(_) => {}
-                Instr::Func(_) => {}
+                Instr::Func(..) => {}
                 Instr::OpenBrace => {
                     i += 1;
                 }
@@ -138,286 +455,421 @@ impl VM {
                     i -= 1;
                     if i == 0 {
                         // found it!
-                        self.pc = ppc; // will be incremented later!
-                        return;
+                        return Some(ppc);
                     }
                 }
             }
             ppc += 1;
         }
-        panic!("No matching brace found!");
+        None
     }
 
-    pub fn i_pop(&mut self) {
+    pub fn i_pop(&mut self) -> Result<(), VmError> {
         self.stack.pop();
+        Ok(())
     }
 
-    pub fn i_min(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        if a.is_number() && b.is_number() {
-            self.stack
-                .push(Value::Number(a.as_number() - b.as_number()));
-        } else {
-            panic!("-: operands must be numbers");
+    pub fn i_min(&mut self) -> Result<(), VmError> {
+        let a = self.pop("-")?;
+        let b = self.pop("-")?;
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => {
+                self.stack.push(Value::Number(a - b));
+                Ok(())
+            }
+            _ => Err(self.error("-: operands must be numbers")),
         }
     }
 
-    pub fn i_mul(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        if a.is_number() && b.is_number() {
-            self.stack
-                .push(Value::Number(a.as_number() * b.as_number()));
-        } else {
-            panic!("*: operands must be numbers");
+    pub fn i_mul(&mut self) -> Result<(), VmError> {
+        let a = self.pop("*")?;
+        let b = self.pop("*")?;
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => {
+                self.stack.push(Value::Number(a * b));
+                Ok(())
+            }
+            _ => Err(self.error("*: operands must be numbers")),
         }
     }
 
-    pub fn i_stdout(&mut self) {
-        match self.stack.pop() {
-            Some(v) => print!("{}", v.js_to_string()),
-            None => print!("undefined"),
-        }
+    pub fn i_stdout(&mut self) -> Result<(), VmError> {
+        let written = match self.stack.pop() {
+            Some(v) => write!(self.stdout, "{}", v.js_to_string()),
+            None => write!(self.stdout, "undefined"),
+        };
+        written.map_err(|e| self.error(format!("stdout: {}", e)))
     }
 
-    pub fn i_goto(&mut self) {
-        let a = self.stack.pop().unwrap();
-        if a.is_number() {
-            self.pc = (a.as_number() - 1 as f64) as usize; // will be incremented after step!
-        } else if a.is_string() {
-            self.pc = (self.labels.get(&a.as_string()).unwrap() - 1) as usize;
-            // will be incremented after step!
+    pub fn i_goto(&mut self) -> Result<(), VmError> {
+        match self.pop("goto")? {
+            Value::Number(n) => {
+                if !self.policy.goto_by_number {
+                    return Err(self.error("goto by number is not allowed"));
+                }
+                // past the end stops at the end; will be incremented after step!
+                self.pc = VM::goto_target(n).min(self.programlist.len()) - 1;
+            }
+            Value::String(label) => match self.labels.get(&label) {
+                // wraps around for a label at pc 0; will be incremented after step!
+                Some(&pc) => self.pc = (pc as usize).wrapping_sub(1),
+                None => return Err(self.error(format!("goto: no label {}", label))),
+            },
         }
+        Ok(())
+    }
+
+    // The pc a numeric `goto` lands on. Numbers past the end land past it.
+    pub fn goto_target(n: f64) -> usize {
+        ((n - 1.0) as usize).saturating_add(1)
     }
 
-    pub fn i_concat(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        self.stack
-            .push(Value::String(format!("{}{}", a.to_string(), b.to_string())))
+    // The label of the region `pc` is in: the last label at or before it.
+    // Several labels on one instruction resolve to the alphabetically first.
+    pub fn label_region(&self, pc: usize) -> Option<&str> {
+        self.labels
+            .iter()
+            .filter(|(_, l)| **l as usize <= pc)
+            .max_by_key(|(name, l)| (**l, std::cmp::Reverse(*name)))
+            .map(|(name, _)| name.as_str())
     }
 
-    pub fn i_rconcat(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        self.stack
-            .push(Value::String(format!("{}{}", b.to_string(), a.to_string())))
+    pub fn i_concat(&mut self) -> Result<(), VmError> {
+        let a = self.pop("concat")?;
+        let b = self.pop("concat")?;
+        self.stack.push(Value::String(format!("{}{}", a, b)));
+        Ok(())
     }
 
-    pub fn i_randint(&mut self) {
-        let a = self.stack.pop().unwrap().as_number();
-        let r = (rng().random::<f64>() * a).floor();
+    pub fn i_rconcat(&mut self) -> Result<(), VmError> {
+        let a = self.pop("rconcat")?;
+        let b = self.pop("rconcat")?;
+        self.stack.push(Value::String(format!("{}{}", b, a)));
+        Ok(())
+    }
+
+    pub fn i_randint(&mut self) -> Result<(), VmError> {
+        let a = self.pop_number("randInt")?;
+        let r = (self.rng.next_f64() * a).floor();
         self.stack.push(Value::Number(r));
+        Ok(())
     }
 
-    pub fn i_charcode(&mut self) {
-        let a = self.stack.pop().unwrap().as_number() as i32;
+    pub fn i_charcode(&mut self) -> Result<(), VmError> {
+        let a = self.pop_number("charCode")? as i32;
         let code = (a as u32) & 0xFFFF;
-        let c = char::from_u32(code).unwrap();
-        self.stack.push(Value::String(c.to_string()));
+        match char::from_u32(code) {
+            Some(c) => {
+                self.stack.push(Value::String(c.to_string()));
+                Ok(())
+            }
+            None => Err(self.error(format!("charCode: {} is not a character", code))),
+        }
     }
 
-    pub fn i_not(&mut self) {
-        let a = self.stack.pop().unwrap().as_number();
-        if a == 0.0 {
-            self.stack.push(Value::Number(1 as f64));
-        } else {
-            self.stack.push(Value::Number(0 as f64));
-        }
+    pub fn i_not(&mut self) -> Result<(), VmError> {
+        let a = self.pop_number("not")?;
+        self.push_bool(a == 0.0);
+        Ok(())
     }
 
-    pub fn i_or(&mut self) {
-        let a = self.stack.pop().unwrap().as_number();
-        let b = self.stack.pop().unwrap().as_number();
-        if a == 0.0 && b == 0.0 {
-            self.stack.push(Value::Number(0 as f64));
-        } else {
-            self.stack.push(Value::Number(1 as f64));
-        }
+    pub fn i_or(&mut self) -> Result<(), VmError> {
+        let a = self.pop_number("or")?;
+        let b = self.pop_number("or")?;
+        self.push_bool(a != 0.0 || b != 0.0);
+        Ok(())
     }
 
-    pub fn i_and(&mut self) {
-        let a = self.stack.pop().unwrap().as_number();
-        let b = self.stack.pop().unwrap().as_number();
-        if a == 0.0 || b == 0.0 {
-            self.stack.push(Value::Number(0 as f64));
-        } else {
-            self.stack.push(Value::Number(1 as f64));
-        }
+    pub fn i_and(&mut self) -> Result<(), VmError> {
+        let a = self.pop_number("and")?;
+        let b = self.pop_number("and")?;
+        self.push_bool(a != 0.0 && b != 0.0);
+        Ok(())
     }
 
-    pub fn i_jgz(&mut self) {
-        let a = self.stack.pop().unwrap().as_number();
+    pub fn i_jgz(&mut self) -> Result<(), VmError> {
+        let a = self.pop_number("jgz")?;
         if a > 0.0 {
             self.pc += 1;
         }
+        Ok(())
     }
 
-    pub fn i_jz(&mut self) {
-        let a = self.stack.pop().unwrap().as_number();
+    pub fn i_jz(&mut self) -> Result<(), VmError> {
+        let a = self.pop_number("jz")?;
         if a == 0.0 {
             self.pc += 1;
         }
+        Ok(())
     }
 
-    pub fn i_gt(&mut self) {
-        let a = self.stack.pop().unwrap().as_number();
-        let b = self.stack.pop().unwrap().as_number();
-        if a > b {
-            self.stack.push(Value::Number(1 as f64));
-        } else {
-            self.stack.push(Value::Number(0 as f64));
-        }
+    pub fn i_gt(&mut self) -> Result<(), VmError> {
+        let a = self.pop_number("gt")?;
+        let b = self.pop_number("gt")?;
+        self.push_bool(a > b);
+        Ok(())
     }
 
-    pub fn i_lt(&mut self) {
-        let a = self.stack.pop().unwrap().as_number();
-        let b = self.stack.pop().unwrap().as_number();
-        if a < b {
-            self.stack.push(Value::Number(1 as f64));
-        } else {
-            self.stack.push(Value::Number(0 as f64));
-        }
+    pub fn i_lt(&mut self) -> Result<(), VmError> {
+        let a = self.pop_number("lt")?;
+        let b = self.pop_number("lt")?;
+        self.push_bool(a < b);
+        Ok(())
     }
 
-    pub fn i_dup(&mut self) {
-        let a = self.stack.pop().unwrap();
-        match a {
-            Value::Number(x) => {
-                self.stack.push(Value::Number(x));
-                self.stack.push(Value::Number(x));
-            }
-            Value::String(x) => {
-                self.stack.push(Value::String(x.clone()));
-                self.stack.push(Value::String(x.clone()));
-            }
-        }
+    pub fn i_dup(&mut self) -> Result<(), VmError> {
+        let a = self.pop("dup")?;
+        self.stack.push(a.clone());
+        self.stack.push(a);
+        Ok(())
     }
 
-    pub fn i_eq(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        match a {
-            Value::Number(_) => {
-                if b.clone().is_number() {
-                    if b.as_number() == a.as_number() {
-                        self.stack.push(Value::Number(1 as f64));
-                    } else {
-                        self.stack.push(Value::Number(0 as f64));
-                    }
-                } else {
-                    self.stack.push(Value::Number(0 as f64));
-                }
-            }
-            Value::String(_) => {
-                if b.clone().is_string() {
-                    if a.as_string().eq(&b.as_string()) {
-                        self.stack.push(Value::Number(1 as f64));
-                    } else {
-                        self.stack.push(Value::Number(0 as f64));
-                    }
-                } else {
-                    self.stack.push(Value::Number(0 as f64));
-                }
-            }
-        }
+    pub fn i_eq(&mut self) -> Result<(), VmError> {
+        let a = self.pop("eq")?;
+        let b = self.pop("eq")?;
+        self.push_bool(a == b);
+        Ok(())
     }
 
-    pub fn i_ppc(&mut self) {
+    pub fn i_ppc(&mut self) -> Result<(), VmError> {
         self.stack.push(Value::Number(self.pc as f64));
+        Ok(())
     }
 
-    pub fn i_stacksize(&mut self) {
+    pub fn i_stacksize(&mut self) -> Result<(), VmError> {
         self.stack.push(Value::Number(self.stack.len() as f64));
+        Ok(())
     }
 
-    pub fn i_pause(&mut self) {
+    pub fn i_pause(&mut self) -> Result<(), VmError> {
         self.running = false;
+        Ok(())
     }
 
-    pub fn i_exit(&mut self) {
+    pub fn i_exit(&mut self) -> Result<(), VmError> {
         self.running = false;
         self.exited = true;
+        Ok(())
     }
 
-    pub fn i_getcontext(&mut self) {
-        let a = self.stack.pop().unwrap();
-        if !a.is_string() {
-            panic!("getContext: key must be a string");
+    fn pop_key(&mut self, name: &str) -> Result<String, VmError> {
+        match self.pop(name)? {
+            Value::String(key) => Ok(key),
+            Value::Number(_) => Err(self.error(format!("{}: key must be a string", name))),
         }
-        let key = a.as_string();
-        let r = self
-            .context
-            .get(&key)
-            .expect("getContext: key not found in context");
-        match r {
-            Value::Number(x) => {
-                self.stack.push(Value::Number(*x as f64));
-            }
-            Value::String(x) => {
-                self.stack.push(Value::String(x.clone()));
+    }
+
+    pub fn i_getcontext(&mut self) -> Result<(), VmError> {
+        let key = self.pop_key("getContext")?;
+        match self.context.get(&key) {
+            Some(v) => {
+                self.stack.push(v);
+                Ok(())
             }
+            None => Err(self.error("getContext: key not found in context")),
         }
     }
-    pub fn i_hascontext(&mut self) {
-        let a = self.stack.pop().unwrap();
-        if !a.is_string() {
-            panic!("hasContext: key must be a string");
+
+    pub fn i_hascontext(&mut self) -> Result<(), VmError> {
+        let key = self.pop_key("hasContext")?;
+        let has = self.context.has(&key);
+        self.push_bool(has);
+        Ok(())
+    }
+
+    pub fn i_delcontext(&mut self) -> Result<(), VmError> {
+        let key = self.pop_key("delContext")?;
+        self.delete_context(&key)?;
+        Ok(())
+    }
+
+    pub fn i_setcontext(&mut self) -> Result<(), VmError> {
+        let key = self.pop("setContext")?;
+        let value = self.pop("setContext")?;
+        match key {
+            Value::String(key) => self.set_context(key, value),
+            Value::Number(_) => Err(self.error("setContext: key must be a string")),
         }
-        let key = a.as_string();
-        if self.context.contains_key(&key) {
-            self.stack.push(Value::Number(1 as f64));
+    }
+
+    // Sets a context value as `setContext` does, telling hooks and
+    // observers.
+    pub fn set_context(&mut self, key: String, value: Value) -> Result<(), VmError> {
+        if self.hooks.is_empty()
+            && self.observers.is_empty()
+            && self.transactions.is_empty()
+            && self.call_journal.is_none()
+        {
+            self.context.set(&key, value).map_err(|e| self.error(e))?;
         } else {
-            self.stack.push(Value::Number(0 as f64));
+            let old = self
+                .context
+                .set(&key, value.clone())
+                .map_err(|e| self.error(e))?;
+            self.journal(&key, &old);
+            self.context_changed(&key, old.as_ref(), Some(&value));
         }
+        Ok(())
     }
-    pub fn i_delcontext(&mut self) {
-        let a = self.stack.pop().unwrap();
-        if !a.is_string() {
-            panic!("delContext: key must be a string");
-        }
-        let key = a.as_string();
-        if self.context.contains_key(&key) {
-            self.context.remove(&key);
-        } else {
-            // do nothing
+
+    // Deletes a context value as `delContext` does, returning it.
+    pub fn delete_context(&mut self, key: &str) -> Result<Option<Value>, VmError> {
+        let old = self.context.delete(key).map_err(|e| self.error(e))?;
+        if old.is_some() {
+            self.journal(key, &old);
+            self.context_changed(key, old.as_ref(), None);
         }
+        Ok(old)
     }
-    pub fn i_setcontext(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        if !a.is_string() {
-            panic!("setContext: key must be a string");
+
+    pub(crate) fn context_changed(&mut self, key: &str, old: Option<&Value>, new: Option<&Value>) {
+        if !self.hooks.is_empty() {
+            self.notify(|h, vm| h.context_write(vm, key, old, new));
         }
-        let key = a.as_string();
-        self.context.insert(key, b);
+        self.observers.notify(key, old, new);
     }
 
     pub fn new() -> VM {
-        return VM {
+        VM::with_io(
+            Random(ChaCha12Rng::from_rng(&mut rng())),
+            Box::new(io::stdout()),
+        )
+    }
+
+    // A VM that is cheap to make, to stand in for one moved out for a while.
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) fn placeholder() -> VM {
+        VM::with_io(Random::seed_from_u64(0), Box::new(io::sink()))
+    }
+
+    fn with_io(rng: Random, stdout: Box<dyn Write>) -> VM {
+        VM {
             pc: 0,
             stack: std::vec::Vec::new(),
             programlist: std::vec::Vec::new(),
             running: false,
             exited: false,
-            context: HashMap::new(),
+            context: Box::default(),
             labels: HashMap::new(),
-            foreign_functions: std::vec::Vec::new(),
-        };
+            foreign_functions: Registry::default(),
+            observers: Observers::default(),
+            transactions: vec![],
+            call_journal: None,
+            policy: Policy::default(),
+            rng,
+            stdout,
+            hooks: std::vec::Vec::new(),
+            pending: None,
+            futures: HashMap::new(),
+            next_ticket: 0,
+        }
     }
 
-    pub fn run(&mut self) {
-        if !self.exited {
-            self.running = true;
-            while self.running && self.pc < self.programlist.len() {
-                self.step();
-            }
+    // Like `try_run`, but panics on runtime errors.
+    pub fn run(&mut self) -> Status {
+        self.try_run().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // Runs until the program finishes, pauses, exits or yields, or an
+    // instruction fails.
+    pub fn try_run(&mut self) -> Result<Status, VmError> {
+        if let Some(ticket) = self.pending {
+            return Ok(Status::Yielded(ticket));
+        }
+        if self.exited {
+            return Err(self.error("Program has already exited!"));
+        }
+        self.running = true;
+        while self.running && self.pc < self.programlist.len() {
+            self.step()?;
+        }
+        Ok(self.status())
+    }
+
+    pub fn status(&self) -> Status {
+        if let Some(ticket) = self.pending {
+            Status::Yielded(ticket)
+        } else if self.exited {
+            Status::Exited
+        } else if self.pc >= self.programlist.len() {
+            Status::Finished
         } else {
-            panic!("Program has already exited!");
+            Status::Paused
         }
     }
 
+    // Called by a foreign function that can't push its results yet, e.g.
+    // because it waits for a database or the player. Suspends the VM, so
+    // that `run` returns `Status::Yielded` with the returned ticket, until
+    // the host passes the results to `complete`.
+    pub fn yield_pending(&mut self) -> Result<Ticket, VmError> {
+        if self.pending.is_some() {
+            return Err(self.error("yield_pending: already waiting for host work"));
+        }
+        let ticket = Ticket(self.next_ticket);
+        self.next_ticket += 1;
+        self.pending = Some(ticket);
+        self.suspend();
+        Ok(ticket)
+    }
+
+    // Like `yield_pending`, for work done by a future; `run_async` awaits it
+    // and pushes what it returns.
+    pub fn await_future(
+        &mut self,
+        future: impl Future<Output = Vec<Value>> + 'static,
+    ) -> Result<Ticket, VmError> {
+        let ticket = self.yield_pending()?;
+        self.futures.insert(ticket, Box::pin(future));
+        Ok(ticket)
+    }
+
+    // Pushes the results of the host work `ticket` stands for, bottom
+    // first, and continues running.
+    pub fn try_complete(&mut self, ticket: Ticket, values: Vec<Value>) -> Result<Status, VmError> {
+        if self.pending != Some(ticket) {
+            return Err(self.error(format!("complete: not waiting for ticket {}", ticket.0)));
+        }
+        self.pending = None;
+        self.futures.remove(&ticket);
+        self.stack.extend(values);
+        self.try_run()
+    }
+
+    // Like `try_complete`, but panics on runtime errors.
+    pub fn complete(&mut self, ticket: Ticket, values: Vec<Value>) -> Status {
+        self.try_complete(ticket, values)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // Runs, awaiting the futures foreign functions wait for with
+    // `await_future`. Returns when the program finishes, pauses or exits, or
+    // yields on a ticket without a future, which the host must `complete`.
+    pub async fn try_run_async(&mut self) -> Result<Status, VmError> {
+        let mut status = self.try_run()?;
+        while let Status::Yielded(ticket) = status {
+            let future = match self.futures.remove(&ticket) {
+                Some(future) => future,
+                None => break,
+            };
+            let values = future.await;
+            status = self.try_complete(ticket, values)?;
+        }
+        Ok(status)
+    }
+
+    // Like `try_run_async`, but panics on runtime errors.
+    pub async fn run_async(&mut self) -> Status {
+        self.try_run_async()
+            .await
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // Makes `randInt` deterministic.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Random::seed_from_u64(seed);
+    }
+
     pub fn suspend(&mut self) {
         self.running = false;
     }
@@ -426,13 +878,30 @@ impl VM {
         self.running = true; // NOTE: does *not* increase programcounter!
     }
 
-    pub fn step(&mut self) {
-        let z = self.programlist.get(self.pc);
-        let z = match z {
-            Some(i) => i,
-            None => {
-                panic!("Program counter exceeds program list size!");
+    // Calls `f` on every hook. The hooks are detached from the VM meanwhile,
+    // so that they can look at it.
+    fn notify(&mut self, mut f: impl FnMut(&mut dyn Hooks, &VM)) {
+        let mut hooks = std::mem::take(&mut self.hooks);
+        for h in hooks.iter_mut() {
+            f(h.as_mut(), self);
+        }
+        self.hooks = hooks;
+    }
+
+    // Executes the instruction at the pc.
+    pub fn step(&mut self) -> Result<(), VmError> {
+        let pc = self.pc;
+        let traced = !self.hooks.is_empty();
+        if traced && pc < self.programlist.len() {
+            self.notify(|h, vm| h.before_instruction(vm, pc));
+            if let Instr::Func(Call::Foreign(call), _) = &self.programlist[pc] {
+                let call = call.clone();
+                self.notify(|h, vm| h.foreign_call(vm, pc, &call.name));
             }
+        }
+        let z = match self.programlist.get(self.pc) {
+            Some(i) => i,
+            None => return Err(self.error("Program counter exceeds program list size!")),
         };
         match z {
             Instr::Number(a) => {
@@ -441,117 +910,287 @@ impl VM {
             Instr::String(a) => {
                 self.stack.push(Value::String(a.clone()));
             }
-            Instr::Func(a) => {
-                a(self);
+            Instr::Func(call, name) => {
+                if !self.policy.allows_everything() {
+                    self.policy.check(name).map_err(|e| self.error(e))?;
+                }
+                match call {
+                    Call::Builtin(f) => {
+                        let f = *f;
+                        f(self)?;
+                    }
+                    Call::Foreign(call) => {
+                        let call = call.clone();
+                        self.call_foreign(&call)?;
+                    }
+                }
+            }
+            Instr::OpenBrace => self.i_openbrace()?,
+            Instr::CloseBrace => self.i_closebrace()?,
+        }
+        self.pc = self.pc.wrapping_add(1);
+        if traced {
+            let to = self.pc;
+            if to != pc.wrapping_add(1) {
+                self.notify(|h, vm| h.jump(vm, pc, to));
             }
-            Instr::OpenBrace => {
-                self.i_openbrace();
+            self.notify(|h, vm| h.after_instruction(vm, pc));
+        }
+        Ok(())
+    }
+
+    // Calls a foreign function. If it panics, the pc, stack, context and
+    // pending ticket are put back as they were and the panic is returned as a
+    // `VmError::ForeignPanic`. For a function with a signature only the values
+    // it takes are copied to put the stack back; without one, all of it is.
+    fn call_foreign(&mut self, call: &ForeignCall) -> Result<(), VmError> {
+        let pc = self.pc;
+        let base = call.arity.map_or(0, |n| self.stack.len().saturating_sub(n));
+        let args = self.stack[base..].to_vec();
+        let pending = self.pending;
+        let outer = self.call_journal.replace(vec![]);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| (call.func)(self)));
+        let journal = std::mem::replace(&mut self.call_journal, outer).unwrap_or_default();
+        let payload = match result {
+            Ok(result) => {
+                // a foreign function calling another one
+                if let Some(outer) = self.call_journal.as_mut() {
+                    outer.extend(journal);
+                }
+                return result;
             }
-            Instr::CloseBrace => {
-                self.i_closebrace();
+            Err(payload) => payload,
+        };
+        self.pc = pc;
+        self.stack.truncate(base);
+        self.stack.extend(args);
+        for (key, old) in journal.into_iter().rev() {
+            // the panic is the error to report, so undo what can be undone
+            let _ = self.undo(&key, old);
+        }
+        if self.pending != pending {
+            if let Some(ticket) = self.pending {
+                self.futures.remove(&ticket);
             }
+            self.pending = pending;
         }
-        self.pc += 1;
+        Err(VmError::ForeignPanic {
+            name: call.name.clone(),
+            message: panic_message(payload.as_ref()),
+            pc,
+        })
     }
 
+    // Registers a foreign function, panicking if its name is taken; see
+    // `try_register_foreign_function`.
     pub fn register_foreign_function(&mut self, ffunc: ForeignFunc) {
-        self.foreign_functions.push(ffunc);
+        if let Err(e) = self.try_register_foreign_function(ffunc) {
+            panic!("{}", e);
+        }
     }
 
+    // Registers a foreign function, unless its name is that of a builtin or
+    // of a function registered before.
+    pub fn try_register_foreign_function(&mut self, ffunc: ForeignFunc) -> Result<(), VmError> {
+        self.check_foreign_name(&ffunc.name)?;
+        self.foreign_functions.insert(ffunc);
+        Ok(())
+    }
+
+    pub fn check_foreign_name(&self, name: &str) -> Result<(), VmError> {
+        let taken = if VM::builtin(name).is_some() || name == "{" || name == "}" {
+            "it is a builtin"
+        } else if self.foreign_functions.contains(name) {
+            "it is already registered"
+        } else {
+            return Ok(());
+        };
+        Err(VmError::Register {
+            name: name.to_string(),
+            message: format!("Cannot register {}: {}", name, taken),
+        })
+    }
+
+    pub fn builtin(name: &str) -> Option<Builtin> {
+        match name {
+            "nop" => Some(VM::i_nop),
+            "pop" => Some(VM::i_pop),
+            "plus" | "+" => Some(VM::i_plus),
+            "min" | "-" => Some(VM::i_min),
+            "mul" | "*" => Some(VM::i_mul),
+            "concat" => Some(VM::i_concat),
+            "rconcat" => Some(VM::i_rconcat),
+            "randInt" => Some(VM::i_randint),
+            "charCode" => Some(VM::i_charcode),
+            "ppc" => Some(VM::i_ppc),
+            "eq" => Some(VM::i_eq),
+            "not" => Some(VM::i_not),
+            "or" => Some(VM::i_or),
+            "and" => Some(VM::i_and),
+            "jgz" => Some(VM::i_jgz),
+            "jz" => Some(VM::i_jz),
+            "gt" => Some(VM::i_gt),
+            "lt" => Some(VM::i_lt),
+            "dup" => Some(VM::i_dup),
+            "pause" => Some(VM::i_pause),
+            "exit" => Some(VM::i_exit),
+            "goto" => Some(VM::i_goto),
+            "getContext" => Some(VM::i_getcontext),
+            "hasContext" => Some(VM::i_hascontext),
+            "setContext" => Some(VM::i_setcontext),
+            "delContext" => Some(VM::i_delcontext),
+            "stacksize" => Some(VM::i_stacksize),
+            "stdout" => Some(VM::i_stdout),
+            _ => None,
+        }
+    }
+
+    pub fn verify(&self) -> Vec<verifier::Diagnostic> {
+        verifier::verify(self)
+    }
+
+    pub fn optimize(&mut self) -> Vec<usize> {
+        optimizer::optimize(self)
+    }
+
+    // Appends instructions to the program, panicking on invalid ones; see
+    // `try_load`.
     pub fn load(&mut self, instructions: std::vec::Vec<serde_json::Value>) {
-        for i in instructions.iter() {
+        if let Err(e) = self.try_load(instructions) {
+            panic!("{}", e);
+        }
+    }
+
+    // Appends instructions to the program. Nothing is appended if any of them
+    // is invalid. Unknown instruction types and unknown functions starting
+    // with `_` are skipped.
+    pub fn try_load(
+        &mut self,
+        instructions: std::vec::Vec<serde_json::Value>,
+    ) -> Result<(), VmError> {
+        let mut program: Vec<Instr> = vec![];
+        let mut labels: Vec<(String, i64)> = vec![];
+        for (index, i) in instructions.iter().enumerate() {
+            let err = |message: String| VmError::Load { index, message };
+            let object = i
+                .as_object()
+                .ok_or_else(|| err("instruction is not an object".to_string()))?;
             if i["type"] == "push-number-instruction" {
-                self.programlist
-                    .push(Instr::Number(i.get("value").unwrap().as_f64().unwrap()));
+                let value = i["value"].as_f64().ok_or_else(|| {
+                    err("push-number-instruction needs a numeric value".to_string())
+                })?;
+                program.push(Instr::Number(value));
             }
             if i["type"] == "push-string-instruction" {
-                self.programlist.push(Instr::String(String::from(
-                    i.get("value").unwrap().as_str().unwrap(),
-                )));
+                let value = i["value"].as_str().ok_or_else(|| {
+                    err("push-string-instruction needs a string value".to_string())
+                })?;
+                program.push(Instr::String(String::from(value)));
             }
             if i["type"] == "invoke-function-instruction" {
-                if i["functionName"] == "nop" {
-                    self.programlist.push(Instr::Func(VM::i_nop));
-                } else if i["functionName"] == "pop" {
-                    self.programlist.push(Instr::Func(VM::i_pop));
-                } else if i["functionName"] == "plus" || i["functionName"] == "+" {
-                    self.programlist.push(Instr::Func(VM::i_plus));
-                } else if i["functionName"] == "min" || i["functionName"] == "-" {
-                    self.programlist.push(Instr::Func(VM::i_min));
-                } else if i["functionName"] == "mul" || i["functionName"] == "*" {
-                    self.programlist.push(Instr::Func(VM::i_mul));
-                } else if i["functionName"] == "concat" {
-                    self.programlist.push(Instr::Func(VM::i_concat));
-                } else if i["functionName"] == "rconcat" {
-                    self.programlist.push(Instr::Func(VM::i_rconcat));
-                } else if i["functionName"] == "randInt" {
-                    self.programlist.push(Instr::Func(VM::i_randint));
-                } else if i["functionName"] == "charCode" {
-                    self.programlist.push(Instr::Func(VM::i_charcode));
-                } else if i["functionName"] == "ppc" {
-                    self.programlist.push(Instr::Func(VM::i_ppc));
-                } else if i["functionName"] == "eq" {
-                    self.programlist.push(Instr::Func(VM::i_eq));
-                } else if i["functionName"] == "not" {
-                    self.programlist.push(Instr::Func(VM::i_not));
-                } else if i["functionName"] == "or" {
-                    self.programlist.push(Instr::Func(VM::i_or));
-                } else if i["functionName"] == "and" {
-                    self.programlist.push(Instr::Func(VM::i_and));
-                } else if i["functionName"] == "jgz" {
-                    self.programlist.push(Instr::Func(VM::i_jgz));
-                } else if i["functionName"] == "jz" {
-                    self.programlist.push(Instr::Func(VM::i_jz));
-                } else if i["functionName"] == "gt" {
-                    self.programlist.push(Instr::Func(VM::i_gt));
-                } else if i["functionName"] == "lt" {
-                    self.programlist.push(Instr::Func(VM::i_lt));
-                } else if i["functionName"] == "dup" {
-                    self.programlist.push(Instr::Func(VM::i_dup));
-                } else if i["functionName"] == "pause" {
-                    self.programlist.push(Instr::Func(VM::i_pause));
-                } else if i["functionName"] == "exit" {
-                    self.programlist.push(Instr::Func(VM::i_exit));
-                } else if i["functionName"] == "goto" {
-                    self.programlist.push(Instr::Func(VM::i_goto));
-                } else if i["functionName"] == "{" {
-                    self.programlist.push(Instr::OpenBrace);
-                } else if i["functionName"] == "}" {
-                    self.programlist.push(Instr::CloseBrace);
-                } else if i["functionName"] == "getContext" {
-                    self.programlist.push(Instr::Func(VM::i_getcontext));
-                } else if i["functionName"] == "hasContext" {
-                    self.programlist.push(Instr::Func(VM::i_hascontext));
-                } else if i["functionName"] == "setContext" {
-                    self.programlist.push(Instr::Func(VM::i_setcontext));
-                } else if i["functionName"] == "delContext" {
-                    self.programlist.push(Instr::Func(VM::i_delcontext));
-                } else if i["functionName"] == "stacksize" {
-                    self.programlist.push(Instr::Func(VM::i_stacksize));
-                } else if i["functionName"] == "stdout" {
-                    self.programlist.push(Instr::Func(VM::i_stdout));
+                let fname = i["functionName"].as_str().ok_or_else(|| {
+                    err("invoke-function-instruction needs a functionName".to_string())
+                })?;
+                if fname == "{" {
+                    program.push(Instr::OpenBrace);
+                } else if fname == "}" {
+                    program.push(Instr::CloseBrace);
                 } else {
-                    let fname = i["functionName"].as_str().unwrap();
-                    let mut found = false;
-                    for i in &self.foreign_functions {
-                        if i.name.eq(fname) {
-                            self.programlist.push(Instr::Func(i.func));
-                            found = true;
+                    let call = match VM::builtin(fname) {
+                        Some(f) => Some(Call::Builtin(f)),
+                        None => self.foreign_functions.get(fname).map(|f| {
+                            Call::Foreign(Rc::new(ForeignCall {
+                                func: f.func.clone(),
+                                name: fname.to_string(),
+                                arity: f.arity(),
+                            }))
+                        }),
+                    };
+                    match call {
+                        Some(call) => {
+                            self.policy.check(fname).map_err(err)?;
+                            let previous = program.last().or_else(|| self.programlist.last());
+                            if fname == "goto"
+                                && !self.policy.goto_by_number
+                                && matches!(previous, Some(Instr::Number(_)))
+                            {
+                                return Err(err("goto by number is not allowed".to_string()));
+                            }
+                            program.push(Instr::Func(call, fname.to_string()));
                         }
-                    }
-                    if found {
-                    } else {
-                        if !fname.starts_with("_") {
-                            panic!("Function not found: {}", fname);
+                        None if !fname.starts_with("_") => {
+                            return Err(err(format!("Function not found: {}", fname)));
                         }
+                        None => {}
                     }
                 }
             }
-            if i.as_object().unwrap().contains_key("label") {
-                let k = i.get("label").unwrap().as_str().unwrap().to_string();
-                self.labels.insert(k, (self.programlist.len() - 1) as i64);
-                print!("> {:?}", self.labels);
+            if let Some(label) = object.get("label") {
+                let label = label
+                    .as_str()
+                    .ok_or_else(|| err("label must be a string".to_string()))?;
+                // a label on a skipped instruction names the one before it
+                let pc = (self.programlist.len() + program.len()) as i64 - 1;
+                if pc < 0 {
+                    return Err(err(format!(
+                        "label {} does not follow an instruction",
+                        label
+                    )));
+                }
+                labels.push((label.to_string(), pc));
             }
         }
+        self.programlist.append(&mut program);
+        self.labels.extend(labels);
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            stack: self.stack.clone(),
+            context: self.context.to_map(),
+            running: self.running,
+            exited: self.exited,
+            rng: self.rng.clone(),
+            pending: self.pending,
+            transactions: self.transactions.clone(),
+        }
+    }
+
+    // Fails if the context store can't be written, leaving all but the
+    // context as it was.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), VmError> {
+        self.context
+            .assign(&snapshot.context)
+            .map_err(|e| self.error(e))?;
+        self.pc = snapshot.pc;
+        self.stack = snapshot.stack.clone();
+        self.running = snapshot.running;
+        self.exited = snapshot.exited;
+        self.rng = snapshot.rng.clone();
+        self.pending = snapshot.pending;
+        // the futures of other tickets would never be awaited
+        self.futures
+            .retain(|ticket, _| Some(*ticket) == snapshot.pending);
+        self.transactions = snapshot.transactions.clone();
+        Ok(())
+    }
+}
+
+// Runs `f`, turning a panic, e.g. of `run` or `load`, into an `Err` holding
+// its message.
+pub fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(panic::AssertUnwindSafe(f))
+        .map_err(|payload| panic_message(payload.as_ref()))
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown error".to_string()
     }
 }
@@ -0,0 +1,426 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::panic::AssertUnwindSafe;
+use std::rc::Rc;
+
+use js_sys::futures::{future_to_promise, JsFuture};
+use js_sys::{Array, Function, Object, Promise, Reflect, Uint8Array, JSON};
+use wasm_bindgen::prelude::*;
+
+use crate::asm;
+use crate::vm::{ForeignFunc, Value, VmError, VM};
+
+// JavaScript API, for running Tzo in the browser without the TypeScript
+// implementation:
+//
+//   const vm = new TzoVM();
+//   vm.registerFunction("alert", (tzo) => alert(tzo.pop()));
+//   vm.registerFunction("ask", (tzo) => fetchAnswer(tzo.pop()));
+//   vm.load('"hi" alert "question" ask stdout');
+//   for (let p = vm.run(); p; p = vm.run()) await p;
+//
+// A JavaScript function that returns a promise suspends the VM: `run`,
+// `step` and `resume` then return a promise to await before calling them
+// again, which pushes what the promise resolved to and carries on.
+//
+// Runtime errors are returned from `run`, `step` and `resume` as JavaScript
+// errors, as are errors thrown by JavaScript functions and promises they
+// return that reject. All of them leave the VM at the failed instruction.
+
+fn error(message: impl fmt::Display) -> JsValue {
+    JsError::new(&message.to_string()).into()
+}
+
+fn to_js(value: &Value) -> JsValue {
+    match value {
+        Value::Number(n) => JsValue::from_f64(*n),
+        Value::String(s) => JsValue::from_str(s),
+    }
+}
+
+fn from_js(value: &JsValue) -> Result<Value, JsValue> {
+    if let Some(n) = value.as_f64() {
+        Ok(Value::Number(n))
+    } else if let Some(s) = value.as_string() {
+        Ok(Value::String(s))
+    } else {
+        Err(error(format!(
+            "not a Tzo value: {}",
+            String::from(JSON::stringify(value).unwrap_or_else(|_| "?".into()))
+        )))
+    }
+}
+
+fn stack_to_js(stack: &[Value]) -> Array {
+    stack.iter().map(to_js).collect()
+}
+
+fn stack_from_js(stack: &JsValue) -> Result<Vec<Value>, JsValue> {
+    if !Array::is_array(stack) {
+        return Err(error("the stack must be an array"));
+    }
+    Array::from(stack).iter().map(|v| from_js(&v)).collect()
+}
+
+fn context_to_js(context: &HashMap<String, Value>) -> Result<Object, JsValue> {
+    let object = Object::new();
+    for (k, v) in context {
+        Reflect::set(&object, &JsValue::from_str(k), &to_js(v))?;
+    }
+    Ok(object)
+}
+
+fn context_from_js(context: &JsValue) -> Result<HashMap<String, Value>, JsValue> {
+    if !context.is_object() {
+        return Err(error("the context must be an object"));
+    }
+    let mut map = HashMap::new();
+    for entry in Object::entries(&Object::from(context.clone())).iter() {
+        let entry = Array::from(&entry);
+        let key = entry.get(0).as_string().unwrap_or_default();
+        map.insert(key, from_js(&entry.get(1))?);
+    }
+    Ok(map)
+}
+
+// What JavaScript functions get to see of the VM while they run: the VM
+// itself, moved out of the `TzoVM` for the duration of the call, so that
+// context writes go through it like those of `setContext`. Empty once the
+// call has returned.
+type Frame = Rc<RefCell<Option<VM>>>;
+
+// Passed to JavaScript foreign functions, to pop their arguments and push
+// their results.
+#[wasm_bindgen]
+pub struct TzoProxy {
+    frame: Frame,
+}
+
+impl TzoProxy {
+    fn with_vm<T>(&self, f: impl FnOnce(&mut VM) -> Result<T, JsValue>) -> Result<T, JsValue> {
+        match self.frame.borrow_mut().as_mut() {
+            Some(vm) => f(vm),
+            None => Err(error("the function has returned")),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl TzoProxy {
+    pub fn pop(&self) -> Result<JsValue, JsValue> {
+        self.with_vm(|vm| match vm.stack.pop() {
+            Some(v) => Ok(to_js(&v)),
+            None => Err(error("the stack is empty")),
+        })
+    }
+
+    pub fn push(&self, value: JsValue) -> Result<(), JsValue> {
+        let value = from_js(&value)?;
+        self.with_vm(|vm| {
+            vm.stack.push(value);
+            Ok(())
+        })
+    }
+
+    // The top of the stack, or undefined if it is empty.
+    pub fn peek(&self) -> Result<JsValue, JsValue> {
+        self.with_vm(|vm| Ok(vm.stack.last().map_or(JsValue::UNDEFINED, to_js)))
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn stack(&self) -> Result<Array, JsValue> {
+        self.with_vm(|vm| Ok(stack_to_js(&vm.stack)))
+    }
+
+    // The value of a context key, or undefined if it isn't set.
+    #[wasm_bindgen(js_name = getContext)]
+    pub fn get_context(&self, key: &str) -> Result<JsValue, JsValue> {
+        self.with_vm(|vm| {
+            Ok(vm
+                .context
+                .get(key)
+                .map_or(JsValue::UNDEFINED, |v| to_js(&v)))
+        })
+    }
+
+    #[wasm_bindgen(js_name = setContext)]
+    pub fn set_context(&self, key: &str, value: JsValue) -> Result<(), JsValue> {
+        let value = from_js(&value)?;
+        self.with_vm(|vm| vm.set_context(key.to_string(), value).map_err(error))
+    }
+
+    #[wasm_bindgen(js_name = delContext)]
+    pub fn del_context(&self, key: &str) -> Result<(), JsValue> {
+        self.with_vm(|vm| vm.delete_context(key).map(|_| ()).map_err(error))
+    }
+}
+
+// Pushes what a JavaScript function returned, if anything.
+fn push_result(stack: &mut Vec<Value>, result: JsValue) -> Result<(), JsValue> {
+    if !result.is_undefined() {
+        stack.push(from_js(&result)?);
+    }
+    Ok(())
+}
+
+// The last JavaScript function call that didn't just return a value.
+enum Call {
+    Done,
+    // What it threw, to return instead of the runtime error it caused.
+    Failed(JsValue),
+    // Resolves, without a value, once the returned promise settles.
+    Waiting(Promise),
+    // With the pc of the call, to go back to if the promise rejected.
+    Settled(Result<JsValue, JsValue>, usize),
+}
+
+// Sends everything the VM writes to `stdout` to a JavaScript function.
+struct JsWriter(Function);
+
+impl Write for JsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = JsValue::from_str(&String::from_utf8_lossy(buf));
+        self.0
+            .call1(&JsValue::NULL, &text)
+            .map_err(|_| io::Error::other("stdout callback failed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[wasm_bindgen]
+pub struct TzoVM {
+    vm: VM,
+    call: Rc<RefCell<Call>>,
+}
+
+impl Default for TzoVM {
+    fn default() -> Self {
+        TzoVM::new()
+    }
+}
+
+#[wasm_bindgen]
+impl TzoVM {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> TzoVM {
+        TzoVM {
+            vm: VM::new(),
+            call: Rc::new(RefCell::new(Call::Done)),
+        }
+    }
+
+    // Appends a program given as a JSON instruction list or fixture, either
+    // as an object or a string, as a string in the text syntax, or as a
+    // Uint8Array of bytecode.
+    pub fn load(&mut self, program: JsValue) -> Result<(), JsValue> {
+        let instructions = if let Some(bytes) = program.dyn_ref::<Uint8Array>() {
+            asm::parse_program_bytes(&bytes.to_vec())
+        } else {
+            let source = match program.as_string() {
+                Some(source) => source,
+                None => JSON::stringify(&program)?.into(),
+            };
+            asm::parse_program(&source)
+        }
+        .map_err(error)?;
+        self.vm.try_load(instructions).map_err(error)
+    }
+
+    // Runs until the program ends, pauses or a JavaScript function throws or
+    // returns a promise. Returns that promise, to await before running again,
+    // or undefined.
+    pub fn run(&mut self) -> Result<JsValue, JsValue> {
+        if let Some(waiting) = self.settle()? {
+            return Ok(waiting);
+        }
+        self.vm.try_run().map_err(|e| self.thrown(e))?;
+        self.check()
+    }
+
+    // Executes a single instruction, like `run`.
+    pub fn step(&mut self) -> Result<JsValue, JsValue> {
+        if let Some(waiting) = self.settle()? {
+            return Ok(waiting);
+        }
+        self.vm.step().map_err(|e| self.thrown(e))?;
+        self.check()
+    }
+
+    // Continues a paused program, like `run`.
+    pub fn resume(&mut self) -> Result<JsValue, JsValue> {
+        self.vm.resume();
+        self.run()
+    }
+
+    // Whether the VM waits for a promise returned by a JavaScript function.
+    #[wasm_bindgen(getter)]
+    pub fn waiting(&self) -> bool {
+        matches!(*self.call.borrow(), Call::Waiting(_))
+    }
+
+    // Applies the result of a promise that has settled since the VM was
+    // suspended, or returns the promise to wait for if it hasn't.
+    fn settle(&mut self) -> Result<Option<JsValue>, JsValue> {
+        let call = self.call.replace(Call::Done);
+        match call {
+            Call::Waiting(promise) => {
+                let waiting = promise.clone().into();
+                self.call.replace(Call::Waiting(promise));
+                Ok(Some(waiting))
+            }
+            Call::Settled(result, pc) => {
+                let pushed = result.and_then(|value| push_result(&mut self.vm.stack, value));
+                if pushed.is_err() {
+                    // like a runtime error, stop at the call
+                    self.vm.pc = pc;
+                }
+                pushed?;
+                Ok(None)
+            }
+            Call::Failed(e) => Err(e),
+            Call::Done => Ok(None),
+        }
+    }
+
+    // The exception behind a runtime error, if a JavaScript function threw
+    // it, or else the error itself.
+    fn thrown(&self, e: VmError) -> JsValue {
+        match self.call.replace(Call::Done) {
+            Call::Failed(thrown) => thrown,
+            _ => error(e),
+        }
+    }
+
+    fn check(&mut self) -> Result<JsValue, JsValue> {
+        Ok(self.settle()?.unwrap_or(JsValue::UNDEFINED))
+    }
+
+    // Makes `randInt` deterministic.
+    pub fn seed(&mut self, seed: u64) {
+        self.vm.seed(seed);
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn pc(&self) -> usize {
+        self.vm.pc
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_pc(&mut self, pc: usize) {
+        self.vm.pc = pc;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn running(&self) -> bool {
+        self.vm.running
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn exited(&self) -> bool {
+        self.vm.exited
+    }
+
+    // Bottom first, as an array of numbers and strings.
+    #[wasm_bindgen(getter)]
+    pub fn stack(&self) -> Array {
+        stack_to_js(&self.vm.stack)
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_stack(&mut self, stack: JsValue) -> Result<(), JsValue> {
+        self.vm.stack = stack_from_js(&stack)?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn context(&self) -> Result<Object, JsValue> {
+        context_to_js(&self.vm.context.to_map())
+    }
+
+    // Replaces the context, setting and deleting keys like `setContext` and
+    // `delContext` would, in the order of their names.
+    #[wasm_bindgen(setter)]
+    pub fn set_context(&mut self, context: JsValue) -> Result<(), JsValue> {
+        let mut context: Vec<(String, Value)> = context_from_js(&context)?.into_iter().collect();
+        context.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut stale: Vec<String> = self
+            .vm
+            .context
+            .iter()
+            .map(|(k, _)| k)
+            .filter(|k| !context.iter().any(|(key, _)| key == k))
+            .collect();
+        stale.sort();
+        for key in stale {
+            self.vm.delete_context(&key).map_err(error)?;
+        }
+        for (key, value) in context {
+            self.vm.set_context(key, value).map_err(error)?;
+        }
+        Ok(())
+    }
+
+    // Calls `f` with each piece of text the program writes with `stdout`.
+    #[wasm_bindgen(js_name = onStdout)]
+    pub fn on_stdout(&mut self, f: Function) {
+        self.vm.stdout = Box::new(JsWriter(f));
+    }
+
+    // Registers `f` as the foreign function `name`, for programs loaded after
+    // this. `f` is called with a `TzoProxy` to pop its arguments from and
+    // push its results to, and what it returns is pushed too unless it is
+    // undefined. If it returns a promise the VM is suspended until it
+    // settles; if it throws, the program stops and the exception is returned
+    // from `run`. Fails if `name` is that of a builtin or is taken.
+    #[wasm_bindgen(js_name = registerFunction)]
+    pub fn register_function(&mut self, name: &str, f: Function) -> Result<(), JsValue> {
+        let call = self.call.clone();
+        let owned = name.to_string();
+        let func = move |vm: &mut VM| {
+            let pc = vm.pc;
+            let frame: Frame =
+                Rc::new(RefCell::new(Some(std::mem::replace(vm, VM::placeholder()))));
+            let proxy = TzoProxy {
+                frame: frame.clone(),
+            };
+            let result = f.call1(&JsValue::NULL, &proxy.into());
+            *vm = frame.take().expect("the VM is back after the call");
+            let failed = match result {
+                Ok(value) if value.is_instance_of::<Promise>() => {
+                    let settled = call.clone();
+                    let promise = Promise::from(value);
+                    let waiting = future_to_promise(AssertUnwindSafe(async move {
+                        let result = JsFuture::from(promise).await;
+                        settled.replace(Call::Settled(result, pc));
+                        Ok(JsValue::UNDEFINED)
+                    }));
+                    call.replace(Call::Waiting(waiting));
+                    vm.suspend();
+                    return Ok(());
+                }
+                Ok(value) => push_result(&mut vm.stack, value).err(),
+                Err(e) => Some(e),
+            };
+            match failed {
+                Some(e) => {
+                    call.replace(Call::Failed(e));
+                    Err(vm.error(format!("{}: the JavaScript function failed", owned)))
+                }
+                None => Ok(()),
+            }
+        };
+        self.vm
+            .try_register_foreign_function(ForeignFunc {
+                func: Rc::new(func),
+                name: name.to_string(),
+                ..Default::default()
+            })
+            .map_err(error)
+    }
+}